@@ -0,0 +1,209 @@
+//! Cartridge header parsing.
+//!
+//! The header lives at [0x0100, 0x014f] and describes the cartridge
+//! hardware: memory bank controller, ROM and RAM sizes and the features
+//! supported by the game.
+
+use std::fmt;
+
+use super::{offsets, CartridgeError, CartridgeResult, ROM_BANK_SIZE};
+
+/// Game Boy Color support, from the CGB flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    /// Classic Game Boy game
+    None,
+    /// Game using the color features that also runs on the classic
+    /// Game Boy
+    Compatible,
+    /// Game that only runs on the Game Boy Color
+    Only,
+}
+
+/// Market the game was sold on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japanese,
+    NonJapanese,
+}
+
+/// Old licensee code meaning the new licensee code is used instead
+const USE_NEW_LICENSEE: u8 = 0x33;
+
+pub struct CartridgeHeader {
+    /// Game title, in uppercase ASCII
+    pub title: String,
+    /// Manufacturer code, only on newer cartridges
+    pub manufacturer: Option<String>,
+    /// Game Boy Color support
+    pub cgb: CgbSupport,
+    /// Super Game Boy features support
+    pub sgb: bool,
+    /// Two characters licensee code used by newer cartridges
+    pub new_licensee_code: String,
+    /// Licensee code used by older cartridges
+    pub old_licensee_code: u8,
+    /// Memory bank controller and hardware present on the cartridge
+    pub cartridge_type: u8,
+    /// Number of 16KB ROM banks
+    pub rom_banks: u16,
+    /// External RAM size in bytes
+    pub ram_size: usize,
+    /// Market the game was sold on
+    pub destination: Destination,
+    /// Version number of the game
+    pub version: u8,
+    /// Checksum of the header bytes, verified by the boot ROM
+    pub header_checksum: u8,
+    /// Checksum of the whole ROM, not verified by the Game Boy
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    /// Parse and verify the header of `rom`
+    pub fn parse(rom: &[u8]) -> CartridgeResult<CartridgeHeader> {
+        if rom.len() < offsets::HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let cgb = match rom[offsets::CGB_FLAG] {
+            0xc0 => CgbSupport::Only,
+            flag if flag & 0x80 != 0 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+
+        // On newer cartridges the end of the title area holds the
+        // manufacturer code and the CGB flag
+        let manufacturer = &rom[offsets::MANUFACTURER..offsets::CGB_FLAG];
+        let (title_end, manufacturer) = if cgb == CgbSupport::None {
+            (offsets::CGB_FLAG + 1, None)
+        } else if manufacturer.iter().all(|c| c.is_ascii_uppercase()) {
+            (offsets::MANUFACTURER, Some(ascii(manufacturer)))
+        } else {
+            (offsets::CGB_FLAG, None)
+        };
+
+        let rom_banks = match rom[offsets::ROM_SIZE] {
+            size @ 0x00 ... 0x08 => 2 << size,
+            0x52 => 72,
+            0x53 => 80,
+            0x54 => 96,
+            size => return Err(CartridgeError::UnknownRomSize(size)),
+        };
+
+        let ram_size = match rom[offsets::RAM_SIZE] {
+            0x00 => 0,
+            0x01 => 2 * 1024,
+            0x02 => 8 * 1024,
+            0x03 => 32 * 1024,
+            0x04 => 128 * 1024,
+            0x05 => 64 * 1024,
+            size => return Err(CartridgeError::UnknownRamSize(size)),
+        };
+
+        let header = CartridgeHeader {
+            title: ascii(&rom[offsets::TITLE..title_end]),
+            manufacturer,
+            cgb,
+            sgb: rom[offsets::SGB_FLAG] == 0x03,
+            new_licensee_code: ascii(&rom[offsets::NEW_LICENSEE..offsets::SGB_FLAG]),
+            old_licensee_code: rom[offsets::OLD_LICENSEE],
+            cartridge_type: rom[offsets::TYPE],
+            rom_banks,
+            ram_size,
+            destination: if rom[offsets::DESTINATION] == 0 {
+                Destination::Japanese
+            } else {
+                Destination::NonJapanese
+            },
+            version: rom[offsets::VERSION],
+            header_checksum: rom[offsets::HEADER_CHECKSUM],
+            global_checksum: (rom[offsets::GLOBAL_CHECKSUM] as u16) << 8
+                | rom[offsets::GLOBAL_CHECKSUM + 1] as u16,
+        };
+
+        // Only the boot ROM checks it, see `check_header_checksum`
+        let checksum = header_checksum(rom);
+        if checksum != header.header_checksum {
+            warn!("Invalid header checksum: expected {:#04x}, computed {:#04x}",
+                  header.header_checksum, checksum);
+        }
+
+        let checksum = global_checksum(rom);
+        if checksum != header.global_checksum {
+            warn!("Invalid global checksum: expected {:#06x}, computed {:#06x}",
+                  header.global_checksum, checksum);
+        }
+
+        Ok(header)
+    }
+
+    /// Check the header checksum of `rom` against this header. The boot ROM
+    /// locks up when it doesn't match, but nothing else checks it.
+    pub fn check_header_checksum(&self, rom: &[u8]) -> CartridgeResult<()> {
+        let checksum = header_checksum(rom);
+
+        if checksum != self.header_checksum {
+            return Err(CartridgeError::HeaderChecksum {
+                expected: self.header_checksum,
+                computed: checksum,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the cartridge has a battery keeping the RAM
+    /// contents, and the clock running, while powered off
+    pub fn has_battery(&self) -> bool {
+        match self.cartridge_type {
+            0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x22 | 0xff => true,
+            _ => false,
+        }
+    }
+
+    /// ROM size in bytes
+    pub fn rom_size(&self) -> usize {
+        self.rom_banks as usize * ROM_BANK_SIZE as usize
+    }
+
+    /// Licensee code, from the old or the new licensee code field
+    pub fn licensee_code(&self) -> String {
+        if self.old_licensee_code == USE_NEW_LICENSEE {
+            self.new_licensee_code.clone()
+        } else {
+            format!("{:02X}", self.old_licensee_code)
+        }
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} v{} (type {:#04x}, {} KB ROM, {} KB RAM)",
+               self.title, self.version, self.cartridge_type,
+               self.rom_size() / 1024, self.ram_size / 1024)
+    }
+}
+
+/// Convert a NUL padded ASCII field into a string
+fn ascii(bytes: &[u8]) -> String {
+    bytes.iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' })
+        .collect()
+}
+
+/// Compute the checksum of the header bytes at [0x0134, 0x014c]
+fn header_checksum(rom: &[u8]) -> u8 {
+    rom[offsets::TITLE..offsets::HEADER_CHECKSUM].iter()
+        .fold(0u8, |checksum, &byte| checksum.wrapping_sub(byte).wrapping_sub(1))
+}
+
+/// Compute the sum of all the ROM bytes except the global checksum
+fn global_checksum(rom: &[u8]) -> u16 {
+    let checksum = rom.iter()
+        .fold(0u16, |checksum, &byte| checksum.wrapping_add(byte as u16));
+
+    rom[offsets::GLOBAL_CHECKSUM..offsets::GLOBAL_CHECKSUM + 2].iter()
+        .fold(checksum, |checksum, &byte| checksum.wrapping_sub(byte as u16))
+}
@@ -1,30 +1,126 @@
 //! Cartridge emulation.
 
+use std::{cmp, error, fmt, io};
+
+use self::rtc::Rtc;
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+pub use self::header::{CartridgeHeader, CgbSupport, Destination};
+
+mod header;
 mod models;
+mod rtc;
 
 // Each ROM bank is always 16KB
 const ROM_BANK_SIZE: i32 = 16 * 1024;
 
+/// Ticks without RAM writes before the save RAM is considered ready to be
+/// flushed, one second
+const SAVE_RAM_QUIET_TICKS: u32 = 1 << 22;
+
 mod offsets {
     //! Various offset values to access special memory location
     //! within the ROM
 
+    /// Game title
+    pub const TITLE: usize = 0x134;
+    /// Manufacturer code, part of the title on older cartridges
+    pub const MANUFACTURER: usize = 0x13f;
+    /// Game Boy Color support flag
+    pub const CGB_FLAG: usize = 0x143;
+    /// New licensee code
+    pub const NEW_LICENSEE: usize = 0x144;
+    /// Super Game Boy support flag
+    pub const SGB_FLAG: usize = 0x146;
     /// Cartridge type
     pub const TYPE: usize = 0x147;
+    /// ROM size
+    pub const ROM_SIZE: usize = 0x148;
+    /// RAM size
+    pub const RAM_SIZE: usize = 0x149;
+    /// Destination code
+    pub const DESTINATION: usize = 0x14a;
+    /// Old licensee code
+    pub const OLD_LICENSEE: usize = 0x14b;
+    /// Mask ROM version number
+    pub const VERSION: usize = 0x14c;
+    /// Header checksum
+    pub const HEADER_CHECKSUM: usize = 0x14d;
+    /// Global checksum, big endian
+    pub const GLOBAL_CHECKSUM: usize = 0x14e;
+    /// End of the header
+    pub const HEADER_END: usize = 0x150;
+}
+
+// ------------------------------------------------------------ [Errors]
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    /// The ROM file couldn't be read
+    Io(io::Error),
+    /// The ROM is too small to hold a header
+    TooSmall(usize),
+    /// The cartridge type isn't supported
+    UnknownType(u8),
+    /// The ROM size code isn't valid
+    UnknownRomSize(u8),
+    /// The RAM size code isn't valid
+    UnknownRamSize(u8),
+    /// The header checksum doesn't match, the boot ROM refuses to start
+    /// these cartridges
+    HeaderChecksum { expected: u8, computed: u8 },
+}
+
+/// Type to be used when returning a result
+pub type CartridgeResult<T> = Result<T, CartridgeError>;
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> CartridgeError {
+        CartridgeError::Io(e)
+    }
 }
 
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CartridgeError::Io(ref e) => write!(f, "Unable to read the ROM: {}", e),
+            CartridgeError::TooSmall(size) => {
+                write!(f, "The ROM is too small to be a cartridge ({} bytes)", size)
+            }
+            CartridgeError::UnknownType(id) => {
+                write!(f, "Cartridge type {:#04x} is not supported", id)
+            }
+            CartridgeError::UnknownRomSize(size) => write!(f, "Invalid ROM size code {:#04x}", size),
+            CartridgeError::UnknownRamSize(size) => write!(f, "Invalid RAM size code {:#04x}", size),
+            CartridgeError::HeaderChecksum { expected, computed } => {
+                write!(f, "Invalid header checksum: expected {:#04x}, computed {:#04x}",
+                       expected, computed)
+            }
+        }
+    }
+}
+
+impl error::Error for CartridgeError {}
+
 // --------------------------------------------------------- [Cartridge]
 
 /// Base state for all types of cartridges
 pub struct Cartridge {
+    /// Cartridge header
+    header: CartridgeHeader,
     // Cartridge ROM data
     rom: Vec<u8>,
+    /// CRC-32 of the ROM, identifies the game on the save states
+    rom_checksum: u32,
     /// Cartridge RAM data
     ram: Vec<u8>,
     /// Total number of ROM banks in this cartridge
-    rom_banks: u8,
+    rom_banks: u16,
     /// Current number of the rom bank mapped at [0x4000, 0x7fff]
-    rom_bank: u8,
+    rom_bank: u16,
+    /// Offset of the bank mapped at [0x0000, 0x3fff], always the bank 0
+    /// except on some MBC1 modes
+    rom0_offset: usize,
     /// Current bank offset for the bank mapped at [0x4000, 0x7fff].
     /// This value is added to ROM register address when they're in
     /// that range
@@ -36,30 +132,92 @@ pub struct Cartridge {
     /// Certain cartridges allow banking either the RAM or ROM
     /// depending on the value of this flag
     bank_ram: bool,
+    /// The battery backed data changed since it was last saved
+    ram_dirty: bool,
+    /// Ticks since the last RAM write
+    quiet_ticks: u32,
+    /// Real time clock, on cartridges with one
+    rtc: Option<Rtc>,
+    /// Clock register mapped at [0xa000, 0xbfff] instead of a RAM bank
+    rtc_register: Option<u8>,
+    /// Rumble motor state, on cartridges with one
+    rumble: bool,
+    /// Rumble motor state not observed by the embedder yet
+    rumble_event: Option<bool>,
     /// Struct used to handle model specific functions
     model: models::Model
 }
 
 impl Cartridge {
     /// create a new Cartridge instance
-    pub fn new(rom_buf: Vec<u8>) -> Cartridge {
+    pub fn new(mut rom_buf: Vec<u8>) -> CartridgeResult<Cartridge> {
+        let header = CartridgeHeader::parse(&rom_buf)?;
+
         // determine the cartridge model
-        let model = models::from_id(rom_buf[offsets::TYPE]);
+        let model = models::from_id(header.cartridge_type, &rom_buf)
+            .ok_or(CartridgeError::UnknownType(header.cartridge_type))?;
+
+        // MBC3 cartridges with a timer
+        let rtc = match header.cartridge_type {
+            0x0f | 0x10 => Some(Rtc::new()),
+            _ => None,
+        };
+
+        // bad dumps may be truncated, fill the missing banks with open bus
+        if rom_buf.len() < header.rom_size() {
+            warn!("The ROM is smaller than the size declared on the header");
+            rom_buf.resize(header.rom_size(), 0xff);
+        }
+
+        // MBC2 has its RAM built in the controller
+        let ram_size = cmp::max(header.ram_size, model.builtin_ram);
+
+        info!("Loaded cartridge {}", header);
 
-        Cartridge {
+        Ok(Cartridge {
+            rom_banks: header.rom_banks,
+            header,
+            rom_checksum: crc32(&rom_buf),
             rom: rom_buf,
-            ram: Vec::new(),
-            // cartridge must have always at least two ROM banks
-            rom_banks: 2,
+            ram: vec![0; ram_size],
             // default to bank 1 for bankable region
             rom_bank: 1,
+            rom0_offset: 0,
             rom_offset: 0,
             ram_offset: 0,
             // by default RAM is write protected
             ram_wp: true,
             bank_ram: false,
+            ram_dirty: false,
+            quiet_ticks: 0,
+            rtc,
+            rtc_register: None,
+            rumble: false,
+            rumble_event: None,
             model: model
-        }
+        })
+    }
+
+    /// Get the cartridge header
+    pub fn header(&self) -> &CartridgeHeader {
+        &self.header
+    }
+
+    /// Fail if the header checksum doesn't match, in which case the boot
+    /// ROM refuses to start the cartridge
+    pub fn check_header_checksum(&self) -> CartridgeResult<()> {
+        self.header.check_header_checksum(&self.rom)
+    }
+
+    /// Get the CRC-32 of the ROM
+    pub fn rom_checksum(&self) -> u32 {
+        self.rom_checksum
+    }
+
+    /// Returns `true` if the cartridge supports the Game Boy Color
+    /// features, in which case it runs in color mode
+    pub fn is_gbc(&self) -> bool {
+        self.header.cgb != CgbSupport::None
     }
 
     /// read a byte from the cartridge memory
@@ -68,7 +226,7 @@ impl Cartridge {
 
         // read rom
         if off < ROM_BANK_SIZE {
-            self.rom[off as usize]
+            self.rom[self.rom0_offset + off as usize]
         } else {
             self.rom[(self.rom_offset + off) as usize]
         }
@@ -78,6 +236,11 @@ impl Cartridge {
 
     /// read a byte from the ram bank
     pub fn ram_byte(&self, offset: u16) -> u8 {
+        // the RAM isn't driven while disabled
+        if self.ram_wp {
+            return 0xff;
+        }
+
         // compute the address
         let address = self.ram_offset + offset as u32;
 
@@ -85,13 +248,23 @@ impl Cartridge {
         (self.model.read_ram)(self, address)
     }
 
-    /// Return the value of a RAM byte at absolute address
+    /// Return the value of a RAM byte at absolute address, the RAM is
+    /// mirrored when it's smaller than the selectable banks
     fn ram_byte_absolute(&self, address: u32) -> u8 {
-        *self.ram.get(address as usize).unwrap_or(&0)
+        if self.ram.is_empty() {
+            return 0xff;
+        }
+
+        self.ram[address as usize % self.ram.len()]
     }
 
     fn ram_byte_absolute_mut(&mut self, addr: u32) -> Option<&mut u8> {
-        self.ram.get_mut(addr as usize)
+        if self.ram.is_empty() {
+            return None;
+        }
+
+        let len = self.ram.len();
+        self.ram.get_mut(addr as usize % len)
     }
 
     /// Enable or disable RAM write protect
@@ -120,30 +293,72 @@ impl Cartridge {
     pub fn set_ram_byte(&mut self, offset: u16, val: u8) {
         let addr = self.ram_offset + offset as u32;
 
+        // writes are ignored while the RAM is disabled
         if self.ram_wp {
-            panic!("Attempt to write to cartridge RAM while protected");
             return;
         }
 
         (self.model.write_ram)(self, addr, val);
+
+        self.ram_dirty = true;
+        self.quiet_ticks = 0;
+    }
+
+    /// Returns `true` if the cartridge keeps its RAM while powered off
+    pub fn has_battery(&self) -> bool {
+        self.header.has_battery()
+    }
+
+    /// Returns `true` when the battery backed data changed and the game
+    /// stopped writing it for a while, so it's a good time to save it
+    pub fn save_data_pending(&self) -> bool {
+        self.ram_dirty && self.quiet_ticks >= SAVE_RAM_QUIET_TICKS
+    }
+
+    /// Battery backed data: the RAM followed by the clock state, if
+    /// the cartridge has a clock
+    pub fn save_data(&mut self) -> Vec<u8> {
+        self.ram_dirty = false;
+
+        let mut data = self.ram.clone();
+
+        if let Some(ref mut rtc) = self.rtc {
+            data.extend(rtc.save());
+        }
+
+        data
+    }
+
+    /// Restore the battery backed data, the clock state is optional
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        let ram_size = cmp::min(self.ram.len(), data.len());
+        self.ram[..ram_size].copy_from_slice(&data[..ram_size]);
+
+        if let Some(ref mut rtc) = self.rtc {
+            let rtc_data = &data[ram_size..];
+
+            if !rtc_data.is_empty() && !rtc.load(rtc_data) {
+                warn!("Invalid real time clock data");
+            }
+        }
     }
 
     // ----------------------------------------------------------- [ROM]
 
     /// Retrieve the number of ROM banks in the cartridge
-    pub fn rom_banks(&self) -> u8 {
+    pub fn rom_banks(&self) -> u16 {
         self.rom_banks
     }
 
     /// Retrieve current ROM bank number for the bankable
     /// range at [0x4000, 0x7ffff]
-    pub fn rom_bank(&self) -> u8 {
+    pub fn rom_bank(&self) -> u16 {
         self.rom_bank
     }
 
     /// Set new ROM bank number for the bankable range
     /// at [0x4000, 0x7ffff]
-    pub fn set_rom_bank(&mut self, bank: u8) {
+    pub fn set_rom_bank(&mut self, bank: u16) {
         self.rom_bank = bank;
     }
 
@@ -151,8 +366,121 @@ impl Cartridge {
         self.rom_offset = offset;
     }
 
+    /// Set the offset of the bank mapped at [0x0000, 0x3fff]
+    pub fn set_rom0_offset(&mut self, offset: usize) {
+        self.rom0_offset = offset;
+    }
+
     /// write a byte into ROM memory
     pub fn set_rom_byte(&mut self, offset: u16, value: u8) {
         (self.model.write_rom)(self, offset, value);
     }
+
+    /// execute the cartridge cycle
+    pub fn do_cycle(&mut self, ticks: u32) {
+        if self.ram_dirty {
+            self.quiet_ticks = self.quiet_ticks.saturating_add(ticks);
+        }
+    }
+
+    // -------------------------------------------------------- [Rumble]
+
+    /// Turn the rumble motor on or off
+    fn set_rumble(&mut self, rumble: bool) {
+        if self.rumble != rumble {
+            self.rumble = rumble;
+            self.rumble_event = Some(rumble);
+        }
+    }
+
+    /// Take the rumble motor state if it changed since the last call
+    pub fn take_rumble_event(&mut self) -> Option<bool> {
+        self.rumble_event.take()
+    }
+}
+
+/// The ROM itself isn't part of the state, the save states are only
+/// loaded on the cartridge they were made with
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_vec(&self.ram);
+        writer.write_u16(self.rom_bank);
+        writer.write_u32(self.rom0_offset as u32);
+        writer.write_i32(self.rom_offset);
+        writer.write_u32(self.ram_offset);
+        writer.write_bool(self.ram_wp);
+        writer.write_bool(self.bank_ram);
+        writer.write_u8(self.rtc_register.unwrap_or(0));
+        writer.write_bool(self.rumble);
+
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        let ram = reader.read_vec()?;
+        if ram.len() != self.ram.len() {
+            return Err(StateError::InvalidValue("cartridge RAM size"));
+        }
+
+        let rom_bank = reader.read_u16()?;
+        let rom0_offset = reader.read_u32()? as usize;
+        let rom_offset = reader.read_i32()?;
+
+        // the offsets are used to index the ROM without any check, the
+        // switchable bank is read with offsets in [0x4000, 0x7fff]
+        let rom_size = self.rom.len() as i64;
+        let bank_size = ROM_BANK_SIZE as i64;
+        if rom0_offset as i64 + bank_size > rom_size
+            || (rom_offset as i64) < -bank_size
+            || rom_offset as i64 + 2 * bank_size > rom_size
+        {
+            return Err(StateError::InvalidValue("ROM bank"));
+        }
+
+        let ram_changed = ram != self.ram;
+        self.ram = ram;
+        self.rom_bank = rom_bank;
+        self.rom0_offset = rom0_offset;
+        self.rom_offset = rom_offset;
+        self.ram_offset = reader.read_u32()?;
+        self.ram_wp = reader.read_bool()?;
+        self.bank_ram = reader.read_bool()?;
+        self.rtc_register = match reader.read_u8()? {
+            0 => None,
+            register @ 0x08 ... 0x0c => Some(register),
+            _ => return Err(StateError::InvalidValue("clock register")),
+        };
+
+        let rumble = reader.read_bool()?;
+        self.set_rumble(rumble);
+
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(reader)?;
+        }
+
+        // the save file follows the loaded RAM
+        if ram_changed && self.has_battery() {
+            self.ram_dirty = true;
+            self.quiet_ticks = SAVE_RAM_QUIET_TICKS;
+        }
+
+        Ok(())
+    }
+}
+
+/// Compute the CRC-32 of `data`, the checksum used by the ROM databases
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut table = [0u32; 256];
+
+    for (index, entry) in table.iter_mut().enumerate() {
+        *entry = (0..8).fold(index as u32, |crc, _| {
+            if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 }
+        });
+    }
+
+    !data.iter().fold(!0u32, |crc, &byte| {
+        table[((crc ^ byte as u32) & 0xff) as usize] ^ (crc >> 8)
+    })
 }
@@ -13,6 +13,8 @@ pub struct Model {
     pub write_ram: fn(cart: &mut Cartridge, address: u32, value: u8),
     /// Handle RAM read
     pub read_ram: fn(cart: &Cartridge, address: u32) -> u8,
+    /// Size of the RAM built in the controller, if any
+    pub builtin_ram: usize,
 }
 
 /// Implement clone trait on the Model
@@ -21,7 +23,8 @@ impl ::std::clone::Clone for Model {
         Model {
             write_rom: self.write_rom,
             write_ram: self.write_ram,
-            read_ram: self.read_ram
+            read_ram: self.read_ram,
+            builtin_ram: self.builtin_ram,
         }
     }
 }
@@ -38,30 +41,37 @@ fn read_ram(cart: &Cartridge, address: u32) -> u8 {
     cart.ram_byte_absolute(address)
 }
 
-/// Get the correspondent model for the given cartridge type
-pub fn from_id(id: u8) -> Model {
+/// Get the correspondent model for the given cartridge type, if it's
+/// supported. The ROM is used to detect the variants sharing a type.
+pub fn from_id(id: u8, rom: &[u8]) -> Option<Model> {
     match id {
-        0x00 => mbc0::MODEL,
-        0x01...0x03 => mbc1::MODEL,
-        0x0f...0x13 => mbc3::MODEL,
-        _ => panic!("Cartridge model {:#x} not implemented", id),
+        0x00 => Some(mbc0::MODEL),
+        0x01...0x03 if mbc1::is_multicart(rom) => Some(mbc1::MULTICART_MODEL),
+        0x01...0x03 => Some(mbc1::MODEL),
+        0x05...0x06 => Some(mbc2::MODEL),
+        0x0f...0x13 => Some(mbc3::MODEL),
+        0x19...0x1b => Some(mbc5::MODEL),
+        0x1c...0x1e => Some(mbc5::RUMBLE_MODEL),
+        _ => None,
     }
 }
 
 /// Default implementation of bank reconfiguration
-fn set_rom_bank(cart: &mut Cartridge, bank: u8) {
+fn set_rom_bank(cart: &mut Cartridge, bank: u16) {
     cart.set_rom_bank(bank);
 
-    let rom_offset = ROM_BANK_SIZE *
-        match bank {
-            // We can't select bank 0, it defaults to 1
-            0 => 0,
-            // The offset is added to the address of the CPU
-            // access. This bankable ROM is just after the bank0 it
-            // means we always have a 1 bank offset already in the
-            // address, so we need to substract 1 here.
-            n => (n - 1) as i32,
-        };
+    // We can't select bank 0, it defaults to 1
+    let bank = if bank == 0 { 1 } else { bank };
+
+    // If the bank overflows we wrap it around, only the connected
+    // address lines are used
+    let bank = bank % cart.rom_banks();
+
+    // The offset is added to the address of the CPU access. This
+    // bankable ROM is just after the bank0 it means we always have a 1
+    // bank offset already in the address, so we need to substract 1
+    // here.
+    let rom_offset = ROM_BANK_SIZE * (bank as i32 - 1);
 
     cart.set_rom_offset(rom_offset);
 }
@@ -72,14 +82,16 @@ mod mbc0 {
     use super::Model;
     use crate::cartridge::Cartridge;
 
-    fn write_rom(_: &mut Cartridge, offset: u16, value: u8) {
-        panic!("Unhandled ROM write: {:04x} {:02x}", offset, value);
+    /// There are no registers, the writes are lost. Some games write
+    /// there anyway, as if they had a MBC1.
+    fn write_rom(_: &mut Cartridge, _offset: u16, _value: u8) {
     }
 
     pub static MODEL: Model = Model {
         write_rom: write_rom,
         write_ram: super::write_ram,
-        read_ram: super::read_ram
+        read_ram: super::read_ram,
+        builtin_ram: 0,
     };
 }
 
@@ -89,73 +101,164 @@ mod mbc1 {
     use super::Model;
     use crate::cartridge::{Cartridge, ROM_BANK_SIZE};
 
-    fn write_rom(cart: &mut Cartridge, offset: u16, value: u8) {
+    /// Size of a multicart ROM, made of four 256KB games
+    const MULTICART_BANKS: usize = 64;
+    /// Each game of a multicart starts with its own header
+    const MULTICART_GAME_BANKS: usize = 0x10;
+    /// Nintendo logo on the header, checked by the boot ROM
+    const LOGO: ::std::ops::Range<usize> = 0x104..0x134;
+
+    /// Multicarts (MBC1M) are wired with only 4 bits for the low bank
+    /// register. They can't be told apart from the header, but unlike
+    /// normal games they have a second header with the logo at the
+    /// start of the second game.
+    pub fn is_multicart(rom: &[u8]) -> bool {
+        let bank_size = ROM_BANK_SIZE as usize;
+
+        if rom.len() != MULTICART_BANKS * bank_size {
+            return false;
+        }
+
+        let second_game = MULTICART_GAME_BANKS * bank_size;
+        let logo = LOGO.start + second_game..LOGO.end + second_game;
+
+        rom[LOGO] == rom[logo]
+    }
+
+    fn write_rom(cart: &mut Cartridge, offset: u16, value: u8, multicart: bool) {
         match offset {
             0x0000 ... 0x1fff => {
                 // Writing a low nibble 0xa to anywhere in that address
                 // range removes RAM write protect, all other values
                 // enable it.
-                cart.set_ram_wp(value & 0xf != 0xa)
+                cart.set_ram_wp(value & 0xf != 0xa);
+                return;
             }
             0x2000 ... 0x3fff => {
-                // Select a new ROM bank, bits [4:0]
-                let cur_bank = cart.rom_bank() & !0x1f;
-
-                // New bank to select
-                let bank = cur_bank | (value & 0x1f);
-
-                // select the new rom bank
-                set_rom_bank(cart, bank);
+                // BANK1 register, bits [4:0] of the ROM bank
+                let bank = (cart.rom_bank() & !0x1f) | (value & 0x1f) as u16;
+                cart.set_rom_bank(bank);
             }
             0x4000 ... 0x5fff => {
-                if cart.bank_ram() {
-                    // select a new RAM bank
-                    cart.set_ram_bank(value & 0x3);
-                } else {
-                    // select a new ROM bank, bits [6:5]
-                    let cur_bank = cart.rom_bank() & !0x60;
-
-                    let bank = cur_bank | ((value << 5) & 0x60);
-
-                    // set the new rom bank
-                    set_rom_bank(cart, bank);
-                }
+                // BANK2 register, bits [6:5] of the ROM bank or the RAM
+                // bank depending on the mode
+                let bank = (cart.rom_bank() & 0x1f) | ((value & 0x3) as u16) << 5;
+                cart.set_rom_bank(bank);
             }
             0x6000 ... 0x7fff => {
                 // switch RAM/ROM banking mode
                 cart.set_bank_ram(value & 1 != 0)
             }
-            _ => panic!("Unhandled ROM write: {:04x} {:02x}", offset, value),
+            _ => (),
         }
+
+        remap(cart, multicart);
     }
 
-    /// I copy this code from another Game Boy Emulator, some
-    /// games crash if the is not well done
-    fn set_rom_bank(cart: &mut Cartridge, bank: u8) {
-        // set the select rom bank
-        cart.set_rom_bank(bank);
+    fn write_rom_normal(cart: &mut Cartridge, offset: u16, value: u8) {
+        write_rom(cart, offset, value, false)
+    }
+
+    fn write_rom_multicart(cart: &mut Cartridge, offset: u16, value: u8) {
+        write_rom(cart, offset, value, true)
+    }
+
+    /// Map the ROM and RAM banks selected by the BANK1 and BANK2
+    /// registers, kept on the `rom_bank` as `BANK2 << 5 | BANK1`
+    fn remap(cart: &mut Cartridge, multicart: bool) {
+        let bank1 = cart.rom_bank() & 0x1f;
+        let bank2 = cart.rom_bank() >> 5;
+
+        // BANK1 can't be 0, the check is done on all the 5 bits even
+        // when they aren't all connected
+        let bank1 = if bank1 == 0 { 1 } else { bank1 };
+
+        // On multicarts BANK2 selects one of the four games
+        let (bank1, bank2) = if multicart {
+            (bank1 & 0x0f, bank2 << 4)
+        } else {
+            (bank1, bank2 << 5)
+        };
+
+        // On mode 1 BANK2 also applies to [0x0000, 0x3fff] and to the RAM
+        let mode1 = cart.bank_ram();
+        let bank0 = if mode1 { bank2 } else { 0 };
+        cart.set_ram_bank(if mode1 { (cart.rom_bank() >> 5) as u8 } else { 0 });
 
-        let bank = if bank & 0x1f != 0 { bank } else { bank | 1 };
+        // If the bank overflows we wrap it around, only the connected
+        // address lines are used
+        let banks = cart.rom_banks();
+        let bank0 = (bank0 % banks) as usize;
+        let bank = ((bank2 | bank1) % banks) as i32;
 
-        // If the bank overflows we wrap it around. This assumes that
-        // MBC1 cart can only have a power of two number of banks.
-        let bank = bank & (cart.rom_banks() - 1);
+        cart.set_rom0_offset(ROM_BANK_SIZE as usize * bank0);
 
         // Same as super::set_rom_bank: we already have a one bank
         // offset in the CPU address when accessing bankable ROM.
-        let bank = (bank as i32) - 1;
+        cart.set_rom_offset(ROM_BANK_SIZE * (bank - 1));
+    }
+
+    pub static MODEL: Model = Model {
+        write_rom: write_rom_normal,
+        write_ram: super::write_ram,
+        read_ram: super::read_ram,
+        builtin_ram: 0,
+    };
+
+    pub static MULTICART_MODEL: Model = Model {
+        write_rom: write_rom_multicart,
+        write_ram: super::write_ram,
+        read_ram: super::read_ram,
+        builtin_ram: 0,
+    };
+}
+
+// --------------------------------------------------------- [MBC2]
+
+mod mbc2 {
+    use super::Model;
+    use crate::cartridge::Cartridge;
+
+    /// The controller has 512 half-bytes of RAM, mirrored on the whole
+    /// [0xa000, 0xbfff] range
+    const RAM_SIZE: usize = 512;
+
+    fn write_rom(cart: &mut Cartridge, offset: u16, value: u8) {
+        match offset {
+            // Bit 8 of the address selects the register
+            0x0000 ... 0x3fff if offset & 0x100 == 0 => {
+                // Writing a low nibble 0xa removes RAM write protect, all
+                // other values enable it.
+                cart.set_ram_wp(value & 0xf != 0xa)
+            }
+            0x0000 ... 0x3fff => {
+                // Select a new ROM bank, only 16 banks are available
+                super::set_rom_bank(cart, (value & 0xf) as u16)
+            }
+            // No registers in this range
+            _ => (),
+        }
+    }
+
+    fn write_ram(cart: &mut Cartridge, address: u32, value: u8) {
+        let address = address % RAM_SIZE as u32;
+
+        // Only the low nibble is stored
+        super::write_ram(cart, address, value & 0xf)
+    }
 
-        // compute the rom offset
-        let rom_offset = ROM_BANK_SIZE * bank;
+    fn read_ram(cart: &Cartridge, address: u32) -> u8 {
+        let address = address % RAM_SIZE as u32;
 
-        // set the rom offset
-        cart.set_rom_offset(rom_offset);
+        // The high nibble isn't connected and reads as 1
+        0xf0 | super::read_ram(cart, address)
     }
 
     pub static MODEL: Model = Model {
         write_rom: write_rom,
-        write_ram: super::write_ram,
-        read_ram: super::read_ram,
+        write_ram: write_ram,
+        read_ram: read_ram,
+        builtin_ram: RAM_SIZE,
     };
 }
 
@@ -171,18 +274,411 @@ mod mbc3 {
             // All other value enable it.
             0x0000...0x1fff => cart.set_ram_wp(val & 0xf != 0xa),
             // Select a new ROM bank
-            0x2000...0x3fff => super::set_rom_bank(cart, val & 0x7f),
+            0x2000...0x3fff => super::set_rom_bank(cart, (val & 0x7f) as u16),
+            // Select a clock register, on cartridges with a clock
+            0x4000...0x5fff if cart.rtc.is_some() && (0x08..=0x0c).contains(&val) => {
+                cart.rtc_register = Some(val);
+            }
+            // Select a new RAM bank
+            0x4000...0x5fff => {
+                cart.rtc_register = None;
+                cart.set_ram_bank(val & 0x03);
+            }
+            // Latch the clock registers
+            0x6000...0x7fff => {
+                if let Some(ref mut rtc) = cart.rtc {
+                    rtc.write_latch(val);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn write_ram(cart: &mut Cartridge, address: u32, value: u8) {
+        match (cart.rtc_register, cart.rtc.as_mut()) {
+            (Some(register), Some(rtc)) => rtc.write(register, value),
+            _ => super::write_ram(cart, address, value),
+        }
+    }
+
+    fn read_ram(cart: &Cartridge, address: u32) -> u8 {
+        match (cart.rtc_register, cart.rtc.as_ref()) {
+            (Some(register), Some(rtc)) => rtc.read(register),
+            _ => super::read_ram(cart, address),
+        }
+    }
+
+    pub static MODEL: Model = Model {
+        write_rom: write_rom,
+        write_ram: write_ram,
+        read_ram: read_ram,
+        builtin_ram: 0,
+    };
+}
+
+// --------------------------------------------------------- [MBC5]
+
+mod mbc5 {
+    use super::Model;
+    use crate::cartridge::{Cartridge, ROM_BANK_SIZE};
+
+    /// Handle the writes shared by both variants
+    fn write_rom(cart: &mut Cartridge, offset: u16, value: u8) {
+        match offset {
+            // Writing 0xa to anywhere in that address range removes RAM
+            // write protect, all other values enable it.
+            0x0000 ... 0x1fff => cart.set_ram_wp(value != 0x0a),
+            // Select the bits [7:0] of the ROM bank
+            0x2000 ... 0x2fff => {
+                let bank = (cart.rom_bank() & 0x100) | value as u16;
+                set_rom_bank(cart, bank);
+            }
+            // Select the bit 8 of the ROM bank
+            0x3000 ... 0x3fff => {
+                let bank = (cart.rom_bank() & 0xff) | ((value as u16 & 1) << 8);
+                set_rom_bank(cart, bank);
+            }
             // Select a new RAM bank
-            0x4000...0x5fff => cart.set_ram_bank(val),
-            0x6000...0x7fff => println!("Unhandled RTC access"),
-            _ => println!("Unhandled ROM write: {:04x} {:02x}", offset, val),
+            0x4000 ... 0x5fff => cart.set_ram_bank(value & 0x0f),
+            _ => (),
         }
     }
 
+    /// On rumble cartridges the bit 3 of the RAM bank register drives
+    /// the motor, so only 8 RAM banks are available
+    fn write_rom_rumble(cart: &mut Cartridge, offset: u16, value: u8) {
+        match offset {
+            0x4000 ... 0x5fff => {
+                cart.set_rumble(value & 0x08 != 0);
+                cart.set_ram_bank(value & 0x07);
+            }
+            _ => write_rom(cart, offset, value),
+        }
+    }
+
+    /// Unlike on the other models the bank 0 can be mapped at
+    /// [0x4000, 0x7fff]
+    fn set_rom_bank(cart: &mut Cartridge, bank: u16) {
+        cart.set_rom_bank(bank);
+
+        // If the bank overflows we wrap it around
+        let bank = bank % cart.rom_banks();
+
+        // We already have a one bank offset in the CPU address when
+        // accessing bankable ROM
+        cart.set_rom_offset(ROM_BANK_SIZE * (bank as i32 - 1));
+    }
+
     pub static MODEL: Model = Model {
         write_rom: write_rom,
         write_ram: super::write_ram,
         read_ram: super::read_ram,
+        builtin_ram: 0,
+    };
+
+    pub static RUMBLE_MODEL: Model = Model {
+        write_rom: write_rom_rumble,
+        write_ram: super::write_ram,
+        read_ram: super::read_ram,
+        builtin_ram: 0,
     };
 }
 
+#[cfg(test)]
+mod tests {
+    use crate::cartridge::{offsets, Cartridge, ROM_BANK_SIZE};
+
+    /// MBC1 with RAM and battery
+    const MBC1: u8 = 0x03;
+    /// MBC2 with battery, the RAM is built in the controller
+    const MBC2: u8 = 0x06;
+    /// MBC5 with RAM and battery
+    const MBC5: u8 = 0x1b;
+    /// MBC5 with rumble, RAM and battery
+    const MBC5_RUMBLE: u8 = 0x1e;
+    /// 128KB of RAM, 16 banks
+    const RAM_128KB: u8 = 0x04;
+    /// 32KB of RAM, 4 banks
+    const RAM_32KB: u8 = 0x03;
+
+    /// Build a ROM of `2 << size_code` banks, each one starting with its
+    /// own bank number
+    fn rom(size_code: u8) -> Vec<u8> {
+        let bank_size = ROM_BANK_SIZE as usize;
+        let banks = 2usize << size_code;
+        let mut rom = vec![0; banks * bank_size];
+
+        for bank in 0..banks {
+            rom[bank * bank_size] = bank as u8;
+        }
+
+        rom[offsets::TYPE] = MBC1;
+        rom[offsets::ROM_SIZE] = size_code;
+        rom[offsets::RAM_SIZE] = RAM_32KB;
+        rom
+    }
+
+    fn cartridge(rom: Vec<u8>) -> Cartridge {
+        Cartridge::new(rom).unwrap()
+    }
+
+    /// Bank mapped at [0x0000, 0x3fff]
+    fn bank0(cart: &Cartridge) -> u8 {
+        cart.read_byte(0x0000)
+    }
+
+    /// Bank mapped at [0x4000, 0x7fff]
+    fn bank(cart: &Cartridge) -> u8 {
+        cart.read_byte(0x4000)
+    }
+
+    #[test]
+    fn bank_0_selects_bank_1() {
+        let mut cart = cartridge(rom(2));
+
+        cart.set_rom_byte(0x2000, 0x03);
+        assert_eq!(bank(&cart), 3);
+
+        cart.set_rom_byte(0x2000, 0x00);
+        assert_eq!(bank(&cart), 1);
+    }
+
+    #[test]
+    fn bank_is_masked_to_the_rom_size() {
+        // 8 banks, only 3 address lines are connected
+        let mut cart = cartridge(rom(2));
+
+        cart.set_rom_byte(0x2000, 0x0b);
+        assert_eq!(bank(&cart), 3);
+
+        // the zero check is done on the 5 bits
+        cart.set_rom_byte(0x2000, 0x08);
+        assert_eq!(bank(&cart), 0);
+    }
+
+    #[test]
+    fn bank2_selects_the_upper_bits() {
+        let mut cart = cartridge(rom(6));
+
+        cart.set_rom_byte(0x4000, 0x01);
+        cart.set_rom_byte(0x2000, 0x00);
+        assert_eq!(bank(&cart), 0x21);
+
+        cart.set_rom_byte(0x4000, 0x03);
+        cart.set_rom_byte(0x2000, 0x05);
+        assert_eq!(bank(&cart), 0x65);
+
+        // only bank 0 is mapped at [0x0000, 0x3fff] on mode 0
+        assert_eq!(bank0(&cart), 0);
+    }
+
+    #[test]
+    fn mode_1_maps_bank2_on_the_first_bank() {
+        let mut cart = cartridge(rom(6));
+
+        cart.set_rom_byte(0x4000, 0x02);
+        cart.set_rom_byte(0x6000, 0x01);
+        assert_eq!(bank0(&cart), 0x40);
+        assert_eq!(bank(&cart), 0x41);
+
+        cart.set_rom_byte(0x6000, 0x00);
+        assert_eq!(bank0(&cart), 0);
+    }
+
+    #[test]
+    fn mode_1_selects_the_ram_bank() {
+        let mut cart = cartridge(rom(2));
+        cart.set_rom_byte(0x0000, 0x0a);
+
+        cart.set_rom_byte(0x6000, 0x01);
+        cart.set_rom_byte(0x4000, 0x02);
+        cart.set_ram_byte(0x0000, 0x55);
+
+        cart.set_rom_byte(0x4000, 0x00);
+        assert_eq!(cart.ram_byte(0x0000), 0x00);
+
+        cart.set_rom_byte(0x4000, 0x02);
+        assert_eq!(cart.ram_byte(0x0000), 0x55);
+
+        // mode 0 always uses the first RAM bank
+        cart.set_rom_byte(0x6000, 0x00);
+        assert_eq!(cart.ram_byte(0x0000), 0x00);
+    }
+
+    #[test]
+    fn disabled_ram_reads_open_bus() {
+        let mut cart = cartridge(rom(2));
+
+        cart.set_rom_byte(0x0000, 0x0a);
+        cart.set_ram_byte(0x0000, 0x55);
+
+        cart.set_rom_byte(0x0000, 0x00);
+        assert_eq!(cart.ram_byte(0x0000), 0xff);
+    }
+
+    #[test]
+    fn multicart_uses_4_bits_of_bank1() {
+        let bank_size = ROM_BANK_SIZE as usize;
+        let mut rom = rom(5);
+
+        // the second game starts with its own logo
+        for offset in 0x104..0x134 {
+            rom[offset] = offset as u8;
+            rom[0x10 * bank_size + offset] = offset as u8;
+        }
+
+        let mut cart = cartridge(rom);
+
+        cart.set_rom_byte(0x4000, 0x01);
+        cart.set_rom_byte(0x2000, 0x12);
+        assert_eq!(bank(&cart), 0x12);
+
+        cart.set_rom_byte(0x4000, 0x02);
+        assert_eq!(bank(&cart), 0x22);
+
+        cart.set_rom_byte(0x6000, 0x01);
+        assert_eq!(bank0(&cart), 0x20);
+    }
+
+    fn mbc2_rom() -> Vec<u8> {
+        let mut rom = rom(3);
+        rom[offsets::TYPE] = MBC2;
+        rom[offsets::RAM_SIZE] = 0x00;
+        rom
+    }
+
+    #[test]
+    fn mbc2_address_bit_8_selects_the_register() {
+        let mut cart = cartridge(mbc2_rom());
+
+        // bit 8 clear, RAM enable
+        cart.set_rom_byte(0x0000, 0x0a);
+        cart.set_ram_byte(0x0000, 0x05);
+        assert_eq!(cart.ram_byte(0x0000), 0xf5);
+        assert_eq!(bank(&cart), 1);
+
+        // bit 8 set, ROM bank, even in [0x0000, 0x1fff]
+        cart.set_rom_byte(0x0100, 0x03);
+        assert_eq!(bank(&cart), 3);
+        assert_eq!(cart.ram_byte(0x0000), 0xf5);
+
+        cart.set_rom_byte(0x2100, 0x1c);
+        assert_eq!(bank(&cart), 0x0c);
+
+        // bit 8 clear, RAM disable, even in [0x2000, 0x3fff]
+        cart.set_rom_byte(0x2000, 0x00);
+        assert_eq!(bank(&cart), 0x0c);
+        assert_eq!(cart.ram_byte(0x0000), 0xff);
+    }
+
+    #[test]
+    fn mbc2_ram_stores_the_low_nibble() {
+        let mut cart = cartridge(mbc2_rom());
+        cart.set_rom_byte(0x0000, 0x0a);
+
+        cart.set_ram_byte(0x0000, 0x5a);
+        assert_eq!(cart.ram_byte(0x0000), 0xfa);
+
+        cart.set_ram_byte(0x01ff, 0x00);
+        assert_eq!(cart.ram_byte(0x01ff), 0xf0);
+    }
+
+    #[test]
+    fn mbc2_ram_is_mirrored() {
+        let mut cart = cartridge(mbc2_rom());
+        cart.set_rom_byte(0x0000, 0x0a);
+
+        cart.set_ram_byte(0x0012, 0x07);
+
+        for mirror in 1..16 {
+            assert_eq!(cart.ram_byte(0x0012 + mirror * 0x200), 0xf7);
+        }
+
+        cart.set_ram_byte(0x1ffe, 0x03);
+        assert_eq!(cart.ram_byte(0x01fe), 0xf3);
+    }
+
+    fn mbc5_rom(cart_type: u8, size_code: u8) -> Vec<u8> {
+        let mut rom = rom(size_code);
+        rom[offsets::TYPE] = cart_type;
+        rom[offsets::RAM_SIZE] = RAM_128KB;
+        rom
+    }
+
+    #[test]
+    fn mbc5_bank_has_9_bits() {
+        let bank_size = ROM_BANK_SIZE as usize;
+        let mut rom = mbc5_rom(MBC5, 8);
+
+        // tell apart the banks sharing their low 8 bits
+        for bank in 0x100..0x200 {
+            rom[bank * bank_size + 1] = 1;
+        }
+
+        let mut cart = cartridge(rom);
+        let bank_high = |cart: &Cartridge| cart.read_byte(0x4001);
+
+        cart.set_rom_byte(0x2000, 0x42);
+        assert_eq!((bank_high(&cart), bank(&cart)), (0, 0x42));
+
+        cart.set_rom_byte(0x3000, 0x01);
+        assert_eq!((bank_high(&cart), bank(&cart)), (1, 0x42));
+
+        // each register only changes its own bits
+        cart.set_rom_byte(0x2fff, 0x24);
+        assert_eq!((bank_high(&cart), bank(&cart)), (1, 0x24));
+
+        cart.set_rom_byte(0x3fff, 0xfe);
+        assert_eq!((bank_high(&cart), bank(&cart)), (0, 0x24));
+    }
+
+    #[test]
+    fn mbc5_bank_0_can_be_selected() {
+        let mut cart = cartridge(mbc5_rom(MBC5, 2));
+
+        cart.set_rom_byte(0x2000, 0x05);
+        assert_eq!(bank(&cart), 5);
+
+        cart.set_rom_byte(0x2000, 0x00);
+        assert_eq!(bank(&cart), 0);
+        assert_eq!(bank0(&cart), 0);
+    }
+
+    #[test]
+    fn mbc5_has_16_ram_banks() {
+        let mut cart = cartridge(mbc5_rom(MBC5, 2));
+        cart.set_rom_byte(0x0000, 0x0a);
+
+        for ram_bank in 0..16 {
+            cart.set_rom_byte(0x4000, ram_bank);
+            cart.set_ram_byte(0x0000, ram_bank + 0x10);
+        }
+
+        for ram_bank in 0..16 {
+            cart.set_rom_byte(0x4000, ram_bank);
+            assert_eq!(cart.ram_byte(0x0000), ram_bank + 0x10);
+        }
+    }
+
+    #[test]
+    fn mbc5_rumble_uses_bit_3_of_the_ram_bank() {
+        let mut cart = cartridge(mbc5_rom(MBC5_RUMBLE, 2));
+        cart.set_rom_byte(0x0000, 0x0a);
+        assert_eq!(cart.take_rumble_event(), None);
+
+        // RAM bank 1 with the motor on
+        cart.set_rom_byte(0x4000, 0x09);
+        assert_eq!(cart.take_rumble_event(), Some(true));
+        assert_eq!(cart.take_rumble_event(), None);
+        cart.set_ram_byte(0x0000, 0x55);
+
+        cart.set_rom_byte(0x4000, 0x01);
+        assert_eq!(cart.take_rumble_event(), Some(false));
+        assert_eq!(cart.ram_byte(0x0000), 0x55);
+
+        // the motor state only changes with bit 3
+        cart.set_rom_byte(0x4000, 0x00);
+        assert_eq!(cart.take_rumble_event(), None);
+        assert_eq!(cart.ram_byte(0x0000), 0x00);
+    }
+}
@@ -0,0 +1,313 @@
+//! MBC3 real time clock.
+//!
+//! The clock counts seconds, minutes, hours and a 9-bit day counter. The
+//! game reads a latched copy of the registers, taken when 0x00 and then
+//! 0x01 are written to [0x6000, 0x7fff]. Instead of ticking with the
+//! emulation the clock follows the wall time, so it keeps running while
+//! the emulator is closed.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
+
+/// Size of the serialised state, in the format used by other emulators:
+/// the current and latched registers as 32-bit values followed by a
+/// 64-bit UNIX timestamp, all little-endian
+pub const RTC_DATA_SIZE: usize = 48;
+
+/// Halt flag on the DH register
+const DH_HALT: u8 = 1 << 6;
+/// Day counter carry flag on the DH register
+const DH_CARRY: u8 = 1 << 7;
+/// Bit 8 of the day counter on the DH register
+const DH_DAY_HIGH: u8 = 1 << 0;
+
+/// Days until the day counter overflows
+const DAYS: u64 = 512;
+
+/// Time elapsed since the UNIX epoch
+fn now() -> Duration {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::from_secs(0))
+}
+
+/// Clock registers, in the order they are selected: seconds, minutes,
+/// hours, day counter low and high
+type Registers = [u8; 5];
+
+pub struct Rtc {
+    /// Current registers
+    registers: Registers,
+    /// Registers seen by the game
+    latched: Registers,
+    /// Time of the last update of `registers` since the UNIX epoch, the
+    /// fraction of second is the progress towards the next second
+    timestamp: Duration,
+    /// `true` after writing 0x00 to the latch register
+    latch_armed: bool,
+}
+
+impl Rtc {
+    /// Create a new clock starting at 0
+    pub fn new() -> Rtc {
+        Rtc {
+            registers: [0; 5],
+            latched: [0; 5],
+            timestamp: now(),
+            latch_armed: false,
+        }
+    }
+
+    /// Read a latched register, selected with 0x08-0x0c
+    pub fn read(&self, register: u8) -> u8 {
+        self.latched[(register - 0x08) as usize]
+    }
+
+    /// Write a register, selected with 0x08-0x0c
+    pub fn write(&mut self, register: u8, value: u8) {
+        self.update();
+
+        let index = (register - 0x08) as usize;
+
+        // writing the seconds restarts the current second
+        if index == 0 {
+            self.timestamp = now();
+        }
+
+        self.registers[index] = match index {
+            0 | 1 => value & 0x3f,
+            2 => value & 0x1f,
+            3 => value,
+            _ => value & (DH_HALT | DH_CARRY | DH_DAY_HIGH),
+        };
+    }
+
+    /// Write the latch register, the registers are latched on a 0x00 to
+    /// 0x01 sequence
+    pub fn write_latch(&mut self, value: u8) {
+        if self.latch_armed && value == 0x01 {
+            self.update();
+            self.latched = self.registers;
+        }
+
+        self.latch_armed = value == 0x00;
+    }
+
+    /// Advance the registers to the current time
+    fn update(&mut self) {
+        let now = now();
+
+        // the clock went backwards, don't try to run it backwards
+        if now < self.timestamp {
+            self.timestamp = now;
+            return;
+        }
+
+        // only whole seconds are counted, the rest is kept for the next
+        // update
+        let elapsed = (now - self.timestamp).as_secs();
+        self.timestamp += Duration::from_secs(elapsed);
+
+        if self.registers[4] & DH_HALT != 0 || elapsed == 0 {
+            return;
+        }
+
+        let days = ((self.registers[4] & DH_DAY_HIGH) as u64) << 8 | self.registers[3] as u64;
+        let total = elapsed
+            + self.registers[0] as u64
+            + self.registers[1] as u64 * 60
+            + self.registers[2] as u64 * 3600
+            + days * 86400;
+
+        let days = total / 86400;
+
+        self.registers[0] = (total % 60) as u8;
+        self.registers[1] = (total / 60 % 60) as u8;
+        self.registers[2] = (total / 3600 % 24) as u8;
+        self.registers[3] = days as u8;
+        self.registers[4] = (self.registers[4] & !DH_DAY_HIGH) | ((days >> 8) & 1) as u8;
+
+        if days >= DAYS {
+            self.registers[4] |= DH_CARRY;
+        }
+    }
+
+    /// Serialise the clock state
+    pub fn save(&mut self) -> Vec<u8> {
+        self.update();
+
+        let mut data = Vec::with_capacity(RTC_DATA_SIZE);
+
+        for &register in self.registers.iter().chain(self.latched.iter()) {
+            data.extend_from_slice(&(register as u32).to_le_bytes());
+        }
+
+        data.extend_from_slice(&self.timestamp.as_secs().to_le_bytes());
+
+        data
+    }
+
+    /// Restore the clock state, the time elapsed since it was saved is
+    /// added to the clock. Returns `false` if the data isn't valid.
+    pub fn load(&mut self, data: &[u8]) -> bool {
+        if data.len() != RTC_DATA_SIZE {
+            return false;
+        }
+
+        let word = |index: usize| {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&data[index * 4..index * 4 + 4]);
+            u32::from_le_bytes(bytes) as u8
+        };
+
+        for index in 0..5 {
+            self.registers[index] = word(index);
+            self.latched[index] = word(index + 5);
+        }
+
+        let mut timestamp = [0u8; 8];
+        timestamp.copy_from_slice(&data[40..48]);
+        self.timestamp = Duration::from_secs(u64::from_le_bytes(timestamp));
+
+        self.update();
+
+        true
+    }
+}
+
+/// Unlike the battery backed data, the clock isn't advanced by the time
+/// elapsed since the state was saved: it resumes where it was
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        let elapsed = now().checked_sub(self.timestamp).unwrap_or_else(|| Duration::from_secs(0));
+
+        writer.write_bytes(&self.registers);
+        writer.write_bytes(&self.latched);
+        writer.write_u64(elapsed.as_nanos() as u64);
+        writer.write_bool(self.latch_armed);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        reader.read_into(&mut self.registers)?;
+        reader.read_into(&mut self.latched)?;
+        let elapsed = Duration::from_nanos(reader.read_u64()?);
+        self.timestamp = now().checked_sub(elapsed).unwrap_or_else(|| Duration::from_secs(0));
+        self.latch_armed = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Registers selected with 0x08-0x0c
+    const SECONDS: u8 = 0x08;
+    const MINUTES: u8 = 0x09;
+    const HOURS: u8 = 0x0a;
+    const DAYS_LOW: u8 = 0x0b;
+    const DAYS_HIGH: u8 = 0x0c;
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn reads_the_latched_registers() {
+        let mut rtc = Rtc::new();
+        rtc.write(DAYS_HIGH, DH_HALT);
+        rtc.write(SECONDS, 12);
+        rtc.write(MINUTES, 34);
+        rtc.write(HOURS, 5);
+        rtc.write(DAYS_LOW, 0x42);
+
+        assert_eq!(rtc.read(SECONDS), 0);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.read(SECONDS), 12);
+        assert_eq!(rtc.read(MINUTES), 34);
+        assert_eq!(rtc.read(HOURS), 5);
+        assert_eq!(rtc.read(DAYS_LOW), 0x42);
+        assert_eq!(rtc.read(DAYS_HIGH), DH_HALT);
+
+        // the latched copy doesn't follow the writes
+        rtc.write(MINUTES, 10);
+        assert_eq!(rtc.read(MINUTES), 34);
+    }
+
+    #[test]
+    fn latches_only_on_a_0_1_sequence() {
+        let mut rtc = Rtc::new();
+        rtc.write(DAYS_HIGH, DH_HALT);
+        rtc.write(MINUTES, 20);
+
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(MINUTES), 0);
+
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x02);
+        rtc.write_latch(0x01);
+        assert_eq!(rtc.read(MINUTES), 0);
+
+        latch(&mut rtc);
+        assert_eq!(rtc.read(MINUTES), 20);
+    }
+
+    #[test]
+    fn counts_the_elapsed_time() {
+        let mut rtc = Rtc::new();
+        rtc.write(SECONDS, 50);
+        rtc.write(MINUTES, 59);
+        rtc.write(HOURS, 23);
+        rtc.write(DAYS_LOW, 0xff);
+
+        // 10 seconds later
+        rtc.timestamp -= Duration::from_secs(10);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.read(SECONDS), 0);
+        assert_eq!(rtc.read(MINUTES), 0);
+        assert_eq!(rtc.read(HOURS), 0);
+        assert_eq!(rtc.read(DAYS_LOW), 0);
+        assert_eq!(rtc.read(DAYS_HIGH), DH_DAY_HIGH);
+    }
+
+    #[test]
+    fn sets_the_carry_on_day_overflow() {
+        let mut rtc = Rtc::new();
+        rtc.write(DAYS_LOW, 0xff);
+        rtc.write(DAYS_HIGH, DH_DAY_HIGH);
+
+        rtc.timestamp -= Duration::from_secs(86400);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.read(DAYS_LOW), 0);
+        assert_eq!(rtc.read(DAYS_HIGH), DH_CARRY);
+    }
+
+    #[test]
+    fn halted_clock_doesnt_count() {
+        let mut rtc = Rtc::new();
+        rtc.write(DAYS_HIGH, DH_HALT);
+        rtc.write(SECONDS, 30);
+
+        rtc.timestamp -= Duration::from_secs(100);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.read(SECONDS), 30);
+    }
+
+    #[test]
+    fn writing_the_seconds_restarts_the_second() {
+        let mut rtc = Rtc::new();
+        rtc.timestamp -= Duration::from_millis(900);
+
+        rtc.write(SECONDS, 5);
+        latch(&mut rtc);
+
+        assert_eq!(rtc.read(SECONDS), 5);
+        assert!(now() - rtc.timestamp < Duration::from_millis(500));
+    }
+}
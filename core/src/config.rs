@@ -1,20 +1,85 @@
+use crate::io::bootrom::Boot;
+use crate::io::fault::FaultPolicy;
+use std::io;
+
 pub struct Config {
-    /// Informs if the emulator must run without a
-    /// frontend
-    pub is_headless: bool,
-    /// Informs if it's running in debug mode
+    /// Break into the debugger when a fault pauses the emulation
     pub is_debug: bool,
     /// Rom name
     pub rom_name: String,
+    /// Address to wait for a link cable connection on
+    pub link_listen: Option<String>,
+    /// Address of the instance to connect the link cable to
+    pub link_connect: Option<String>,
+    /// Key bindings file
+    pub bindings: Option<String>,
+    /// What to do on invalid memory accesses
+    pub fault_policy: FaultPolicy,
+    /// Boot ROM dump to run instead of the built-in one
+    pub boot_rom: Option<String>,
+    /// Start the cartridge directly, without running a boot ROM
+    pub skip_boot: bool,
+    /// Memory used to keep the frames to rewind, `None` disables rewinding
+    pub rewind_budget: Option<usize>,
+    /// Pace the frames on the audio output instead of the wall clock
+    pub audio_sync: bool,
+    /// Frames to run in headless mode, `None` outside of it
+    pub max_frames: Option<u64>,
+    /// Serial output that ends a headless run successfully
+    pub success_text: Option<String>,
+    /// Serial output that ends a headless run with a failure
+    pub failure_text: Option<String>,
+    /// PNG file to write the last frame of a headless run to
+    pub screen_dump: Option<String>,
+    /// Copy the serial output to stdout in headless mode
+    pub serial_stdout: bool,
 }
 
+/// Memory used by the rewind buffer when it isn't configured, in MB
+const DEFAULT_REWIND_MEMORY: usize = 64;
+
 impl Config {
     /// Creates a new Config instance from the clap arguments
     pub fn from_clap(matches: clap::ArgMatches) -> Self {
+        let is_debug = matches.occurrences_of("debug") > 0;
+
         Self {
-            is_headless: matches.occurrences_of("headless") > 0,
-            is_debug: matches.occurrences_of("debug") > 0,
+            is_debug,
             rom_name: matches.value_of("ROM").unwrap().to_string(),
+            link_listen: matches.value_of("link-listen").map(String::from),
+            link_connect: matches.value_of("link-connect").map(String::from),
+            bindings: matches.value_of("bindings").map(String::from),
+            // the debugger only runs on the paused faults
+            fault_policy: match matches.value_of("fault-policy") {
+                Some(policy) => policy.parse().unwrap_or_default(),
+                None if is_debug => FaultPolicy::Pause,
+                None => FaultPolicy::default(),
+            },
+            boot_rom: matches.value_of("boot-rom").map(String::from),
+            skip_boot: matches.occurrences_of("skip-boot") > 0,
+            rewind_budget: match matches.value_of("rewind-memory").map(str::parse) {
+                Some(Ok(0)) => None,
+                Some(Ok(megabytes)) => Some(megabytes * 1024 * 1024),
+                _ => Some(DEFAULT_REWIND_MEMORY * 1024 * 1024),
+            },
+            audio_sync: matches.value_of("sync") != Some("clock"),
+            max_frames: matches.value_of("frames").and_then(|frames| frames.parse().ok()),
+            success_text: matches.value_of("until").map(String::from),
+            failure_text: matches.value_of("fail-on").map(String::from),
+            screen_dump: matches.value_of("dump-screen").map(String::from),
+            serial_stdout: matches.occurrences_of("serial-stdout") > 0,
         }
     }
-}
\ No newline at end of file
+
+    /// Get the boot selected by the options, loading the boot ROM dump.
+    /// The errors name the dump.
+    pub fn boot(&self) -> io::Result<Boot> {
+        if self.skip_boot {
+            Ok(Boot::Skip)
+        } else if let Some(ref path) = self.boot_rom {
+            Boot::from_file(path).map_err(|e| io::Error::new(e.kind(), format!("{}: {}", path, e)))
+        } else {
+            Ok(Boot::Internal)
+        }
+    }
+}
@@ -2,6 +2,7 @@
 
 use super::io::Interconnect;
 use self::registers::Registers;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 use std::fmt::{Debug, Error, Formatter};
 
 mod registers;
@@ -12,6 +13,8 @@ pub struct Cpu {
     pub regs: Registers,
     /// CPU halted flag
     pub halted: bool,
+    /// CPU stopped flag, only a joypad input wakes it up
+    pub stopped: bool,
     // interrupts are enabled?
     pub ime: bool,
     // is to disable interrupts
@@ -26,6 +29,7 @@ impl Cpu {
         Cpu {
             regs: Registers::new(),
             halted: false,
+            stopped: false,
             ime: false,
             setdi: 0,
             setei: 0,
@@ -40,6 +44,11 @@ impl Cpu {
         }
     }
 
+    /// set the registers to their power on values, to run a boot ROM
+    pub fn power_on(&mut self) {
+        self.regs = Registers::power_on();
+    }
+
     /// fetch one byte from the interconnect using PC
     fn fetch_byte(&mut self, interconnect: &mut Interconnect) -> u8 {
         let byte = interconnect.read_byte(self.regs.pc);
@@ -82,6 +91,16 @@ impl Cpu {
 
     /// do the internal CPU cycle
     fn do_internal_cycle(&mut self, interconnect: &mut Interconnect) -> u32 {
+        // a stopped CPU waits for a key on a selected joypad line, even
+        // with the interrupt disabled
+        if self.stopped {
+            if !interconnect.joypad.is_input_low() {
+                return 1;
+            }
+
+            self.stopped = false;
+        }
+
         // update IME if needed
         self.updateIme();
 
@@ -247,7 +266,13 @@ impl Cpu {
             }
             // STOP
             0x10 => {
-                interconnect.switch_speed();
+                // the second byte of the opcode is ignored
+                self.fetch_byte(interconnect);
+
+                // STOP is also used to switch the speed on color mode
+                if !interconnect.switch_speed() {
+                    self.stopped = true;
+                }
                 1
             }
             // LD (DE),A
@@ -1605,3 +1630,34 @@ impl Cpu {
         self.regs.pc = pc as u16;
     }
 }
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.regs.af());
+        writer.write_u16(self.regs.bc());
+        writer.write_u16(self.regs.de());
+        writer.write_u16(self.regs.hl());
+        writer.write_u16(self.regs.sp);
+        writer.write_u16(self.regs.pc);
+        writer.write_bool(self.halted);
+        writer.write_bool(self.stopped);
+        writer.write_bool(self.ime);
+        writer.write_u8(self.setdi);
+        writer.write_u8(self.setei);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.regs.set_af(reader.read_u16()?);
+        self.regs.set_bc(reader.read_u16()?);
+        self.regs.set_de(reader.read_u16()?);
+        self.regs.set_hl(reader.read_u16()?);
+        self.regs.sp = reader.read_u16()?;
+        self.regs.pc = reader.read_u16()?;
+        self.halted = reader.read_bool()?;
+        self.stopped = reader.read_bool()?;
+        self.ime = reader.read_bool()?;
+        self.setdi = reader.read_u8()?;
+        self.setei = reader.read_u8()?;
+        Ok(())
+    }
+}
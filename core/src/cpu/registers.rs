@@ -48,15 +48,15 @@ impl Registers {
     /// initial values after the internal ROM execute
     pub fn new() -> Registers {
         let mut instance = Registers {
-            a: 0x00,
+            a: 0x01,
             b: 0x00,
-            c: 0x00,
+            c: 0x13,
             d: 0x00,
-            e: 0x00,
-            h: 0x00,
-            l: 0x00,
-            sp: 0x0000,
-            pc: 0x0000,
+            e: 0xd8,
+            h: 0x01,
+            l: 0x4d,
+            sp: 0xfffe,
+            pc: 0x0100,
             flags: Flags {
                 c: false,
                 h: false,
@@ -75,10 +75,41 @@ impl Registers {
     /// create a new Register instance for GBC and set the
     /// regs for initial value after the internal ROM execute
     pub fn new_gbc() -> Registers {
-        Registers {
+        let mut instance = Registers {
             a: 0x11,
+            b: 0x00,
+            c: 0x00,
+            d: 0xff,
+            e: 0x56,
+            h: 0x00,
+            l: 0x0d,
+            ..Registers::new()
+        };
+
+        instance.set_f(0x80);
+
+        instance
+    }
+
+    /// create a new Registers instance with the values on power on,
+    /// before running the boot ROM
+    pub fn power_on() -> Registers {
+        let mut instance = Registers {
+            a: 0x00,
+            b: 0x00,
+            c: 0x00,
+            d: 0x00,
+            e: 0x00,
+            h: 0x00,
+            l: 0x00,
+            sp: 0x0000,
+            pc: 0x0000,
             ..Registers::new()
-        }
+        };
+
+        instance.set_f(0x00);
+
+        instance
     }
 
     // -------------------------------------------------------------------- [Gets]
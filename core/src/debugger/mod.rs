@@ -6,28 +6,34 @@ use self::command::Command;
 
 mod command;
 
-pub struct Debugger {
-  machine: Machine,
+/// Command line debugger on the standard input, working on a machine
+/// stopped by the embedder
+pub struct Debugger<'a> {
+  machine: &'a mut Machine,
   last_command: Option<Command>,
 }
 
-impl Debugger {
+impl<'a> Debugger<'a> {
   /// Create a new Debugger instance.
-  pub fn new(machine: Machine) -> Self {
+  pub fn new(machine: &'a mut Machine) -> Self {
     Self {
       machine,
       last_command: None,
     }
   }
 
-  /// Run the debugger
+  /// Run the debugger until it's exited, or the standard input is closed
   pub fn run(&mut self) {
     loop {
       print!("rustboy> ");
       stdout().flush().unwrap();
 
+      let input = match read_stdio() {
+        Some(input) => input,
+        None => break,
+      };
 
-      let command = match (read_stdio().parse(), self.last_command) {
+      let command = match (input.parse(), self.last_command) {
         (Ok(Command::Repeat), Some(c)) => Ok(c),
         (Ok(Command::Repeat), None) => Err("No last command".into()),
         (Ok(c), _) => Ok(c),
@@ -47,17 +53,24 @@ impl Debugger {
 
   fn step(&mut self, count: usize) {
     for _ in 0..count {
-      print_cpu_state(&self.machine);
-      self.machine.emulate();
+      print_cpu_state(self.machine);
+
+      if let Err(fault) = self.machine.emulate() {
+        println!("{}", fault);
+        break;
+      }
     }
   }
 }
 
-/// Read from the keyboard
-fn read_stdio() -> String {
+/// Read a line from the keyboard, `None` at the end of the input
+fn read_stdio() -> Option<String> {
   let mut input = String::new();
-  stdin().read_line(&mut input).unwrap();
-  input.trim().into()
+
+  match stdin().read_line(&mut input) {
+    Ok(0) | Err(_) => None,
+    Ok(_) => Some(input.trim().into()),
+  }
 }
 
 /// Print the current CPU state
@@ -1,11 +1,33 @@
-use self::types::Color;
+use self::types::{Color, SCREEN_WIDTH};
+use crate::io::io_map;
+use crate::io::irq::{Irq, Interrupt};
+use crate::io::GbMode;
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
 
 pub mod types;
+pub mod png;
 
 const VOAM_SIZE: usize = 0xa0;
 
-const ACCESS_OAM_CYCLES: isize = 21;
+/// Dots spent searching the OAM at the start of each visible line
+const ACCESS_OAM_CYCLES: isize = 80;
+/// Dots spent transferring pixels to the LCD driver
+const ACCESS_VRAM_CYCLES: isize = 172;
+/// Dots left in the line after the pixel transfer ends
+const HBLANK_CYCLES: isize = 204;
+/// Dots in a full scanline, used for each line of the VBlank period
+const VBLANK_LINE_CYCLES: isize = 456;
+/// First line of the VBlank period
+const VBLANK_LINE: u8 = 144;
+/// Last line of the VBlank period, after it we go back to line 0
+const LAST_LINE: u8 = 153;
+/// Maximum number of sprites the hardware can display on a single line
+const MAX_SPRITES_PER_LINE: usize = 10;
+/// Dots taken by a VRAM DMA to copy a 16 bytes block
+pub const VRAM_DMA_BLOCK_CYCLES: u32 = 32;
 const CHARACTER_RAM_TILES: usize = 384;
+/// Size of the color palette memory, 8 palettes of 4 colors
+const COLOR_PALETTE_SIZE: usize = 64;
 const OAM_SPRITES: usize = 40;
 const TILE_MAP_SIZE: usize = 0x400;
 const UNDEFINED_READ: u8 = 0xff;
@@ -25,7 +47,8 @@ impl Tile {
 
 bitflags!(
     struct SpriteFlags: u8 {
-        const UNUSED_MASK = 0b_0000_1111;
+        const CGB_PALETTE = 0b_0000_0111;
+        const VRAM_BANK   = 0b_0000_1000;
         const PALETTE     = 0b_0001_0000;
         const FLIPX       = 0b_0010_0000;
         const FLIPY       = 0b_0100_0000;
@@ -33,6 +56,24 @@ bitflags!(
     }
 );
 
+bitflags!(
+    /// Background map attributes, stored on VRAM bank 1 (CGB only)
+    struct BgAttributes: u8 {
+        const PALETTE     = 0b_0000_0111;
+        const VRAM_BANK   = 0b_0000_1000;
+        const FLIPX       = 0b_0010_0000;
+        const FLIPY       = 0b_0100_0000;
+        const PRIORITY    = 0b_1000_0000;
+    }
+);
+
+impl BgAttributes {
+    /// Number of the color palette used by the tile
+    fn palette(&self) -> usize {
+        (*self & BgAttributes::PALETTE).bits() as usize
+    }
+}
+
 #[derive(Clone, Copy)]
 struct Sprite {
     x: u8,
@@ -72,9 +113,16 @@ bitflags!(
     const VBLANK_INT = 1 << 4;
     const ACCESS_OAM_INT = 1 << 5;
     const COMPARE_INT = 1 << 6;
+
+    /// Bits that can be written by the CPU
+    const WRITABLE = Stat::HBLANK_INT.bits | Stat::VBLANK_INT.bits |
+                     Stat::ACCESS_OAM_INT.bits | Stat::COMPARE_INT.bits;
   }
 );
 
+/// Unused STAT bit, always reads as 1
+const STAT_UNUSED_MASK: u8 = 1 << 7;
+
 struct Palette {
     off: Color,
     light: Color,
@@ -113,6 +161,59 @@ impl Palette {
     }
 }
 
+/// Color palette memory of the Game Boy Color. It's accessed through an
+/// index register (BCPS/OCPS) and a data register (BCPD/OCPD), each color
+/// takes two bytes in the RGB555 format.
+struct ColorPalette {
+    data: [u8; COLOR_PALETTE_SIZE],
+    /// Index of the byte accessed through the data register
+    index: u8,
+    /// Advance the index after each data write
+    auto_increment: bool,
+}
+
+impl ColorPalette {
+    fn new() -> Self {
+        ColorPalette {
+            data: [0xff; COLOR_PALETTE_SIZE],
+            index: 0,
+            auto_increment: false,
+        }
+    }
+
+    fn read_index(&self) -> u8 {
+        // Bit 6 is unused and always reads as 1
+        let auto_increment = if self.auto_increment { 0x80 } else { 0 };
+        auto_increment | 0x40 | self.index
+    }
+
+    fn write_index(&mut self, value: u8) {
+        self.index = value & 0x3f;
+        self.auto_increment = value & 0x80 != 0;
+    }
+
+    fn read_data(&self) -> u8 {
+        self.data[self.index as usize]
+    }
+
+    fn write_data(&mut self, value: u8) {
+        self.data[self.index as usize] = value;
+
+        if self.auto_increment {
+            self.index = (self.index + 1) & 0x3f;
+        }
+    }
+
+    /// Get the RGB555 value of a color number in the given palette
+    fn rgb555(&self, palette: usize, color: u8) -> u16 {
+        let offset = palette * 8 + color as usize * 2;
+        let low = self.data[offset] as u16;
+        let high = self.data[offset + 1] as u16;
+
+        ((high << 8) | low) & 0x7fff
+    }
+}
+
 #[derive(PartialEq, Eq)]
 enum Mode {
     AccessOam,
@@ -121,7 +222,21 @@ enum Mode {
     VBlank,
 }
 
+impl Mode {
+    /// Value of the mode as reported on the STAT register
+    fn bits(&self) -> u8 {
+        match *self {
+            Mode::HBlank => 0,
+            Mode::VBlank => 1,
+            Mode::AccessOam => 2,
+            Mode::AccessVram => 3,
+        }
+    }
+}
+
 pub struct Gpu {
+    /// Classic or color mode
+    gbmode: GbMode,
     /// LCDC (LCD Control)
     control: Control,
     /// STATE (LCDC Status)
@@ -138,25 +253,46 @@ pub struct Gpu {
     window_x: u8,
     /// Window Y Position,
     window_y: u8,
+    /// Line of the window to be drawn next. The window keeps its own
+    /// line counter, which only advances on lines where it's visible
+    window_line: u8,
     /// Background palette
     bg_palette: Palette,
     /// Object palette 0
     obj_palette0: Palette,
     /// Object palette 1
     obj_palette1: Palette,
+    /// Background color palettes (CGB only)
+    bg_color_palette: ColorPalette,
+    /// Object color palettes (CGB only)
+    obj_color_palette: ColorPalette,
     mode: Mode,
     cycles: isize,
-    character_ram: [Tile; CHARACTER_RAM_TILES],
+    /// State of the STAT interrupt line. The interrupt is only requested
+    /// when the line goes from low to high
+    stat_line: bool,
+    /// Set when a HBlank starts, used to trigger the HBlank DMA
+    hblank_started: bool,
+    /// Set when a VBlank starts, the frame is complete on the back buffer
+    vblank_started: bool,
+    /// Character RAM of both VRAM banks, bank 1 is only used on color mode
+    character_ram: [[Tile; CHARACTER_RAM_TILES]; 2],
     oam: [Sprite; OAM_SPRITES],
     tile_map1: [u8; TILE_MAP_SIZE],
     tile_map2: [u8; TILE_MAP_SIZE],
+    /// Attributes of the tile map 1, on VRAM bank 1
+    tile_attributes1: [u8; TILE_MAP_SIZE],
+    /// Attributes of the tile map 2, on VRAM bank 1
+    tile_attributes2: [u8; TILE_MAP_SIZE],
     pub back_buffer: Box<types::ScreenBuffer>,
-    vramBank: u8,
+    /// VRAM bank mapped for the CPU
+    vram_bank: u8,
 }
 
 impl Gpu {
     pub fn new() -> Gpu {
         Gpu {
+            gbmode: GbMode::Classic,
             control: Control::empty(),
             status: Stat::empty(),
             current_line: 0,
@@ -165,17 +301,34 @@ impl Gpu {
             scroll_y: 0,
             window_x: 0,
             window_y: 0,
+            window_line: 0,
             bg_palette: Palette::new(),
             obj_palette0: Palette::new(),
             obj_palette1: Palette::new(),
-            mode: Mode::AccessOam,
+            bg_color_palette: ColorPalette::new(),
+            obj_color_palette: ColorPalette::new(),
+            // The LCD starts off, which leaves the controller in HBlank
+            mode: Mode::HBlank,
             cycles: ACCESS_OAM_CYCLES,
-            character_ram: [Tile::new(); CHARACTER_RAM_TILES],
+            stat_line: false,
+            hblank_started: false,
+            vblank_started: false,
+            character_ram: [[Tile::new(); CHARACTER_RAM_TILES]; 2],
             oam: [Sprite::new(); OAM_SPRITES],
             tile_map1: [0; TILE_MAP_SIZE],
             tile_map2: [0; TILE_MAP_SIZE],
+            tile_attributes1: [0; TILE_MAP_SIZE],
+            tile_attributes2: [0; TILE_MAP_SIZE],
             back_buffer: Box::new(types::SCREEN_EMPTY),
-            vramBank: 0,
+            vram_bank: 0,
+        }
+    }
+
+    /// Create a new Gpu instance for the Game Boy Color
+    pub fn new_gbc() -> Gpu {
+        Gpu {
+            gbmode: GbMode::Color,
+            ..Gpu::new()
         }
     }
 
@@ -184,8 +337,7 @@ impl Gpu {
             return;
         }
 
-        let tile = &mut self.character_ram[address as usize / 16];
-        tile.data[address as usize % 16] = value;
+        self.set_character_ram(address, value);
     }
 
     pub fn read_character_ram(&self, address: u16) -> u8 {
@@ -193,7 +345,7 @@ impl Gpu {
             return UNDEFINED_READ;
         }
 
-        let tile = &self.character_ram[address as usize / 16];
+        let tile = &self.character_ram[self.vram_bank as usize][address as usize / 16];
         tile.data[address as usize % 16]
     }
 
@@ -202,7 +354,7 @@ impl Gpu {
             return;
         }
 
-        self.tile_map1[address as usize] = value;
+        self.set_tile_map1(address, value);
     }
 
     pub fn read_tile_map1(&self, address: u16) -> u8 {
@@ -210,7 +362,11 @@ impl Gpu {
             return UNDEFINED_READ;
         }
 
-        self.tile_map1[address as usize]
+        if self.vram_bank == 1 {
+            self.tile_attributes1[address as usize]
+        } else {
+            self.tile_map1[address as usize]
+        }
     }
 
     pub fn write_tile_map2(&mut self, address: u16, value: u8) {
@@ -218,7 +374,7 @@ impl Gpu {
             return;
         }
 
-        self.tile_map2[address as usize] = value;
+        self.set_tile_map2(address, value);
     }
 
     pub fn read_tile_map2(&self, address: u16) -> u8 {
@@ -226,37 +382,441 @@ impl Gpu {
             return UNDEFINED_READ;
         }
 
-        self.tile_map2[address as usize]
+        if self.vram_bank == 1 {
+            self.tile_attributes2[address as usize]
+        } else {
+            self.tile_map2[address as usize]
+        }
+    }
+
+    /// Write to the VRAM without checking the mode, used by the VRAM DMA.
+    /// The address is relative to the VRAM start (0x8000).
+    pub fn write_vram_dma(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000 ... 0x17ff => self.set_character_ram(address, value),
+            0x1800 ... 0x1bff => self.set_tile_map1(address - 0x1800, value),
+            _ => self.set_tile_map2(address - 0x1c00, value),
+        }
     }
 
-    pub fn set_bg_palette(&mut self, value: u8) {
-        self.bg_palette.set_bits(value);
+    fn set_character_ram(&mut self, address: u16, value: u8) {
+        let tile = &mut self.character_ram[self.vram_bank as usize][address as usize / 16];
+        tile.data[address as usize % 16] = value;
     }
 
-    pub fn do_cycle(&mut self, _trick: u32) {
+    fn set_tile_map1(&mut self, address: u16, value: u8) {
+        if self.vram_bank == 1 {
+            self.tile_attributes1[address as usize] = value;
+        } else {
+            self.tile_map1[address as usize] = value;
+        }
+    }
+
+    fn set_tile_map2(&mut self, address: u16, value: u8) {
+        if self.vram_bank == 1 {
+            self.tile_attributes2[address as usize] = value;
+        } else {
+            self.tile_map2[address as usize] = value;
+        }
+    }
+
+    /// Returns `true` once per HBlank, when a HBlank DMA block must be
+    /// transferred
+    pub fn take_hblank(&mut self) -> bool {
+        let started = self.hblank_started;
+        self.hblank_started = false;
+        started
+    }
+
+    /// Returns `true` once per VBlank, when a new frame is ready
+    pub fn take_vblank(&mut self) -> bool {
+        let started = self.vblank_started;
+        self.vblank_started = false;
+        started
+    }
+
+    /// Returns `true` while the LCD is on
+    pub fn is_lcd_on(&self) -> bool {
+        self.control.contains(Control::LCD_ON)
+    }
+
+    /// Advance the LCD controller by the given number of dots.
+    ///
+    /// A single CPU instruction can span more than one mode, so we keep
+    /// switching modes until all the ticks are consumed.
+    pub fn do_cycle(&mut self, ticks: u32, irq: &mut Irq) {
         if !self.control.contains(Control::LCD_ON) {
             return;
         }
+
+        self.cycles -= ticks as isize;
+
+        // Register writes since the last cycle may have changed the
+        // STAT line (e.g. a new LYC value)
+        self.update_stat_line(irq);
+
+        while self.cycles <= 0 {
+            self.next_mode(irq);
+            self.update_stat_line(irq);
+        }
+    }
+
+    /// Update the LY=LYC coincidence flag and the STAT interrupt line,
+    /// requesting the interrupt on its rising edge
+    fn update_stat_line(&mut self, irq: &mut Irq) {
+        let coincidence = self.current_line == self.compare_line;
+        self.status.set(Stat::COMPARE, coincidence);
+
+        let line = match self.mode {
+            Mode::HBlank => self.status.contains(Stat::HBLANK_INT),
+            Mode::VBlank => self.status.contains(Stat::VBLANK_INT),
+            Mode::AccessOam => self.status.contains(Stat::ACCESS_OAM_INT),
+            Mode::AccessVram => false,
+        } || (coincidence && self.status.contains(Stat::COMPARE_INT));
+
+        if line && !self.stat_line {
+            irq.request_interrupt(Interrupt::LCDState);
+        }
+
+        self.stat_line = line;
+    }
+
+    /// Move to the mode that follows the current one
+    fn next_mode(&mut self, irq: &mut Irq) {
+        match self.mode {
+            Mode::AccessOam => self.set_mode(Mode::AccessVram, irq),
+            Mode::AccessVram => {
+                self.render_line();
+                self.set_mode(Mode::HBlank, irq);
+            }
+            Mode::HBlank => {
+                self.current_line += 1;
+
+                if self.current_line < VBLANK_LINE {
+                    self.set_mode(Mode::AccessOam, irq);
+                } else {
+                    self.set_mode(Mode::VBlank, irq);
+                }
+            }
+            Mode::VBlank => {
+                self.current_line += 1;
+
+                if self.current_line > LAST_LINE {
+                    self.current_line = 0;
+                    self.window_line = 0;
+                    self.set_mode(Mode::AccessOam, irq);
+                } else {
+                    self.cycles += VBLANK_LINE_CYCLES;
+                }
+            }
+        }
+    }
+
+    /// Switch to a new mode and schedule its duration. The STAT
+    /// interrupts are handled by `update_stat_line`.
+    fn set_mode(&mut self, mode: Mode, irq: &mut Irq) {
+        self.mode = mode;
+
+        match self.mode {
+            Mode::AccessOam => self.cycles += ACCESS_OAM_CYCLES,
+            Mode::AccessVram => self.cycles += ACCESS_VRAM_CYCLES,
+            Mode::HBlank => {
+                self.cycles += HBLANK_CYCLES;
+                self.hblank_started = true;
+            }
+            Mode::VBlank => {
+                self.cycles += VBLANK_LINE_CYCLES;
+                self.vblank_started = true;
+                irq.request_interrupt(Interrupt::VBank);
+            }
+        }
+    }
+
+    /// Render the current line into the back buffer
+    fn render_line(&mut self) {
+        let color_mode = self.gbmode == GbMode::Color;
+
+        // Color numbers (before the palette is applied) and attributes of
+        // the background and window, sprites need them to know if they
+        // are hidden behind the background
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        let mut bg_attributes = [BgAttributes::empty(); SCREEN_WIDTH];
+
+        // On color mode BG_ON doesn't hide the background, it only
+        // removes its priority over the sprites
+        let bg_visible = color_mode || self.control.contains(Control::BG_ON);
+
+        if bg_visible {
+            self.render_background(&mut bg_colors, &mut bg_attributes);
+
+            if self.control.contains(Control::WINDOW_ON) {
+                self.render_window(&mut bg_colors, &mut bg_attributes);
+            }
+        }
+
+        let line_offset = self.current_line as usize * SCREEN_WIDTH;
+
+        for x in 0..SCREEN_WIDTH {
+            self.back_buffer[line_offset + x] = if color_mode {
+                self.bg_color_palette.rgb555(bg_attributes[x].palette(), bg_colors[x])
+            } else if bg_visible {
+                self.bg_palette.get(&Color::from_u8(bg_colors[x])).to_rgb555()
+            } else {
+                Color::Off.to_rgb555()
+            };
+        }
+
+        if self.control.contains(Control::OBJ_ON) {
+            self.render_sprites(&bg_colors, &bg_attributes);
+        }
+    }
+
+    /// Fill the line with the background color numbers
+    fn render_background(&self,
+                         bg_colors: &mut [u8; SCREEN_WIDTH],
+                         bg_attributes: &mut [BgAttributes; SCREEN_WIDTH]) {
+        let map_high = self.control.contains(Control::BG_MAP);
+        let y = self.current_line.wrapping_add(self.scroll_y);
+
+        for x in 0..SCREEN_WIDTH {
+            let map_x = (x as u8).wrapping_add(self.scroll_x);
+            let (color, attributes) = self.map_pixel(map_high, map_x, y);
+
+            bg_colors[x] = color;
+            bg_attributes[x] = attributes;
+        }
+    }
+
+    /// Draw the window over the background color numbers
+    fn render_window(&mut self,
+                     bg_colors: &mut [u8; SCREEN_WIDTH],
+                     bg_attributes: &mut [BgAttributes; SCREEN_WIDTH]) {
+        // WX holds the window position plus 7
+        let start = self.window_x as isize - 7;
+
+        if self.current_line < self.window_y || start >= SCREEN_WIDTH as isize {
+            return;
+        }
+
+        let map_high = self.control.contains(Control::WINDOW_MAP);
+
+        for x in start.max(0) as usize..SCREEN_WIDTH {
+            let map_x = (x as isize - start) as u8;
+            let (color, attributes) = self.map_pixel(map_high, map_x, self.window_line);
+
+            bg_colors[x] = color;
+            bg_attributes[x] = attributes;
+        }
+
+        self.window_line = self.window_line.wrapping_add(1);
+    }
+
+    /// Draw up to 10 sprites of the current line into the back buffer
+    fn render_sprites(&mut self,
+                      bg_colors: &[u8; SCREEN_WIDTH],
+                      bg_attributes: &[BgAttributes; SCREEN_WIDTH]) {
+        let color_mode = self.gbmode == GbMode::Color;
+        let height = if self.control.contains(Control::OBJ_SIZE) { 16 } else { 8 };
+        let line = self.current_line as isize;
+
+        // The OAM search picks the first 10 sprites in OAM order that
+        // intersect the line, even if they are off-screen horizontally
+        let mut visible = [0usize; MAX_SPRITES_PER_LINE];
+        let mut count = 0;
+
+        for (index, sprite) in self.oam.iter().enumerate() {
+            let y = sprite.y.wrapping_add(16) as isize - 16;
+
+            if line >= y && line < y + height {
+                visible[count] = index;
+                count += 1;
+
+                if count == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+
+        // On the classic Game Boy sprites with a lower X coordinate are
+        // drawn on top, when two sprites share the same X the first one
+        // in OAM wins. The sort is stable so the OAM order is kept for
+        // ties. On color mode only the OAM order matters.
+        let visible = &mut visible[..count];
+        if !color_mode {
+            let oam = &self.oam;
+            visible.sort_by_key(|&index| oam[index].x);
+        }
+
+        // On color mode clearing BG_ON puts every sprite above the
+        // background
+        let bg_priority = !color_mode || self.control.contains(Control::BG_ON);
+
+        let line_offset = self.current_line as usize * SCREEN_WIDTH;
+        let mut drawn = [false; SCREEN_WIDTH];
+
+        for &index in visible.iter() {
+            let sprite = self.oam[index];
+            let sprite_x = sprite.x.wrapping_add(8) as isize - 8;
+            let sprite_y = sprite.y.wrapping_add(16) as isize - 16;
+
+            let mut row = (line - sprite_y) as u8;
+            if sprite.flags.contains(SpriteFlags::FLIPY) {
+                row = height as u8 - 1 - row;
+            }
+
+            // On 8x16 mode the least significant bit of the tile number
+            // is ignored, the bottom half uses the following tile
+            let tile_num = if height == 16 { sprite.tile_num & 0xfe } else { sprite.tile_num };
+            let tile = tile_num as usize + row as usize / 8;
+
+            let bank = if color_mode && sprite.flags.contains(SpriteFlags::VRAM_BANK) { 1 } else { 0 };
+
+            for column in 0..8 {
+                let x = sprite_x + column;
+
+                if x < 0 || x >= SCREEN_WIDTH as isize || drawn[x as usize] {
+                    continue;
+                }
+
+                let x = x as usize;
+
+                let tile_x = if sprite.flags.contains(SpriteFlags::FLIPX) {
+                    7 - column as u8
+                } else {
+                    column as u8
+                };
+
+                let color = self.tile_pixel(bank, tile, tile_x, row % 8);
+
+                // Color 0 is transparent for sprites
+                if color == 0 {
+                    continue;
+                }
+
+                // The pixel belongs to this sprite even if it ends up
+                // hidden behind the background
+                drawn[x] = true;
+
+                let behind_bg = sprite.flags.contains(SpriteFlags::PRIORITY) ||
+                    bg_attributes[x].contains(BgAttributes::PRIORITY);
+
+                if bg_priority && behind_bg && bg_colors[x] != 0 {
+                    continue;
+                }
+
+                self.back_buffer[line_offset + x] = if color_mode {
+                    let palette = (sprite.flags & SpriteFlags::CGB_PALETTE).bits() as usize;
+                    self.obj_color_palette.rgb555(palette, color)
+                } else if sprite.flags.contains(SpriteFlags::PALETTE) {
+                    self.obj_palette1.get(&Color::from_u8(color)).to_rgb555()
+                } else {
+                    self.obj_palette0.get(&Color::from_u8(color)).to_rgb555()
+                };
+            }
+        }
     }
 
-    pub fn set_scroll_y(&mut self, value: u8) {
-        self.scroll_y = value;
+    /// Get the color number and attributes of the pixel at (`x`, `y`) of
+    /// a tile map.
+    ///
+    /// When `BG_ADDR` is set tiles are indexed from 0x8000 with an
+    /// unsigned number, otherwise tiles are indexed from 0x9000 with a
+    /// signed one.
+    fn map_pixel(&self, map_high: bool, x: u8, y: u8) -> (u8, BgAttributes) {
+        let (map, attributes) = if map_high {
+            (&self.tile_map2, &self.tile_attributes2)
+        } else {
+            (&self.tile_map1, &self.tile_attributes1)
+        };
+
+        let index = (y as usize / 8) * 32 + x as usize / 8;
+        let tile_num = map[index];
+
+        let tile = if self.control.contains(Control::BG_ADDR) {
+            tile_num as usize
+        } else {
+            (256 + (tile_num as i8) as isize) as usize
+        };
+
+        // Attributes only exist on color mode
+        let attributes = if self.gbmode == GbMode::Color {
+            BgAttributes::from_bits_truncate(attributes[index])
+        } else {
+            BgAttributes::empty()
+        };
+
+        let mut tile_x = x % 8;
+        if attributes.contains(BgAttributes::FLIPX) {
+            tile_x = 7 - tile_x;
+        }
+
+        let mut tile_y = y % 8;
+        if attributes.contains(BgAttributes::FLIPY) {
+            tile_y = 7 - tile_y;
+        }
+
+        let bank = if attributes.contains(BgAttributes::VRAM_BANK) { 1 } else { 0 };
+
+        (self.tile_pixel(bank, tile, tile_x, tile_y), attributes)
     }
 
-    pub fn set_scroll_x(&mut self, value: u8) {
-        self.scroll_x = value;
+    /// Get the color number of the pixel at (`x`, `y`) of a tile
+    fn tile_pixel(&self, bank: usize, tile: usize, x: u8, y: u8) -> u8 {
+        let tile = &self.character_ram[bank][tile];
+        let low = tile.data[y as usize * 2];
+        let high = tile.data[y as usize * 2 + 1];
+
+        // Bit 7 holds the leftmost pixel
+        let bit = 7 - x;
+
+        (((high >> bit) & 1) << 1) | ((low >> bit) & 1)
     }
 
-    pub fn set_window_y(&mut self, value: u8) {
-        self.window_y = value;
+    /// Write to the LCD Control register.
+    ///
+    /// Turning the LCD off resets LY and leaves the controller in HBlank,
+    /// turning it back on restarts the frame from the OAM search of line 0.
+    fn set_control(&mut self, value: u8) {
+        let control = Control::from_bits_truncate(value);
+
+        if self.control.contains(Control::LCD_ON) && !control.contains(Control::LCD_ON) {
+            self.current_line = 0;
+            self.mode = Mode::HBlank;
+            self.stat_line = false;
+        }
+
+        if !self.control.contains(Control::LCD_ON) && control.contains(Control::LCD_ON) {
+            self.current_line = 0;
+            self.window_line = 0;
+            self.mode = Mode::AccessOam;
+            self.cycles = ACCESS_OAM_CYCLES;
+        }
+
+        self.control = control;
     }
 
-    pub fn set_window_x(&mut self, value: u8) {
-        self.window_x = value;
+    /// Write to the OAM memory, the CPU can't access it while the OAM
+    /// is being searched or during the pixel transfer
+    pub fn write_oam(&mut self, address: u16, value: u8) {
+        if self.mode == Mode::AccessVram || self.mode == Mode::AccessOam {
+            return;
+        }
+
+        self.write_oam_dma(address, value);
     }
 
-    pub fn set_vramBank(&mut self, value: u8) {
-        self.vramBank = value;
+    /// Write to the OAM memory without checking the mode, the OAM DMA
+    /// has access to it at any time
+    pub fn write_oam_dma(&mut self, address: u16, value: u8) {
+        let sprite = &mut self.oam[address as usize / 4];
+
+        // Coordinates are stored relative to the screen origin
+        match address as usize % 4 {
+            3 => sprite.flags = SpriteFlags::from_bits_truncate(value),
+            2 => sprite.tile_num = value,
+            1 => sprite.x = value.wrapping_sub(8),
+            _ => sprite.y = value.wrapping_sub(16),
+        }
     }
 
     /// Read from the OAM memory
@@ -275,10 +835,169 @@ impl Gpu {
         }
     }
 
-    /// Read a byte from the VRAM
+    /// Read a byte from the LCD registers
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
-            _ => panic!("GPU does not handle read {:#x}", address),
+            io_map::LCDC => self.control.bits(),
+            io_map::LCD_STAT => {
+                STAT_UNUSED_MASK | self.status.bits() | self.mode.bits()
+            }
+            io_map::LCD_SCY => self.scroll_y,
+            io_map::LCD_SCX => self.scroll_x,
+            io_map::LCD_LY => self.current_line,
+            io_map::LCD_LYC => self.compare_line,
+            io_map::LCD_BGP => self.bg_palette.bits,
+            io_map::LCD_OBP0 => self.obj_palette0.bits,
+            io_map::LCD_OBP1 => self.obj_palette1.bits,
+            io_map::LCD_WY => self.window_y,
+            io_map::LCD_WX => self.window_x,
+            io_map::VBK => 0xfe | self.vram_bank,
+            io_map::BCPS => self.bg_color_palette.read_index(),
+            io_map::BCPD => self.bg_color_palette.read_data(),
+            io_map::OCPS => self.obj_color_palette.read_index(),
+            io_map::OCPD => self.obj_color_palette.read_data(),
+            _ => 0xff,
+        }
+    }
+
+    /// Write a byte to the LCD registers
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            io_map::LCDC => self.set_control(value),
+            io_map::LCD_STAT => {
+                let status = Stat::from_bits_truncate(value) & Stat::WRITABLE;
+                self.status = (self.status - Stat::WRITABLE) | status;
+            }
+            io_map::LCD_SCY => self.scroll_y = value,
+            io_map::LCD_SCX => self.scroll_x = value,
+            // LY is read only
+            io_map::LCD_LY => (),
+            io_map::LCD_LYC => self.compare_line = value,
+            io_map::LCD_BGP => self.bg_palette.set_bits(value),
+            io_map::LCD_OBP0 => self.obj_palette0.set_bits(value),
+            io_map::LCD_OBP1 => self.obj_palette1.set_bits(value),
+            io_map::LCD_WY => self.window_y = value,
+            io_map::LCD_WX => self.window_x = value,
+            io_map::VBK => self.vram_bank = value & 0x1,
+            io_map::BCPS => self.bg_color_palette.write_index(value),
+            io_map::BCPD => self.bg_color_palette.write_data(value),
+            io_map::OCPS => self.obj_color_palette.write_index(value),
+            io_map::OCPD => self.obj_color_palette.write_data(value),
+            _ => (),
+        }
+    }
+}
+
+impl SaveState for ColorPalette {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+        writer.write_u8(self.index);
+        writer.write_bool(self.auto_increment);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        reader.read_into(&mut self.data)?;
+        self.index = reader.read_u8()? & 0x3f;
+        self.auto_increment = reader.read_bool()?;
+        Ok(())
+    }
+}
+
+impl SaveState for Gpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.control.bits());
+        writer.write_u8(self.status.bits());
+        writer.write_u8(self.current_line);
+        writer.write_u8(self.compare_line);
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.window_x);
+        writer.write_u8(self.window_y);
+        writer.write_u8(self.window_line);
+        writer.write_u8(self.bg_palette.bits);
+        writer.write_u8(self.obj_palette0.bits);
+        writer.write_u8(self.obj_palette1.bits);
+        self.bg_color_palette.save_state(writer);
+        self.obj_color_palette.save_state(writer);
+        writer.write_u8(self.mode.bits());
+        writer.write_i32(self.cycles as i32);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.hblank_started);
+
+        for bank in self.character_ram.iter() {
+            for tile in bank.iter() {
+                writer.write_bytes(&tile.data);
+            }
+        }
+
+        for sprite in self.oam.iter() {
+            writer.write_u8(sprite.y);
+            writer.write_u8(sprite.x);
+            writer.write_u8(sprite.tile_num);
+            writer.write_u8(sprite.flags.bits());
         }
+
+        writer.write_bytes(&self.tile_map1);
+        writer.write_bytes(&self.tile_map2);
+        writer.write_bytes(&self.tile_attributes1);
+        writer.write_bytes(&self.tile_attributes2);
+        writer.write_u8(self.vram_bank);
+
+        // keep the last frame, so a state loaded while paused is shown
+        for &pixel in self.back_buffer.iter() {
+            writer.write_u16(pixel);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.control = Control::from_bits_truncate(reader.read_u8()?);
+        self.status = Stat::from_bits_truncate(reader.read_u8()?);
+        self.current_line = reader.read_u8()?;
+        self.compare_line = reader.read_u8()?;
+        self.scroll_x = reader.read_u8()?;
+        self.scroll_y = reader.read_u8()?;
+        self.window_x = reader.read_u8()?;
+        self.window_y = reader.read_u8()?;
+        self.window_line = reader.read_u8()?;
+        self.bg_palette.set_bits(reader.read_u8()?);
+        self.obj_palette0.set_bits(reader.read_u8()?);
+        self.obj_palette1.set_bits(reader.read_u8()?);
+        self.bg_color_palette.load_state(reader)?;
+        self.obj_color_palette.load_state(reader)?;
+        self.mode = match reader.read_u8()? {
+            0 => Mode::HBlank,
+            1 => Mode::VBlank,
+            2 => Mode::AccessOam,
+            3 => Mode::AccessVram,
+            _ => return Err(StateError::InvalidValue("GPU mode")),
+        };
+        self.cycles = reader.read_i32()? as isize;
+        self.stat_line = reader.read_bool()?;
+        self.hblank_started = reader.read_bool()?;
+
+        for bank in self.character_ram.iter_mut() {
+            for tile in bank.iter_mut() {
+                reader.read_into(&mut tile.data)?;
+            }
+        }
+
+        for sprite in self.oam.iter_mut() {
+            sprite.y = reader.read_u8()?;
+            sprite.x = reader.read_u8()?;
+            sprite.tile_num = reader.read_u8()?;
+            sprite.flags = SpriteFlags::from_bits_truncate(reader.read_u8()?);
+        }
+
+        reader.read_into(&mut self.tile_map1)?;
+        reader.read_into(&mut self.tile_map2)?;
+        reader.read_into(&mut self.tile_attributes1)?;
+        reader.read_into(&mut self.tile_attributes2)?;
+        self.vram_bank = reader.read_u8()? & 0x1;
+
+        for pixel in self.back_buffer.iter_mut() {
+            *pixel = reader.read_u16()?;
+        }
+
+        Ok(())
     }
 }
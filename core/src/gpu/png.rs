@@ -0,0 +1,164 @@
+//! PNG encoding of the screen.
+//!
+//! The image is written as 8-bit RGB with the deflate data left
+//! uncompressed, a screen is small enough that it isn't worth pulling a
+//! compression library for it.
+
+use std::io::{self, Write};
+
+use super::types::{rgb555_to_rgb888, ScreenBuffer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use crate::cartridge::crc32;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a];
+
+/// Largest block of a stored deflate stream
+const MAX_STORED_BLOCK: usize = 0xffff;
+
+/// Write the screen as a PNG image
+pub fn write_png<W: Write>(pixels: &ScreenBuffer, writer: &mut W) -> io::Result<()> {
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(SCREEN_WIDTH as u32).to_be_bytes());
+    header.extend_from_slice(&(SCREEN_HEIGHT as u32).to_be_bytes());
+    // 8 bits per channel, RGB, default compression, filter and interlacing
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // every line starts with its filter type, none here
+    let mut image = Vec::with_capacity(SCREEN_HEIGHT * (1 + SCREEN_WIDTH * 3));
+    for line in pixels.chunks(SCREEN_WIDTH) {
+        image.push(0);
+
+        for &pixel in line {
+            let (r, g, b) = rgb555_to_rgb888(pixel);
+            image.extend_from_slice(&[r, g, b]);
+        }
+    }
+
+    writer.write_all(&SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"IDAT", &zlib_stored(&image))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+/// Write a chunk: its length, type, data and the CRC of the type and data
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut chunk = Vec::with_capacity(data.len() + 4);
+    chunk.extend_from_slice(kind);
+    chunk.extend_from_slice(data);
+
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(&chunk)?;
+    writer.write_all(&crc32(&chunk).to_be_bytes())
+}
+
+/// Wrap `data` in a zlib stream made of stored deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // deflate with a 32 KB window, no preset dictionary
+    let mut output = vec![0x78, 0x01];
+
+    let blocks = data.chunks(MAX_STORED_BLOCK).count();
+    for (index, block) in data.chunks(MAX_STORED_BLOCK).enumerate() {
+        let last = index + 1 == blocks;
+        let size = block.len() as u16;
+
+        output.push(last as u8);
+        output.extend_from_slice(&size.to_le_bytes());
+        output.extend_from_slice(&(!size).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
+/// Compute the Adler-32 checksum of `data`, used by zlib
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), &byte| {
+        let a = (a + byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gpu::types::{SCREEN_EMPTY, SCREEN_PIXELS};
+
+    /// Split a PNG file in its chunks, checking their CRC
+    fn chunks(png: &[u8]) -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut chunks = Vec::new();
+        let mut position = SIGNATURE.len();
+
+        while position < png.len() {
+            let mut size = [0; 4];
+            size.copy_from_slice(&png[position..position + 4]);
+            let size = u32::from_be_bytes(size) as usize;
+
+            let chunk = &png[position + 4..position + 8 + size];
+            let mut crc = [0; 4];
+            crc.copy_from_slice(&png[position + 8 + size..position + 12 + size]);
+            assert_eq!(u32::from_be_bytes(crc), crc32(chunk));
+
+            chunks.push((chunk[..4].to_vec(), chunk[4..].to_vec()));
+            position += 12 + size;
+        }
+
+        chunks
+    }
+
+    /// Read back a zlib stream of stored blocks
+    fn unzlib_stored(data: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        let mut position = 2;
+
+        loop {
+            let last = data[position] & 1 != 0;
+            let size = data[position + 1] as usize | (data[position + 2] as usize) << 8;
+            let inverted = data[position + 3] as usize | (data[position + 4] as usize) << 8;
+            assert_eq!(size, !inverted & 0xffff);
+
+            output.extend_from_slice(&data[position + 5..position + 5 + size]);
+            position += 5 + size;
+
+            if last {
+                break;
+            }
+        }
+
+        assert_eq!(&data[position..], &adler32(&output).to_be_bytes()[..]);
+        output
+    }
+
+    #[test]
+    fn adler32_of_known_data() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e6_0398);
+        // the sums wrap around the modulo
+        assert_eq!(adler32(&[0xff; 10000]), 0xb623_eb2b);
+    }
+
+    #[test]
+    fn writes_the_screen() {
+        let mut pixels = SCREEN_EMPTY;
+        // red pixel on the top left corner, blue on the bottom right one
+        pixels[0] = 0x001f;
+        pixels[SCREEN_PIXELS - 1] = 0x7c00;
+
+        let mut png = Vec::new();
+        write_png(&pixels, &mut png).unwrap();
+        assert_eq!(&png[..8], &SIGNATURE[..]);
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[u8]> = chunks.iter().map(|&(ref kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], b"IDAT", b"IEND"]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 160, 0, 0, 0, 144, 8, 2, 0, 0, 0]);
+
+        let image = unzlib_stored(&chunks[1].1);
+        let line = 1 + SCREEN_WIDTH * 3;
+        assert_eq!(image.len(), SCREEN_HEIGHT * line);
+        assert!(image.chunks(line).all(|line| line[0] == 0));
+        assert_eq!(&image[1..7], &[0xff, 0, 0, 0xff, 0xff, 0xff]);
+        assert_eq!(&image[image.len() - 3..], &[0, 0, 0xff]);
+    }
+}
@@ -1,7 +1,10 @@
 pub const SCREEN_WIDTH: usize = 160;
 pub const SCREEN_HEIGHT: usize = 144;
 pub const SCREEN_PIXELS: usize = SCREEN_WIDTH * SCREEN_HEIGHT;
-pub const SCREEN_EMPTY: ScreenBuffer = [Color::Off; SCREEN_PIXELS];
+pub const SCREEN_EMPTY: ScreenBuffer = [WHITE; SCREEN_PIXELS];
+
+/// White in the RGB555 format
+const WHITE: u16 = 0x7fff;
 
 #[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Color {
@@ -22,6 +25,32 @@ impl Color {
             _ => Off
         }
     }
+
+    /// Get the RGB555 value used to display this shade
+    #[inline]
+    pub fn to_rgb555(&self) -> u16 {
+        match *self {
+            Color::Off => WHITE,
+            Color::Light => 0x56b5,
+            Color::Dark => 0x294a,
+            Color::On => 0x0000,
+        }
+    }
+}
+
+/// Convert an RGB555 pixel (red on the lowest bits) into 8-bit red, green
+/// and blue components
+#[inline]
+pub fn rgb555_to_rgb888(pixel: u16) -> (u8, u8, u8) {
+    // Replicate the high bits on the low ones so 0x1f maps to 0xff
+    let expand = |value: u16| {
+        let value = (value & 0x1f) as u8;
+        (value << 3) | (value >> 2)
+    };
+
+    (expand(pixel), expand(pixel >> 5), expand(pixel >> 10))
 }
 
-pub type ScreenBuffer = [Color; SCREEN_PIXELS];
\ No newline at end of file
+/// Screen pixels in the RGB555 format, the native format of the Game Boy
+/// Color. Classic Game Boy shades are converted with `Color::to_rgb555`.
+pub type ScreenBuffer = [u16; SCREEN_PIXELS];
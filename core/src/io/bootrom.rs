@@ -1,11 +1,55 @@
 //! The GameBoy ROM that gets mapped at address 0 at startup. It's not
 //! accessible during normal game execution.
 
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// How the machine starts
+pub enum Boot {
+    /// Run the built-in boot ROM, only available for the classic Game Boy
+    Internal,
+    /// Run a boot ROM dump, of the classic Game Boy or the Game Boy Color
+    BootRom(Vec<u8>),
+    /// Start the cartridge directly, with the registers left by the boot
+    /// ROM
+    Skip,
+}
+
+impl Boot {
+    /// Load a boot ROM dump, it must have the size of a DMG or a CGB
+    /// boot ROM
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Boot> {
+        let data = fs::read(path)?;
+
+        match data.len() {
+            DMG_BOOTROM_SIZE | CGB_BOOTROM_SIZE => Ok(Boot::BootRom(data)),
+            size => Err(io::Error::new(io::ErrorKind::InvalidData,
+                                       format!("Invalid boot ROM size: {} bytes", size))),
+        }
+    }
+}
+
+/// Size of the DMG boot ROM
+pub const DMG_BOOTROM_SIZE: usize = 0x100;
+/// Size of the CGB boot ROM, the cartridge header at [0x100, 0x1ff] stays
+/// visible in the middle of it
+pub const CGB_BOOTROM_SIZE: usize = 0x900;
+/// Cartridge header range left visible by the CGB boot ROM
+const CARTRIDGE_HEADER: ::std::ops::Range<usize> = 0x100..0x200;
+
+/// Returns `true` if a boot ROM of `size` bytes covers `address`
+pub fn is_mapped(size: usize, address: u16) -> bool {
+    let address = address as usize;
+
+    address < size && !CARTRIDGE_HEADER.contains(&address)
+}
+
 /// Original GameBoy bootrom. It scrolls the NINTENDO logo down the
 /// screen and emits the signature two notes when it reaches the
 /// middle. It also checks that the cartridge ROM header is correct
 /// and deadlocks if that check fails.
-pub static BOOTROM: [u8; 0x100] = [
+pub static BOOTROM: [u8; DMG_BOOTROM_SIZE] = [
     // init_stack:
     0x31, 0xFE, 0xFF, // LD     SP 0xfffe
     0xAF, // XOR    A A
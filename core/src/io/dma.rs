@@ -0,0 +1,210 @@
+//! OAM DMA transfer controller.
+//!
+//! Writing to the DMA register copies 160 bytes from `value * 0x100` to the
+//! OAM, one byte per machine cycle. While the transfer is running the bus
+//! used as source is busy, so the CPU can only safely run code from HRAM.
+
+use super::map;
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+/// Number of bytes copied by an OAM DMA transfer
+const OAM_DMA_LENGTH: u16 = 0xa0;
+/// Clock ticks needed to copy one byte
+const TICKS_PER_BYTE: u32 = 4;
+
+/// Memory buses that can conflict with a DMA transfer
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum Bus {
+    /// Cartridge ROM/RAM and work RAM
+    External,
+    /// Video RAM
+    Video,
+    /// OAM, I/O ports and HRAM, never used as a DMA source
+    Internal,
+}
+
+impl Bus {
+    fn from_address(address: u16) -> Bus {
+        match address {
+            0x8000 ... 0x9fff => Bus::Video,
+            0x0000 ... 0xfdff => Bus::External,
+            _ => Bus::Internal,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Copy)]
+enum State {
+    Idle,
+    /// The transfer was requested and starts on the next machine cycle.
+    /// `true` if a previous transfer was still running, in that case the
+    /// OAM stays locked during the startup cycle.
+    Starting(bool),
+    /// The transfer is running, holds the offset of the next byte
+    Running(u16),
+}
+
+pub struct OamDma {
+    /// Last value written to the DMA register
+    register: u8,
+    /// Source address of the transfer
+    source: u16,
+    /// Transfer state
+    state: State,
+    /// Last byte read from the source bus
+    value: u8,
+    /// Ticks received but not used to copy a byte yet
+    ticks: u32,
+}
+
+impl OamDma {
+    /// Create a new OamDma instance
+    pub fn new() -> Self {
+        Self {
+            register: 0xff,
+            source: 0,
+            state: State::Idle,
+            value: 0xff,
+            ticks: 0,
+        }
+    }
+
+    /// Read the DMA register
+    pub fn read_byte(&self) -> u8 {
+        self.register
+    }
+
+    /// Write the DMA register, starting a new transfer
+    pub fn write_byte(&mut self, value: u8) {
+        self.register = value;
+
+        // Sources above 0xdfff map to work RAM, like the echo area
+        let high = if value >= 0xe0 { value - 0x20 } else { value };
+        self.source = (high as u16) << 8;
+
+        self.state = State::Starting(self.is_active());
+        self.ticks = 0;
+    }
+
+    /// Returns `true` while the OAM is locked by a transfer
+    pub fn is_active(&self) -> bool {
+        match self.state {
+            State::Idle => false,
+            State::Starting(running) => running,
+            State::Running(_) => true,
+        }
+    }
+
+    /// Consume the given ticks and return the number of bytes that must
+    /// be copied in that time
+    pub fn cycles(&mut self, ticks: u32) -> u32 {
+        if self.state == State::Idle {
+            return 0;
+        }
+
+        self.ticks += ticks;
+        let cycles = self.ticks / TICKS_PER_BYTE;
+        self.ticks %= TICKS_PER_BYTE;
+
+        cycles
+    }
+
+    /// Advance the transfer by one machine cycle, returning the source
+    /// address and OAM offset of the byte to copy on this cycle
+    pub fn step(&mut self) -> Option<(u16, u16)> {
+        match self.state {
+            State::Idle => None,
+            State::Starting(_) => {
+                self.state = State::Running(0);
+                None
+            }
+            State::Running(offset) => {
+                self.state = if offset + 1 < OAM_DMA_LENGTH {
+                    State::Running(offset + 1)
+                } else {
+                    State::Idle
+                };
+
+                Some((self.source + offset, offset))
+            }
+        }
+    }
+
+    /// Record the byte read from the source bus by the last step
+    pub fn set_value(&mut self, value: u8) {
+        self.value = value;
+    }
+
+    /// Value seen by the CPU when reading `address` during a transfer, or
+    /// `None` if the access doesn't conflict with it.
+    ///
+    /// The OAM reads as 0xff, and reads from the bus used as source get
+    /// the byte the DMA is currently transferring.
+    pub fn conflicting_read(&self, address: u16) -> Option<u8> {
+        if !self.is_active() {
+            return None;
+        }
+
+        if map::in_range(address, map::OAM).is_some() {
+            return Some(0xff);
+        }
+
+        if self.conflicts(address) {
+            Some(self.value)
+        } else {
+            None
+        }
+    }
+
+    /// Returns `true` if a CPU write to `address` is lost because of a
+    /// running transfer
+    pub fn blocks_write(&self, address: u16) -> bool {
+        if !self.is_active() {
+            return false;
+        }
+
+        map::in_range(address, map::OAM).is_some() || self.conflicts(address)
+    }
+
+    /// Returns `true` if `address` is on the same bus as the source
+    fn conflicts(&self, address: u16) -> bool {
+        let bus = Bus::from_address(address);
+        bus != Bus::Internal && bus == Bus::from_address(self.source)
+    }
+}
+
+impl SaveState for OamDma {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.register);
+        writer.write_u16(self.source);
+
+        match self.state {
+            State::Idle => writer.write_u8(0),
+            State::Starting(restarted) => {
+                writer.write_u8(1);
+                writer.write_bool(restarted);
+            }
+            State::Running(offset) => {
+                writer.write_u8(2);
+                writer.write_u16(offset);
+            }
+        }
+
+        writer.write_u8(self.value);
+        writer.write_u32(self.ticks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.register = reader.read_u8()?;
+        self.source = reader.read_u16()?;
+        self.state = match reader.read_u8()? {
+            0 => State::Idle,
+            1 => State::Starting(reader.read_bool()?),
+            2 => State::Running(reader.read_u16()?),
+            _ => return Err(StateError::InvalidValue("OAM DMA state")),
+        };
+        self.value = reader.read_u8()?;
+        self.ticks = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+//! Invalid memory accesses.
+//!
+//! Unmapped reads return 0xff and unmapped writes are lost, like on the
+//! real hardware. Those accesses are usually bugs in the emulated program
+//! or missing emulator features, the `FaultPolicy` decides if they are
+//! reported.
+
+use std::cell::Cell;
+use std::fmt;
+use std::str::FromStr;
+
+/// Invalid memory access performed by the emulated program
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// Read from an address not mapped to anything
+    UnmappedRead(u16),
+    /// Write to an address not mapped to anything
+    UnmappedWrite(u16, u8),
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::UnmappedRead(address) => write!(f, "Read from unmapped address {:#06x}", address),
+            Fault::UnmappedWrite(address, value) => {
+                write!(f, "Write of {:#04x} to unmapped address {:#06x}", value, address)
+            }
+        }
+    }
+}
+
+/// What to do when the emulated program performs an invalid access
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPolicy {
+    /// Carry on silently
+    Ignore,
+    /// Log the fault and carry on
+    Log,
+    /// Stop after the current instruction and let the embedder pause the
+    /// emulation or run the `Debugger`, so the machine can be inspected.
+    /// It can be resumed.
+    Pause,
+    /// Stop the emulation with an error
+    Error,
+}
+
+impl Default for FaultPolicy {
+    /// Development builds log the faults, release builds ignore them
+    fn default() -> FaultPolicy {
+        if cfg!(debug_assertions) {
+            FaultPolicy::Log
+        } else {
+            FaultPolicy::Ignore
+        }
+    }
+}
+
+impl FromStr for FaultPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<FaultPolicy, String> {
+        match s {
+            "ignore" => Ok(FaultPolicy::Ignore),
+            "log" => Ok(FaultPolicy::Log),
+            "pause" => Ok(FaultPolicy::Pause),
+            "error" => Ok(FaultPolicy::Error),
+            _ => Err(format!("Unknown fault policy {}", s)),
+        }
+    }
+}
+
+/// Collects the faults according to the policy. The memory map reads
+/// don't have mutable access, so the pending fault lives in a `Cell`.
+pub struct Faults {
+    policy: FaultPolicy,
+    /// First fault since the last `take`, only kept when the policy
+    /// stops the emulation
+    pending: Cell<Option<Fault>>,
+}
+
+impl Faults {
+    pub fn new() -> Faults {
+        Faults {
+            policy: FaultPolicy::default(),
+            pending: Cell::new(None),
+        }
+    }
+
+    pub fn policy(&self) -> FaultPolicy {
+        self.policy
+    }
+
+    pub fn set_policy(&mut self, policy: FaultPolicy) {
+        self.policy = policy;
+    }
+
+    /// Report a fault
+    pub fn report(&self, fault: Fault) {
+        match self.policy {
+            FaultPolicy::Ignore => (),
+            FaultPolicy::Log => warn!("{}", fault),
+            FaultPolicy::Pause | FaultPolicy::Error => {
+                if self.pending.get().is_none() {
+                    self.pending.set(Some(fault));
+                }
+            }
+        }
+    }
+
+    /// Take the fault that must stop the emulation, if any
+    pub fn take(&self) -> Option<Fault> {
+        self.pending.take()
+    }
+}
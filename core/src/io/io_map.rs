@@ -97,3 +97,39 @@ pub const LCD_OBP1: u16 = 0x49;
 pub const LCD_WY: u16 = 0x4a;
 /// Window X position + 7
 pub const LCD_WX: u16 = 0x4b;
+
+
+/// Prepare speed switch (CGB only)
+pub const KEY1: u16 = 0x4d;
+/// VRAM bank (CGB only)
+pub const VBK: u16 = 0x4f;
+
+
+/// Boot ROM unmap, writing 1 maps the cartridge ROM over the boot ROM
+pub const BOOT: u16 = 0x50;
+
+
+/// VRAM DMA source, high (CGB only)
+pub const HDMA1: u16 = 0x51;
+/// VRAM DMA source, low (CGB only)
+pub const HDMA2: u16 = 0x52;
+/// VRAM DMA destination, high (CGB only)
+pub const HDMA3: u16 = 0x53;
+/// VRAM DMA destination, low (CGB only)
+pub const HDMA4: u16 = 0x54;
+/// VRAM DMA length, mode and start (CGB only)
+pub const HDMA5: u16 = 0x55;
+
+
+/// Background palette index (CGB only)
+pub const BCPS: u16 = 0x68;
+/// Background palette data (CGB only)
+pub const BCPD: u16 = 0x69;
+/// Sprite palette index (CGB only)
+pub const OCPS: u16 = 0x6a;
+/// Sprite palette data (CGB only)
+pub const OCPD: u16 = 0x6b;
+
+
+/// Work RAM bank (CGB only)
+pub const SVBK: u16 = 0x70;
@@ -1,4 +1,6 @@
 use crate::types::GbKey;
+use super::irq::{Irq, Interrupt};
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 
 /// P1 register
 ///
@@ -14,6 +16,8 @@ bitflags!(
 
         /// When only select bits are writable
         const WRITABLE = P1::SELECT_DIRECTIONAL.bits | P1::SELECT_BUTTON.bits;
+        /// Input lines, shared by the dpad and the buttons
+        const INPUT = P1::P10.bits | P1::P11.bits | P1::P12.bits | P1::P13.bits;
     }
 );
 
@@ -48,6 +52,8 @@ pub struct Joypad {
     directional: P1,
     button: P1,
     register: P1,
+    /// An input line went low since the last cycle
+    interrupt: bool,
 }
 
 impl Joypad {
@@ -57,6 +63,7 @@ impl Joypad {
             directional: P1::empty(),
             button: P1::empty(),
             register: P1::empty(),
+            interrupt: false,
         }
     }
 
@@ -75,9 +82,30 @@ impl Joypad {
         self.update_register();
     }
 
+    /// Press a key
+    pub fn key_down(&mut self, key: GbKey) {
+        self.directional.insert(P1::directional(&key));
+        self.button.insert(P1::buttons(&key));
+        self.update_register();
+    }
+
+    /// Release a key
+    pub fn key_up(&mut self, key: GbKey) {
+        self.directional.remove(P1::directional(&key));
+        self.button.remove(P1::buttons(&key));
+        self.update_register();
+    }
+
+    /// Returns `true` while a key is pressed on a selected line, this is
+    /// what wakes the CPU from STOP
+    pub fn is_input_low(&self) -> bool {
+        self.register.intersects(P1::INPUT)
+    }
+
     /// Updates the register state based on select bits P14-P15 and the
     /// pressed buttons
     pub fn update_register(&mut self) {
+        let old_input = self.register & P1::INPUT;
         self.register &= P1::WRITABLE;
 
         if self.register.contains(P1::SELECT_DIRECTIONAL) {
@@ -87,5 +115,35 @@ impl Joypad {
         if self.register.contains(P1::SELECT_BUTTON) {
             self.register.insert(self.button);
         }
+
+        // A line going from high to low raises the interrupt, this also
+        // happens when selecting a line with a key already pressed
+        let new_input = self.register & P1::INPUT;
+        if !(new_input - old_input).is_empty() {
+            self.interrupt = true;
+        }
     }
-}
\ No newline at end of file
+
+    /// execute the joypad cycle
+    pub fn do_cycle(&mut self, irq: &mut Irq) {
+        if self.interrupt {
+            irq.request_interrupt(Interrupt::JoyPad);
+            self.interrupt = false;
+        }
+    }
+}
+
+/// Only the selected lines are part of the state, the keys stay as they
+/// are currently held so they don't get stuck after loading a state
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8((self.register & P1::WRITABLE).bits());
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.register = P1::from_bits_truncate(reader.read_u8()?) & P1::WRITABLE;
+        self.update_register();
+        self.interrupt = false;
+        Ok(())
+    }
+}
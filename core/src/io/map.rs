@@ -19,12 +19,7 @@ pub const OAM: (u16, u16) = (0xfe00, 0xfe9f);
 /// Empty but unusable for
 pub const EMPTY_RAM: (u16, u16) = (0xfea0, 0xfeff);
 /// IO ports
-pub const IO: (u16, u16) = (0xff00, 0xff4b);
-/// Register used to unmap the bootrom, Should not
-/// be used by regular games
-pub const UNMAP_BOOTROM: u16 = 0xff50;
-/// Working RAM Bank Number
-pub const WRAMBANK: u16 = 0xff70;
+pub const IO: (u16, u16) = (0xff00, 0xff7f);
 /// Zero page memory (High RAM)
 pub const ZERO_PAGE: (u16, u16) = (0xff80, 0xfffe);
 /// Interrupt Enable Register
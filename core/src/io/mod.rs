@@ -1,46 +1,64 @@
 //! Input/Output abstraction for memory, ROM, and I/O mapped registers.
 
 use crate::cartridge::Cartridge;
-use crate::gpu::Gpu;
+use crate::gpu::{Gpu, VRAM_DMA_BLOCK_CYCLES};
 use crate::gpu::types;
 use super::sound::AudioPlayer;
-use super::sound::CpalPlayer;
 use super::sound::Sound;
 use self::io_map::*;
 use self::irq::{Irq, Interrupt};
+use self::dma::OamDma;
 use self::ram::Ram;
 use self::serial::Serial;
 use self::timer::Timer;
 use self::joypad::Joypad;
+use self::fault::{Fault, Faults};
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
 
 mod map;
 mod ram;
 pub mod io_map;
-mod bootrom;
-mod irq;
+pub mod bootrom;
+pub mod irq;
+mod dma;
 
 mod timer;
-mod serial;
+pub mod serial;
+pub mod fault;
 mod joypad;
 
+#[derive(PartialEq, Copy, Clone)]
+pub enum GbMode {
+    Classic,
+    Color,
+}
+
 #[derive(PartialEq, Copy, Clone)]
 pub enum GbSpeed {
     Single,
     Double,
 }
 
+/// VRAM DMA transfer mode (CGB only)
 #[derive(PartialEq)]
 enum DMAType {
-    OAM,
+    NoDMA,
+    /// General purpose DMA, copies everything at once
     GDMA,
+    /// HBlank DMA, copies a 16 bytes block on each HBlank
     HDMA,
 }
 
+/// Size of each work RAM bank
+const WRAM_BANK_SIZE: u16 = 0x1000;
+/// System counter left by the DMG boot ROM, DIV reads 0xab
+const POST_BOOT_SYSTEM_COUNTER: u16 = 0xabcc;
+
 pub struct Interconnect {
     /// Cartridge
-    cartridge: Cartridge,
-    /// I/O ports
-    io: Vec<u8>,
+    pub cartridge: Cartridge,
+    /// Classic or color mode
+    gbmode: GbMode,
     /// Interrupt module
     pub irq: Irq,
     // Work RAM
@@ -54,17 +72,22 @@ pub struct Interconnect {
     // Sound
     sound: Sound,
     // Serial
-    serial: Serial,
-    // Bootrom is mapped
-    bootrom: bool,
+    pub serial: Serial,
+    // Boot ROM, while it's mapped
+    bootrom: Option<Vec<u8>>,
     // GB speed mode
     gbspeed: GbSpeed,
     // Speed switch request
     speed_switch_req: bool,
-    // Working RAM Bank
+    // Working RAM Bank mapped at [0xd000, 0xdfff]
     wrambank: usize,
-    joypad: Joypad,
-    // DMA state
+    // Joypad
+    pub joypad: Joypad,
+    // Invalid accesses
+    pub faults: Faults,
+    // OAM DMA
+    oam_dma: OamDma,
+    // VRAM DMA state
     dma_status: DMAType,
     dma_src: u16,
     dma_dst: u16,
@@ -72,37 +95,126 @@ pub struct Interconnect {
 }
 
 impl Interconnect {
-    pub fn new(cartridge: Cartridge, gpu: Gpu) -> Interconnect {
-        // creates a new player
-        let player = CpalPlayer::get();
+    pub fn new(cartridge: Cartridge, gpu: Gpu, player: Box<AudioPlayer>) -> Interconnect {
+        let gbmode = if cartridge.is_gbc() { GbMode::Color } else { GbMode::Classic };
 
         Interconnect {
             cartridge,
-            io: vec![0x20; 0x7f],
+            gbmode,
             irq: Irq::new(),
-            iram: Ram::new(0x2000),
+            // 8 banks of work RAM, the classic Game Boy only uses 2
+            iram: Ram::new(8 * WRAM_BANK_SIZE as usize),
             zpage: Ram::new(0x7f),
             gpu,
             timer: Timer::new(),
-            sound: Sound::new(Box::new(player.expect("Player is mandatory")) as Box<AudioPlayer>),
+            sound: Sound::new(player),
             serial: Serial::new(),
-            bootrom: true,
+            bootrom: None,
             gbspeed: GbSpeed::Single,
             speed_switch_req: false,
             wrambank: 1,
-            dma_status: DMAType::OAM,
+            dma_status: DMAType::NoDMA,
             joypad: Joypad::new(),
+            faults: Faults::new(),
+            oam_dma: OamDma::new(),
             dma_src: 0,
             dma_dst: 0,
             dma_len: 0xff,
         }
     }
 
+    /// Run the pending VRAM DMA transfers, returning the number of dots
+    /// the CPU was stalled by them
     fn perform_vramdma(&mut self) -> u32 {
         match self.dma_status {
-            DMAType::OAM => 0,
-            DMAType::GDMA => panic!("TODO: implement GDMA"),
-            DMAType::HDMA => panic!("TODO: implement HDMA"),
+            DMAType::NoDMA => 0,
+            DMAType::GDMA => self.perform_gdma(),
+            DMAType::HDMA => self.perform_hdma(),
+        }
+    }
+
+    /// Copy a single block when a new HBlank starts
+    fn perform_hdma(&mut self) -> u32 {
+        if !self.gpu.take_hblank() {
+            return 0;
+        }
+
+        self.perform_vramdma_block();
+
+        if self.dma_len == 0x7f {
+            self.dma_status = DMAType::NoDMA;
+        }
+
+        VRAM_DMA_BLOCK_CYCLES
+    }
+
+    /// Copy all the blocks at once
+    fn perform_gdma(&mut self) -> u32 {
+        let blocks = self.dma_len as u32 + 1;
+
+        for _ in 0..blocks {
+            self.perform_vramdma_block();
+        }
+
+        self.dma_status = DMAType::NoDMA;
+
+        blocks * VRAM_DMA_BLOCK_CYCLES
+    }
+
+    /// Copy a 16 bytes block from the source to the VRAM
+    fn perform_vramdma_block(&mut self) {
+        for i in 0..0x10 {
+            let value = self.read_mapped_byte(self.dma_src.wrapping_add(i));
+            self.gpu.write_vram_dma((self.dma_dst + i) & 0x1fff, value);
+        }
+
+        self.dma_src = self.dma_src.wrapping_add(0x10);
+        self.dma_dst = (self.dma_dst + 0x10) & 0x1ff0;
+
+        // The length counts the remaining blocks minus one, it reads as
+        // 0x7f once the transfer ends
+        self.dma_len = if self.dma_len == 0 { 0x7f } else { self.dma_len - 1 };
+    }
+
+    /// Read the VRAM DMA registers, only HDMA5 is readable
+    fn read_vramdma(&self, address: u16) -> u8 {
+        match address {
+            HDMA5 => {
+                let inactive = if self.dma_status == DMAType::NoDMA { 0x80 } else { 0 };
+                inactive | self.dma_len
+            }
+            _ => 0xff,
+        }
+    }
+
+    /// Write the VRAM DMA registers
+    fn write_vramdma(&mut self, address: u16, value: u8) {
+        match address {
+            HDMA1 => self.dma_src = (self.dma_src & 0x00ff) | ((value as u16) << 8),
+            HDMA2 => self.dma_src = (self.dma_src & 0xff00) | (value & 0xf0) as u16,
+            HDMA3 => self.dma_dst = (self.dma_dst & 0x00ff) | (((value & 0x1f) as u16) << 8),
+            HDMA4 => self.dma_dst = (self.dma_dst & 0xff00) | (value & 0xf0) as u16,
+            HDMA5 => {
+                // Writing with bit 7 cleared stops a running HBlank DMA
+                if self.dma_status == DMAType::HDMA {
+                    if value & 0x80 == 0 {
+                        self.dma_status = DMAType::NoDMA;
+                    }
+
+                    return;
+                }
+
+                self.dma_len = value & 0x7f;
+                self.dma_status = if value & 0x80 == 0x80 {
+                    // The first block waits for the next HBlank, drop the
+                    // one latched while no transfer was running
+                    self.gpu.take_hblank();
+                    DMAType::HDMA
+                } else {
+                    DMAType::GDMA
+                };
+            }
+            _ => (),
         }
     }
 
@@ -119,20 +231,93 @@ impl Interconnect {
 
         self.timer.do_cycle(cpu_ticks, &mut self.irq);
 
-        // TODO: Keypad
-        
+        // The OAM DMA runs at the CPU speed
+        self.do_oam_dma(cpu_ticks);
+
+        // Joypad cycle
+        self.joypad.do_cycle(&mut self.irq);
+
         // GPU cycle
-        self.gpu.do_cycle(gputricks);
+        self.gpu.do_cycle(gputricks, &mut self.irq);
+
+        // Sound runs at the same speed on both modes
+        self.sound.do_cycle(gputricks);
 
-        // TODO: sound cycle
+        // The cartridge only tracks the time since the last RAM write
+        self.cartridge.do_cycle(gputricks);
 
-        // TODO: serial cycle
+        // Serial cycle, the internal clock follows the CPU speed but the
+        // link cable device follows the emulated time
+        self.serial.sync_link(gputricks);
+        self.serial.do_cycle(cpu_ticks, &mut self.irq);
 
         return gputricks;
     }
 
-    pub fn switch_speed(&mut self) {
-        if self.speed_switch_req {
+    /// Copy the OAM DMA bytes due in the given CPU ticks
+    fn do_oam_dma(&mut self, ticks: u32) {
+        for _ in 0..self.oam_dma.cycles(ticks) {
+            if let Some((source, offset)) = self.oam_dma.step() {
+                let value = self.read_mapped_byte(source);
+                self.oam_dma.set_value(value);
+                self.gpu.write_oam_dma(offset, value);
+            }
+        }
+    }
+
+    /// Read the speed switch register
+    fn read_key1(&self) -> u8 {
+        let speed = if self.gbspeed == GbSpeed::Double { 0x80 } else { 0 };
+        let request = if self.speed_switch_req { 0x01 } else { 0 };
+
+        0x7e | speed | request
+    }
+
+    /// Map a work RAM offset into the `iram`, taking the selected bank
+    /// into account
+    fn iram_offset(&self, offset: u16) -> u16 {
+        let offset = offset % (2 * WRAM_BANK_SIZE);
+
+        if offset < WRAM_BANK_SIZE {
+            offset
+        } else {
+            offset + (self.wrambank as u16 - 1) * WRAM_BANK_SIZE
+        }
+    }
+
+    /// Map a boot ROM at the start of the address space until the
+    /// program writes to the BOOT register
+    pub fn map_bootrom(&mut self, bootrom: Vec<u8>) {
+        self.bootrom = Some(bootrom);
+    }
+
+    /// Set the I/O registers to the values left by the boot ROM, to
+    /// start the cartridge directly
+    pub fn skip_boot(&mut self) {
+        // the APU must be powered on before writing the sound registers
+        let registers = [
+            (INPUT, 0xcf), (TAC, 0xf8), (IF, 0xe1),
+            (NR52, 0x80), (NR10, 0x80), (NR11, 0xbf), (NR12, 0xf3), (NR14, 0xbf),
+            (NR21, 0x3f), (NR22, 0x00), (NR24, 0xbf), (NR30, 0x7f), (NR31, 0xff),
+            (NR32, 0x9f), (NR34, 0xbf), (NR41, 0xff), (NR42, 0x00), (NR43, 0x00),
+            (NR44, 0xbf), (NR50, 0x77), (NR51, 0xf3),
+            (LCD_BGP, 0xfc), (LCD_OBP0, 0xff), (LCD_OBP1, 0xff), (LCDC, 0x91),
+        ];
+
+        for &(register, value) in registers.iter() {
+            self.write_io(register, value);
+        }
+
+        self.timer.set_system_counter(POST_BOOT_SYSTEM_COUNTER);
+        self.bootrom = None;
+    }
+
+    /// Switch the CPU speed if it was requested on KEY1, returns `true`
+    /// if the speed was switched
+    pub fn switch_speed(&mut self) -> bool {
+        let requested = self.speed_switch_req;
+
+        if requested {
             if self.gbspeed == GbSpeed::Double {
                 self.gbspeed = GbSpeed::Single;
             } else {
@@ -141,15 +326,28 @@ impl Interconnect {
         }
 
         self.speed_switch_req = false;
+        requested
     }
 
     /// read a byte from the interconnect
     pub fn read_byte(&self, address: u16) -> u8 {
+        // a running OAM DMA owns the bus it reads from
+        if let Some(value) = self.oam_dma.conflicting_read(address) {
+            return value;
+        }
+
+        self.read_mapped_byte(address)
+    }
+
+    /// read a byte from the memory map, ignoring DMA bus conflicts
+    fn read_mapped_byte(&self, address: u16) -> u8 {
         // ROM
         if let Some(off) = map::in_range(address, map::ROM) {
             // bootrom is still mapped, read from it
-            if self.bootrom && off < 0x100 {
-                return bootrom::BOOTROM[off as usize];
+            if let Some(ref bootrom) = self.bootrom {
+                if bootrom::is_mapped(bootrom.len(), off) {
+                    return bootrom[off as usize];
+                }
             }
 
             // read a byte from the cartridge
@@ -178,20 +376,17 @@ impl Interconnect {
 
         // Internal RAM
         if let Some(off) = map::in_range(address, map::IRAM){
-            return self.iram.byte(off);
+            return self.iram.byte(self.iram_offset(off));
         }
 
         // Internal RAM Echo
         if let Some(off) = map::in_range(address, map::IRAM_ECHO) {
-            return self.iram.byte(off);
+            return self.iram.byte(self.iram_offset(off));
         }
 
         // Object Attribute Mapping
         if let Some(off) = map::in_range(address, map::OAM) {
-            return match self.dma_status {
-                DMAType::OAM => 0xff,
-                _ => self.gpu.read_oam(off),
-            };
+            return self.gpu.read_oam(off);
         }
 
         // Empty I/O zone.
@@ -204,11 +399,6 @@ impl Interconnect {
             return self.read_io(off);
         }
 
-        // Working RAM Bank Number
-        if address == map::WRAMBANK {
-            return self.wrambank as u8;
-        }
-
         // Zero Page (High RAM)
         if let Some(off) = map::in_range(address, map::ZERO_PAGE) {
              return self.zpage.byte(off);
@@ -219,10 +409,10 @@ impl Interconnect {
             return self.irq.get_interrupt_enabled();
         }
 
-        // Infrared (Implementation don't needed)
-        // 0xff56 => { 0 }
-
-        panic!("Read from an unrecognized address: {:04x}", address);
+        // the whole address space is mapped, but keep the bus floating
+        // in case a range is missing
+        self.faults.report(Fault::UnmappedRead(address));
+        0xff
     }
 
     /// read a word from the interconnect
@@ -232,6 +422,11 @@ impl Interconnect {
 
     /// write a byte to the interconnect
     pub fn write_byte(&mut self, address: u16, value: u8) {
+        // writes to the bus used by a running OAM DMA are lost
+        if self.oam_dma.blocks_write(address) {
+            return;
+        }
+
         // ROM
         if let Some(off) = map::in_range(address, map::ROM) {
             return self.cartridge.set_rom_byte(off, value);
@@ -259,17 +454,19 @@ impl Interconnect {
 
         // IRAM
         if let Some(off) = map::in_range(address, map::IRAM) {
+            let off = self.iram_offset(off);
             return self.iram.set_byte(off, value);
         }
 
         // IRAM Echo
         if let Some(off) = map::in_range(address, map::IRAM_ECHO) {
+            let off = self.iram_offset(off);
             return self.iram.set_byte(off, value);
         }
 
         // Object Attribute Mapping
-        if let Some(_off) = map::in_range(address, map::OAM) {
-            panic!("TODO: implement OAM2");
+        if let Some(off) = map::in_range(address, map::OAM) {
+            return self.gpu.write_oam(off, value);
         }
 
         // IO
@@ -277,11 +474,6 @@ impl Interconnect {
             return self.write_io(off, value);
         }
 
-        // Working RAM Bank Number
-        if address == map::WRAMBANK {
-            return self.wrambank = value as usize;
-        }
-
         // Zero Page (High RAM)
         if let Some(off) = map::in_range(address, map::ZERO_PAGE) {
             return self.zpage.set_byte(off, value);
@@ -292,15 +484,12 @@ impl Interconnect {
             return self.irq.set_interrupt_enabled(value);
         }
 
-        // TODO: organize this code a bit better
-        if address == 0xff4f {
-            return self.gpu.set_vramBank(value);
+        // Empty I/O zone, games often clear it along with the OAM
+        if let Some(_off) = map::in_range(address, map::EMPTY_RAM) {
+            return;
         }
 
-        panic!("Unsupported write at ${:04x} = {:02x}", address, value);
-
-        // Infrared (Implementation don't needed)
-        // 0xff56 => {}
+        self.faults.report(Fault::UnmappedWrite(address, value));
     }
 
     /// write a word in memory
@@ -315,32 +504,49 @@ impl Interconnect {
 
     /// Read a byte from the IO ports
     fn read_io(&self, address: u16) -> u8 {
+        // Color registers are not mapped on the classic Game Boy
+        if self.gbmode == GbMode::Classic && is_color_register(address) {
+            return 0xff;
+        }
+
         match address {
             // Keypad
             0x00 => self.joypad.get_register(),
             // Serial
             0x01 ... 0x02 => self.serial.read_byte(0xff00 | address),
-            0x03 => 0,
             // Timer
             0x04 ... 0x07 => self.timer.read_byte(0xff00 | address),
-            0x08 ... 0x0e => 0,
             // Interrupt flags
             0x0f => self.irq.get_interrupt_flag(),
             // Sound registers
             0x10 ... 0x3f => self.sound.read_byte(address),
-            // Read data from the VRAM
-            0x40 ... 0x4f => self.gpu.read_byte(address),
-            0x51 ... 0x7f => 0,
+            // OAM DMA
+            DMA => self.oam_dma.read_byte(),
             // GPU registers
-//            0x40 ... 0x4b => self.gpu.read_byte(address),
+            LCDC ... LCD_WX | VBK | BCPS ... OCPD => self.gpu.read_byte(address),
+            // Speed switch
+            KEY1 => self.read_key1(),
+            // Boot ROM unmap, write only
+            BOOT => 0xff,
+            // VRAM DMA
+            HDMA1 ... HDMA5 => self.read_vramdma(address),
+            // Working RAM Bank Number
+            SVBK => 0xf8 | self.wrambank as u8,
+            _ if is_unused_register(address) => 0xff,
             _ => {
-                panic!("Read to a IO address not handled: {:#x}", address);
+                self.faults.report(Fault::UnmappedRead(0xff00 | address));
+                0xff
             }
         }
     }
 
     /// Write a byte to the IO ports
     fn write_io(&mut self, address: u16, value: u8) {
+        // Color registers are not mapped on the classic Game Boy
+        if self.gbmode == GbMode::Classic && is_color_register(address) {
+            return;
+        }
+
         match address {
             // Keypad
             0x00 => self.joypad.set_register(value),
@@ -352,18 +558,143 @@ impl Interconnect {
             0x0f => self.irq.set_interrupt_flag(value),
             // Sound registers
             0x10 ... 0x3f => self.sound.write_byte(address, value),
-            0x42 => self.gpu.set_scroll_y(value),
-            0x43 => self.gpu.set_scroll_x(value),
-            0x47 => self.gpu.set_bg_palette(value),
-            0x4a => self.gpu.set_window_y(value),
-            0x4b => self.gpu.set_window_x(value),
-            _ => {
-                panic!("Writing to a IO address not handled: {:#x}", address);
+            // OAM DMA
+            DMA => self.oam_dma.write_byte(value),
+            // GPU registers
+            LCDC ... LCD_LYC | LCD_BGP ... LCD_WX | VBK | BCPS ... OCPD => {
+                self.gpu.write_byte(address, value)
+            }
+            // Speed switch
+            KEY1 => self.speed_switch_req = value & 0x1 == 0x1,
+            // Boot ROM unmap, it can't be mapped again
+            BOOT => {
+                if value & 0x1 != 0 {
+                    self.bootrom = None;
+                }
+            }
+            // VRAM DMA
+            HDMA1 ... HDMA5 => self.write_vramdma(address, value),
+            // Working RAM Bank Number, bank 0 selects bank 1
+            SVBK => {
+                self.wrambank = match value & 0x7 {
+                    0 => 1,
+                    n => n as usize,
+                };
             }
+            _ if is_unused_register(address) => (),
+            _ => self.faults.report(Fault::UnmappedWrite(0xff00 | address, value)),
         }
     }
 
+    /// Returns `true` if the audio player ran out of audio to play
+    pub fn audio_underflowed(&self) -> bool {
+        self.sound.underflowed()
+    }
+
+    /// Resample the audio for an emulation running `speed` times faster
+    pub fn set_audio_speed(&mut self, speed: f64) {
+        self.sound.set_speed(speed);
+    }
+
+    /// Stop or resume sending the audio to the player
+    pub fn set_audio_muted(&mut self, muted: bool) {
+        self.sound.set_muted(muted);
+    }
+
     pub fn screen_buffer(&self) -> &types::ScreenBuffer {
         &self.gpu.back_buffer
     }
 }
+
+/// The fault policy isn't part of the state, it's chosen by the embedder
+impl SaveState for Interconnect {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.irq.enabled);
+        writer.write_u8(self.irq.flag.bits());
+        self.iram.save_state(writer);
+        self.zpage.save_state(writer);
+
+        match self.bootrom {
+            Some(ref bootrom) => {
+                writer.write_bool(true);
+                writer.write_vec(bootrom);
+            }
+            None => writer.write_bool(false),
+        }
+
+        writer.write_bool(self.gbspeed == GbSpeed::Double);
+        writer.write_bool(self.speed_switch_req);
+        writer.write_u8(self.wrambank as u8);
+        writer.write_u8(match self.dma_status {
+            DMAType::NoDMA => 0,
+            DMAType::GDMA => 1,
+            DMAType::HDMA => 2,
+        });
+        writer.write_u16(self.dma_src);
+        writer.write_u16(self.dma_dst);
+        writer.write_u8(self.dma_len);
+
+        self.gpu.save_state(writer);
+        self.timer.save_state(writer);
+        self.sound.save_state(writer);
+        self.serial.save_state(writer);
+        self.joypad.save_state(writer);
+        self.oam_dma.save_state(writer);
+        self.cartridge.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.irq.enabled = reader.read_u8()?;
+        self.irq.set_interrupt_flag(reader.read_u8()?);
+        self.iram.load_state(reader)?;
+        self.zpage.load_state(reader)?;
+
+        self.bootrom = if reader.read_bool()? {
+            Some(reader.read_vec()?)
+        } else {
+            None
+        };
+
+        self.gbspeed = if reader.read_bool()? { GbSpeed::Double } else { GbSpeed::Single };
+        self.speed_switch_req = reader.read_bool()?;
+        self.wrambank = match reader.read_u8()? {
+            bank @ 1 ... 7 => bank as usize,
+            _ => return Err(StateError::InvalidValue("work RAM bank")),
+        };
+        self.dma_status = match reader.read_u8()? {
+            0 => DMAType::NoDMA,
+            1 => DMAType::GDMA,
+            2 => DMAType::HDMA,
+            _ => return Err(StateError::InvalidValue("VRAM DMA state")),
+        };
+        self.dma_src = reader.read_u16()?;
+        self.dma_dst = reader.read_u16()? & 0x1ff0;
+        self.dma_len = reader.read_u8()?;
+
+        self.gpu.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.sound.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.oam_dma.load_state(reader)?;
+        self.cartridge.load_state(reader)
+    }
+}
+
+/// Returns `true` for the IO registers that only exist on the Game Boy
+/// Color
+fn is_color_register(address: u16) -> bool {
+    match address {
+        KEY1 | VBK | HDMA1 ... HDMA5 | BCPS ... OCPD | SVBK => true,
+        _ => false,
+    }
+}
+
+/// Returns `true` for the gaps between the IO registers, they read 0xff
+/// and ignore the writes like on the hardware, without a fault
+fn is_unused_register(address: u16) -> bool {
+    match address {
+        0x03 | 0x08 ... 0x0e | 0x4c | 0x4e | 0x56 ... 0x67 | 0x6c ... 0x6f | 0x71 ... 0x7f => true,
+        _ => false,
+    }
+}
@@ -2,6 +2,8 @@
 
 use std::iter;
 
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
+
 /// RAM image
 pub struct Ram {
     data: Vec<u8>
@@ -25,3 +27,13 @@ impl Ram {
     /// Write a byte to the Ram
     pub fn set_byte(&mut self, offset: u16, val: u8) { self.data[offset as usize] = val; }
 }
+
+impl SaveState for Ram {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.data);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        reader.read_into(&mut self.data)
+    }
+}
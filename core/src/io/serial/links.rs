@@ -0,0 +1,54 @@
+//! Built-in link cable devices.
+
+use std::io::{self, Write};
+
+use super::SerialLink;
+
+/// Nothing connected, every received byte reads as 0xff
+pub struct DisconnectedLink;
+
+impl SerialLink for DisconnectedLink {
+    fn is_connected(&self) -> bool {
+        false
+    }
+
+    fn exchange(&mut self, _value: u8) -> io::Result<u8> {
+        Ok(0xff)
+    }
+}
+
+/// Prints every sent byte to the standard output as text. Test ROMs (e.g.
+/// Blargg's) report their results this way.
+pub struct StdoutLink;
+
+impl SerialLink for StdoutLink {
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn exchange(&mut self, value: u8) -> io::Result<u8> {
+        let mut stdout = io::stdout();
+        stdout.write_all(&[value])?;
+        stdout.flush()?;
+
+        Ok(0xff)
+    }
+}
+
+/// Cable plugged back into the same Game Boy, every sent byte is received
+/// back. Also answers transfers waiting on the external clock.
+pub struct LoopbackLink;
+
+impl SerialLink for LoopbackLink {
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn exchange(&mut self, value: u8) -> io::Result<u8> {
+        Ok(value)
+    }
+
+    fn external_clock(&mut self, value: u8, ready: bool) -> io::Result<Option<u8>> {
+        Ok(if ready { Some(value) } else { None })
+    }
+}
@@ -0,0 +1,324 @@
+//! Serial port (link cable) emulation.
+//!
+//! The port shifts out the SB register one bit at a time while shifting in
+//! the bits of the other device. What is connected on the other end of the
+//! cable is abstracted by the `SerialLink` trait.
+
+use std::io;
+
+use super::irq::{Irq, Interrupt};
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
+
+pub use self::links::{DisconnectedLink, StdoutLink, LoopbackLink};
+pub use self::socket_link::SocketLink;
+
+mod links;
+mod socket_link;
+
+/// Clock ticks per transferred bit using the internal clock (8192 Hz)
+const TICKS_PER_BIT: u32 = 512;
+/// Bits of a transfer
+const TRANSFER_BITS: u32 = 8;
+
+/// Device connected to the other end of the link cable.
+///
+/// Errors mean the device was disconnected, the port then behaves as if
+/// the cable was unplugged.
+pub trait SerialLink: Send {
+    /// Returns `true` if there is a device on the other end of the cable
+    fn is_connected(&self) -> bool;
+
+    /// Exchange a byte clocked by this Game Boy (internal clock): `value`
+    /// is sent and the byte sent back by the other device is returned
+    fn exchange(&mut self, value: u8) -> io::Result<u8>;
+
+    /// Start an exchange without blocking the emulation. Returns `None`
+    /// when the answer isn't there yet, `poll_exchange` is then called
+    /// until it is.
+    fn begin_exchange(&mut self, value: u8) -> io::Result<Option<u8>> {
+        self.exchange(value).map(Some)
+    }
+
+    /// Get the answer of the exchange started by `begin_exchange`, if it
+    /// arrived
+    fn poll_exchange(&mut self) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    /// Called on every serial cycle where this Game Boy isn't driving a
+    /// transfer, so the other device can clock one (external clock). When
+    /// it did and `ready` is set, i.e. a transfer was started, `value` is
+    /// sent back and the received byte is returned.
+    fn external_clock(&mut self, _value: u8, _ready: bool) -> io::Result<Option<u8>> {
+        Ok(None)
+    }
+
+    /// Called with the dots (4 MHz, whatever the CPU speed) emulated since
+    /// the last call, so devices running their own clock can keep up
+    fn sync(&mut self, _dots: u32) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+bitflags!(
+    struct Control: u8 {
+        /// Shift clock, set when this Game Boy drives the transfer
+        const INTERNAL_CLOCK = 1 << 0;
+        /// Set to start a transfer, cleared once it's finished
+        const START          = 1 << 7;
+    }
+);
+
+/// Unused SC bits, they always read as 1
+const CONTROL_UNUSED_MASK: u8 = 0x7e;
+
+pub struct Serial {
+    // data
+    data: u8,
+    // control
+    control: Control,
+    // ticks left until the running internal clock transfer ends
+    remaining_ticks: u32,
+    // the transfer ended and waits for the answer of the device
+    exchanging: bool,
+    // device connected to the port
+    link: Box<SerialLink>,
+    // error that disconnected the last link, until it's taken
+    link_error: Option<io::Error>,
+}
+
+impl Serial {
+    /// create a new serial instance, without any device connected
+    pub fn new() -> Serial {
+        Serial {
+            data: 0,
+            control: Control::empty(),
+            remaining_ticks: 0,
+            exchanging: false,
+            link: Box::new(DisconnectedLink),
+            link_error: None,
+        }
+    }
+
+    /// connect a new device to the port
+    pub fn set_link(&mut self, link: Box<SerialLink>) {
+        self.link = link;
+    }
+
+    /// take the error that disconnected the link, if any
+    pub fn take_link_error(&mut self) -> Option<io::Error> {
+        self.link_error.take()
+    }
+
+    /// unplug the link after an error, keeping the error for the embedder
+    fn disconnect(&mut self, error: io::Error) {
+        error!("Link cable disconnected: {}", error);
+
+        self.link = Box::new(DisconnectedLink);
+        self.link_error = Some(error);
+        self.exchanging = false;
+    }
+
+    /// let the device follow the emulated time
+    pub fn sync_link(&mut self, dots: u32) {
+        if let Err(e) = self.link.sync(dots) {
+            self.disconnect(e);
+        }
+    }
+
+    /// exchange SB with the device at the end of an internal clock
+    /// transfer, returns `None` while its answer is pending so the
+    /// emulation goes on meanwhile
+    fn exchange(&mut self) -> Option<u8> {
+        // without a cable the input line is pulled up
+        if !self.link.is_connected() {
+            return Some(0xff);
+        }
+
+        let result = if self.exchanging {
+            self.link.poll_exchange()
+        } else {
+            self.link.begin_exchange(self.data)
+        };
+
+        match result {
+            Ok(Some(value)) => {
+                self.exchanging = false;
+                Some(value)
+            }
+            Ok(None) => {
+                self.exchanging = true;
+                None
+            }
+            Err(e) => {
+                self.exchanging = false;
+                self.disconnect(e);
+                Some(0xff)
+            }
+        }
+    }
+
+    /// read a byte from the serial
+    pub fn read_byte(&self, address: u16) -> u8 {
+        match address {
+            0xff01 => self.data,
+            0xff02 => self.control.bits() | CONTROL_UNUSED_MASK,
+            _ => 0xff,
+        }
+    }
+
+    /// write a byte to the serial port
+    pub fn write_byte(&mut self, address: u16, value: u8) {
+        match address {
+            0xff01 => self.data = value,
+            0xff02 => {
+                self.control = Control::from_bits_truncate(value);
+
+                // the transfer takes 8 bits at the internal clock rate
+                if self.control.contains(Control::START | Control::INTERNAL_CLOCK) {
+                    self.remaining_ticks = TICKS_PER_BIT * TRANSFER_BITS;
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// execute the serial cycle
+    pub fn do_cycle(&mut self, ticks: u32, irq: &mut Irq) {
+        let internal = self.control.contains(Control::START | Control::INTERNAL_CLOCK);
+
+        let received = if internal {
+            if self.remaining_ticks > ticks {
+                self.remaining_ticks -= ticks;
+                return;
+            }
+
+            self.remaining_ticks = 0;
+
+            match self.exchange() {
+                Some(value) => value,
+                None => return,
+            }
+        } else {
+            // the other device may clock a transfer at any time, but it
+            // only completes if one was started with the external clock
+            let ready = self.control.contains(Control::START);
+
+            match self.link.external_clock(self.data, ready) {
+                Ok(Some(value)) if ready => value,
+                Ok(_) => return,
+                Err(e) => {
+                    self.disconnect(e);
+                    return;
+                }
+            }
+        };
+
+        self.data = received;
+        self.control.remove(Control::START);
+        irq.request_interrupt(Interrupt::SerialIOComplete);
+    }
+}
+
+/// The device plugged on the port isn't part of the state, a transfer
+/// running when the state was saved carries on with the current one
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.data);
+        writer.write_u8(self.control.bits());
+        writer.write_u32(self.remaining_ticks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.data = reader.read_u8()?;
+        self.control = Control::from_bits_truncate(reader.read_u8()?);
+        self.remaining_ticks = reader.read_u32()?;
+        self.exchanging = false;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SB: u16 = 0xff01;
+    const SC: u16 = 0xff02;
+    /// Ticks of a whole internal clock transfer
+    const TRANSFER_TICKS: u32 = TICKS_PER_BIT * TRANSFER_BITS;
+
+    fn transfer_completed(irq: &Irq) -> bool {
+        irq.get_interrupt_flag() & Interrupt::SerialIOComplete as u8 != 0
+    }
+
+    fn transfer_running(serial: &Serial) -> bool {
+        serial.read_byte(SC) & Control::START.bits() != 0
+    }
+
+    #[test]
+    fn internal_clock_transfer_takes_8_bits() {
+        let mut serial = Serial::new();
+        let mut irq = Irq::new();
+
+        serial.write_byte(SB, 0x42);
+        serial.write_byte(SC, 0x81);
+        serial.do_cycle(TRANSFER_TICKS - 1, &mut irq);
+        assert!(transfer_running(&serial));
+        assert!(!transfer_completed(&irq));
+
+        serial.do_cycle(1, &mut irq);
+        assert!(!transfer_running(&serial));
+        assert!(transfer_completed(&irq));
+    }
+
+    #[test]
+    fn disconnected_link_reads_0xff() {
+        let mut serial = Serial::new();
+        let mut irq = Irq::new();
+        serial.set_link(Box::new(DisconnectedLink));
+
+        serial.write_byte(SB, 0x42);
+        serial.write_byte(SC, 0x81);
+        serial.do_cycle(TRANSFER_TICKS, &mut irq);
+
+        assert_eq!(serial.read_byte(SB), 0xff);
+        assert!(transfer_completed(&irq));
+    }
+
+    #[test]
+    fn loopback_link_echoes_sb() {
+        let mut serial = Serial::new();
+        let mut irq = Irq::new();
+        serial.set_link(Box::new(LoopbackLink));
+
+        serial.write_byte(SB, 0x42);
+        serial.write_byte(SC, 0x81);
+        serial.do_cycle(TRANSFER_TICKS, &mut irq);
+        assert_eq!(serial.read_byte(SB), 0x42);
+        assert!(transfer_completed(&irq));
+
+        // it also clocks the transfers waiting on the external clock
+        let mut irq = Irq::new();
+        serial.write_byte(SB, 0x24);
+        serial.write_byte(SC, 0x80);
+        serial.do_cycle(4, &mut irq);
+        assert_eq!(serial.read_byte(SB), 0x24);
+        assert!(transfer_completed(&irq));
+    }
+
+    #[test]
+    fn external_clock_transfer_waits_for_a_device() {
+        let mut serial = Serial::new();
+        let mut irq = Irq::new();
+
+        serial.write_byte(SB, 0x42);
+        serial.write_byte(SC, 0x80);
+        for _ in 0..100 {
+            serial.do_cycle(TRANSFER_TICKS, &mut irq);
+        }
+
+        assert!(transfer_running(&serial));
+        assert!(!transfer_completed(&irq));
+        assert_eq!(serial.read_byte(SB), 0x42);
+    }
+}
@@ -0,0 +1,449 @@
+//! Link cable between two emulator instances over a TCP or Unix socket.
+//!
+//! Every message is two bytes: a kind and a value. After a handshake, the
+//! instance that starts a transfer with its internal clock acts as master
+//! for that byte: it sends `TRANSFER` and the transfer only completes once
+//! the other instance answers with `REPLY` and its own SB, or 0xff when it
+//! wasn't waiting for a transfer. The clock source is negotiated per
+//! transfer from the SC register of each side, like on the real hardware.
+//!
+//! The instances also run in lockstep: each one sends a `SYNC` message
+//! every `SYNC_QUANTUM` dots of emulated time and stops when it gets more
+//! than `MAX_LEAD` quanta ahead of the other one, until the other one
+//! catches up. The messages are only read on the quantum boundaries, the
+//! emulation goes on while a transfer waits for its answer. An instance
+//! that stops sending `SYNC` messages for `REPLY_TIMEOUT`, e.g. because it
+//! was paused, unplugs the cable.
+
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::time::{Duration, Instant};
+
+use super::SerialLink;
+
+/// Protocol version exchanged on the handshake
+const PROTOCOL_VERSION: u8 = 2;
+
+/// Handshake, the value is the protocol version
+const HELLO: u8 = 0x01;
+/// Transfer clocked by the sender, the value is its SB
+const TRANSFER: u8 = 0x02;
+/// Answer to a transfer, the value is the SB of the receiver
+const REPLY: u8 = 0x03;
+/// The sender ran another `SYNC_QUANTUM` dots, the value is unused
+const SYNC: u8 = 0x04;
+
+/// Time to wait for the other instance to answer before considering it
+/// gone
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+/// Dots between two `SYNC` messages, a quarter of a transferred byte
+const SYNC_QUANTUM: u32 = 1024;
+/// Quanta an instance can run ahead of the other one
+const MAX_LEAD: u64 = 4;
+
+/// Prefix used to select a Unix domain socket on the address strings
+const UNIX_PREFIX: &str = "unix:";
+
+/// Operations needed from the socket types
+trait Stream: Read + Write + Send {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+}
+
+impl Stream for TcpStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
+
+#[cfg(unix)]
+impl Stream for UnixStream {
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        UnixStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        UnixStream::set_read_timeout(self, timeout)
+    }
+}
+
+pub struct SocketLink {
+    stream: Box<Stream>,
+    /// Bytes of a message not completely received yet
+    pending: Vec<u8>,
+    /// Current blocking mode of the stream, to avoid a syscall per read
+    nonblocking: bool,
+    /// Dots run since the last `SYNC` sent
+    dots: u32,
+    /// Quanta run by this instance
+    quanta: u64,
+    /// Quanta run by the other instance
+    peer_quanta: u64,
+    /// Byte of a transfer clocked by the other instance, not answered yet
+    incoming: Option<u8>,
+    /// Answer to the transfer sent by this instance
+    reply: Option<u8>,
+    /// When the transfer waiting for an answer was sent
+    transfer_sent: Option<Instant>,
+}
+
+impl SocketLink {
+    /// Wait for the other instance to connect on `address`, either
+    /// `host:port` or `unix:/path/to/socket`
+    pub fn listen(address: &str) -> io::Result<SocketLink> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            return listen_unix(path);
+        }
+
+        let (stream, _) = TcpListener::bind(address)?.accept()?;
+        stream.set_nodelay(true)?;
+
+        SocketLink::handshake(Box::new(stream))
+    }
+
+    /// Connect to an instance listening on `address`, either `host:port`
+    /// or `unix:/path/to/socket`
+    pub fn connect(address: &str) -> io::Result<SocketLink> {
+        if let Some(path) = address.strip_prefix(UNIX_PREFIX) {
+            return connect_unix(path);
+        }
+
+        let stream = TcpStream::connect(address)?;
+        stream.set_nodelay(true)?;
+
+        SocketLink::handshake(Box::new(stream))
+    }
+
+    /// Exchange the protocol version with the other instance
+    fn handshake(stream: Box<Stream>) -> io::Result<SocketLink> {
+        stream.set_read_timeout(Some(REPLY_TIMEOUT))?;
+
+        let mut link = SocketLink {
+            stream,
+            pending: Vec::with_capacity(2),
+            nonblocking: false,
+            dots: 0,
+            quanta: 0,
+            peer_quanta: 0,
+            incoming: None,
+            reply: None,
+            transfer_sent: None,
+        };
+
+        link.send(HELLO, PROTOCOL_VERSION)?;
+
+        match link.receive(true)? {
+            Some((HELLO, PROTOCOL_VERSION)) => Ok(link),
+            Some((HELLO, version)) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Link cable protocol version mismatch: {} != {}", version, PROTOCOL_VERSION),
+            )),
+            _ => Err(invalid_message()),
+        }
+    }
+
+    /// Send a message
+    fn send(&mut self, kind: u8, value: u8) -> io::Result<()> {
+        self.stream.write_all(&[kind, value])?;
+        self.stream.flush()
+    }
+
+    /// Receive a message. When `blocking` waits up to `REPLY_TIMEOUT`,
+    /// otherwise returns `None` if no complete message is available.
+    fn receive(&mut self, blocking: bool) -> io::Result<Option<(u8, u8)>> {
+        if self.nonblocking == blocking {
+            self.stream.set_nonblocking(!blocking)?;
+            self.nonblocking = !blocking;
+        }
+
+        while self.pending.len() < 2 {
+            let mut bytes = [0u8; 2];
+            let missing = 2 - self.pending.len();
+
+            match self.stream.read(&mut bytes[..missing]) {
+                Ok(0) => {
+                    return Err(io::Error::new(io::ErrorKind::ConnectionAborted,
+                                              "The other instance closed the link cable"));
+                }
+                Ok(count) => self.pending.extend_from_slice(&bytes[..count]),
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock && !blocking => return Ok(None),
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    return Err(stopped_answering());
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        let message = (self.pending[0], self.pending[1]);
+        self.pending.clear();
+
+        Ok(Some(message))
+    }
+
+    /// Update the link with a message of the other instance
+    fn handle(&mut self, message: (u8, u8)) -> io::Result<()> {
+        match message {
+            (SYNC, _) => self.peer_quanta += 1,
+            // Both instances started a transfer with the internal clock at
+            // the same time, each one gets the byte of the other
+            (TRANSFER, value) if self.transfer_sent.is_some() => self.reply = Some(value),
+            (TRANSFER, value) => self.incoming = Some(value),
+            (REPLY, value) if self.transfer_sent.is_some() => self.reply = Some(value),
+            _ => return Err(invalid_message()),
+        }
+
+        Ok(())
+    }
+
+    /// Handle the messages already received, without waiting
+    fn handle_received(&mut self) -> io::Result<()> {
+        while let Some(message) = self.receive(false)? {
+            self.handle(message)?;
+        }
+
+        Ok(())
+    }
+
+    /// Wait for the next message of the other instance and handle it
+    fn handle_next(&mut self) -> io::Result<()> {
+        match self.receive(true)? {
+            Some(message) => self.handle(message),
+            None => Err(stopped_answering()),
+        }
+    }
+}
+
+impl SerialLink for SocketLink {
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    /// Blocks until the other instance answers, which it only does while
+    /// running: the serial port uses `begin_exchange` instead
+    fn exchange(&mut self, value: u8) -> io::Result<u8> {
+        self.reply = self.incoming.take();
+        self.send(TRANSFER, value)?;
+        self.transfer_sent = Some(Instant::now());
+
+        while self.reply.is_none() {
+            self.handle_next()?;
+        }
+
+        self.transfer_sent = None;
+        Ok(self.reply.take().unwrap())
+    }
+
+    fn begin_exchange(&mut self, value: u8) -> io::Result<Option<u8>> {
+        // the other instance clocked a transfer too before this one was
+        // started, it's answered by this one
+        self.reply = self.incoming.take();
+        self.send(TRANSFER, value)?;
+        self.transfer_sent = Some(Instant::now());
+
+        self.poll_exchange()
+    }
+
+    fn poll_exchange(&mut self) -> io::Result<Option<u8>> {
+        if let Some(value) = self.reply.take() {
+            self.transfer_sent = None;
+            return Ok(Some(value));
+        }
+
+        if self.transfer_sent.map_or(false, |sent| sent.elapsed() > REPLY_TIMEOUT) {
+            self.transfer_sent = None;
+            return Err(stopped_answering());
+        }
+
+        Ok(None)
+    }
+
+    fn external_clock(&mut self, value: u8, ready: bool) -> io::Result<Option<u8>> {
+        let received = match self.incoming.take() {
+            Some(received) => received,
+            None => return Ok(None),
+        };
+
+        // without a transfer started the bits are shifted in and out of
+        // nothing, the other instance reads the line pulled up
+        if ready {
+            self.send(REPLY, value)?;
+            Ok(Some(received))
+        } else {
+            self.send(REPLY, 0xff)?;
+            Ok(None)
+        }
+    }
+
+    fn sync(&mut self, dots: u32) -> io::Result<()> {
+        self.dots += dots;
+
+        while self.dots >= SYNC_QUANTUM {
+            self.dots -= SYNC_QUANTUM;
+            self.quanta += 1;
+            self.send(SYNC, 0)?;
+            self.handle_received()?;
+
+            // wait for the other instance to catch up
+            while self.quanta > self.peer_quanta + MAX_LEAD {
+                self.handle_next()?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn invalid_message() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Unexpected link cable message")
+}
+
+fn stopped_answering() -> io::Error {
+    io::Error::new(io::ErrorKind::TimedOut, "The other instance stopped answering the link cable")
+}
+
+#[cfg(unix)]
+fn listen_unix(path: &str) -> io::Result<SocketLink> {
+    let (stream, _) = UnixListener::bind(path)?.accept()?;
+    SocketLink::handshake(Box::new(stream))
+}
+
+#[cfg(unix)]
+fn connect_unix(path: &str) -> io::Result<SocketLink> {
+    SocketLink::handshake(Box::new(UnixStream::connect(path)?))
+}
+
+#[cfg(not(unix))]
+fn listen_unix(_path: &str) -> io::Result<SocketLink> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+}
+
+#[cfg(not(unix))]
+fn connect_unix(_path: &str) -> io::Result<SocketLink> {
+    Err(io::Error::new(io::ErrorKind::Other, "Unix sockets are not supported on this platform"))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::io::irq::{Interrupt, Irq};
+    use crate::io::serial::Serial;
+
+    const SB: u16 = 0xff01;
+    const SC: u16 = 0xff02;
+    /// Bounds the loops waiting for a transfer, a few transfers long
+    const MAX_STEPS: u32 = 100_000;
+
+    /// Two serial ports wired through a socket pair
+    fn linked() -> (Serial, Serial) {
+        let (first, second) = UnixStream::pair().unwrap();
+        let other = thread::spawn(move || SocketLink::handshake(Box::new(second)).unwrap());
+        let link = SocketLink::handshake(Box::new(first)).unwrap();
+
+        let mut master = Serial::new();
+        let mut slave = Serial::new();
+        master.set_link(Box::new(link));
+        slave.set_link(Box::new(other.join().unwrap()));
+
+        (master, slave)
+    }
+
+    /// Run one instruction worth of cycles on a serial port
+    fn step(serial: &mut Serial, irq: &mut Irq) {
+        serial.sync_link(4);
+        serial.do_cycle(4, irq);
+    }
+
+    fn transfer_completed(irq: &Irq) -> bool {
+        irq.get_interrupt_flag() & Interrupt::SerialIOComplete as u8 != 0
+    }
+
+    #[test]
+    fn master_and_slave_exchange_their_bytes() {
+        let (mut master, mut slave) = linked();
+        let (mut master_irq, mut slave_irq) = (Irq::new(), Irq::new());
+
+        master.write_byte(SB, 0x42);
+        slave.write_byte(SB, 0x24);
+        slave.write_byte(SC, 0x80);
+        master.write_byte(SC, 0x81);
+
+        for _ in 0..MAX_STEPS {
+            if transfer_completed(&master_irq) && transfer_completed(&slave_irq) {
+                break;
+            }
+
+            step(&mut master, &mut master_irq);
+            step(&mut slave, &mut slave_irq);
+        }
+
+        assert!(transfer_completed(&master_irq));
+        assert!(transfer_completed(&slave_irq));
+        assert_eq!(master.read_byte(SB), 0x24);
+        assert_eq!(slave.read_byte(SB), 0x42);
+        assert!(master.take_link_error().is_none());
+        assert!(slave.take_link_error().is_none());
+    }
+
+    #[test]
+    fn slave_not_ready_answers_0xff() {
+        let (mut master, mut slave) = linked();
+        let (mut master_irq, mut slave_irq) = (Irq::new(), Irq::new());
+
+        master.write_byte(SB, 0x42);
+        slave.write_byte(SB, 0x24);
+        master.write_byte(SC, 0x81);
+
+        for _ in 0..MAX_STEPS {
+            if transfer_completed(&master_irq) {
+                break;
+            }
+
+            step(&mut master, &mut master_irq);
+            step(&mut slave, &mut slave_irq);
+        }
+
+        assert!(transfer_completed(&master_irq));
+        assert_eq!(master.read_byte(SB), 0xff);
+
+        // the transfer is lost, a later one doesn't complete with it
+        slave.write_byte(SC, 0x80);
+        for _ in 0..MAX_STEPS / 10 {
+            step(&mut master, &mut master_irq);
+            step(&mut slave, &mut slave_irq);
+        }
+
+        assert!(!transfer_completed(&slave_irq));
+        assert_eq!(slave.read_byte(SB), 0x24);
+    }
+
+    #[test]
+    fn dropped_peer_disconnects_the_cable() {
+        let (mut master, slave) = linked();
+        let mut irq = Irq::new();
+        drop(slave);
+
+        master.write_byte(SB, 0x42);
+        master.write_byte(SC, 0x81);
+
+        for _ in 0..MAX_STEPS {
+            if transfer_completed(&irq) {
+                break;
+            }
+
+            step(&mut master, &mut irq);
+        }
+
+        assert!(transfer_completed(&irq));
+        assert_eq!(master.read_byte(SB), 0xff);
+        assert!(master.take_link_error().is_some());
+    }
+}
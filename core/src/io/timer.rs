@@ -1,15 +1,36 @@
 use super::irq::{Irq, Interrupt};
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
+
+/// Clock ticks in a machine cycle, the timer is updated once per machine
+/// cycle
+const TICKS_PER_CYCLE: u32 = 4;
+
+/// State of the TIMA reload after an overflow
+#[derive(Debug, PartialEq, Clone, Copy)]
+enum Reload {
+    /// No reload is pending
+    None,
+    /// TIMA overflowed during the last machine cycle and reads as 0, it
+    /// will be reloaded with TMA on the next one. Writing to TIMA now
+    /// cancels the reload.
+    Pending,
+    /// TIMA was reloaded on this machine cycle, TIMA writes are ignored
+    /// and TMA writes are also copied to TIMA
+    Reloading,
+}
 
 #[derive(Debug)]
 pub struct Timer {
-    // DIV (Divider Register)
-    divider: u8,
+    // Internal 16-bit system counter, DIV is its upper byte
+    system_counter: u16,
     // TIMA  (timer counter)
     counter: u8,
     // TMA (Timer Modulo)
     modulo: u8,
     // TAC (Timer Control)
     control: u8,
+    // TIMA reload state
+    reload: Reload,
     // Internal Ticks
     internal_ticks: u32
 }
@@ -18,10 +39,11 @@ impl Timer {
     /// create a new Timer instance
     pub fn new() -> Timer {
         Timer {
-            divider: 0,
+            system_counter: 0,
             counter: 0,
             modulo: 0,
             control: 0,
+            reload: Reload::None,
             internal_ticks: 0
         }
     }
@@ -30,73 +52,297 @@ impl Timer {
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
             // divider
-            0xff04 => self.divider,
+            0xff04 => (self.system_counter >> 8) as u8,
             // counter
             0xff05 => self.counter,
             // modulo
             0xff06 => self.modulo,
-            // control
-            0xff07 => self.control,
-            _ => panic!("Timer does not handler read {:#x}", address),
+            // control, the unused bits always read as 1
+            0xff07 => 0xf8 | self.control,
+            _ => 0xff,
         }
     }
 
+    /// set the system counter, the upper byte is the divider. Unlike a
+    /// write to DIV it doesn't clock the counter.
+    pub fn set_system_counter(&mut self, value: u16) {
+        self.system_counter = value;
+    }
+
     /// write a byte on timer
     pub fn write_byte(&mut self, address: u16, value: u8) {
         match address {
-            // divider
-            0xff04 => { self.divider = 0; }
+            // divider, any write resets the whole system counter which
+            // can produce a falling edge on the timer input
+            0xff04 => {
+                let input = self.timer_input();
+                self.system_counter = 0;
+                self.detect_falling_edge(input);
+            }
             // counter
-            0xff05 => { self.counter.wrapping_add(value); }
+            0xff05 => {
+                match self.reload {
+                    Reload::None => self.counter = value,
+                    Reload::Pending => {
+                        self.counter = value;
+                        self.reload = Reload::None;
+                    }
+                    Reload::Reloading => (),
+                }
+            }
             // modulo
-            0xff06 => { self.modulo.wrapping_add(value); }
-            // control
-            0xff07 => { self.control = value; }
-            _ => panic!("Timer does not handler write {:#x}", address),
+            0xff06 => {
+                self.modulo = value;
+
+                if self.reload == Reload::Reloading {
+                    self.counter = value;
+                }
+            }
+            // control, changing the clock or disabling the timer can
+            // also produce a falling edge
+            0xff07 => {
+                let input = self.timer_input();
+                self.control = value & 0b111;
+                self.detect_falling_edge(input);
+            }
+            _ => (),
         }
     }
 
-    /// get timer clock
-    fn get_clock(&self) -> u8 {
+    /// get the bit of the system counter used as timer clock
+    fn get_clock(&self) -> u16 {
         match self.control & 0b11 {
             // 4.096 KHz
-            0b00 => 4,
+            0b00 => 1 << 9,
             // 262.144 KHz
-            0b01 => 262,
+            0b01 => 1 << 3,
             // 65.536 KHz
-            0b10 => 65,
+            0b10 => 1 << 5,
             // 16.384 KHz
-            0b11 => 16,
-            // Invalid clock
-            _ => panic!("Invalid clock"),
+            _ => 1 << 7,
         }
     }
 
     /// is clock enable
-    fn is_clock_enable(&self) -> bool { self.control & 0b100 == 1 }
+    fn is_clock_enable(&self) -> bool { self.control & 0b100 != 0 }
 
-    /// execute the timer cycle
-    pub fn do_cycle(&mut self, ticks: u32, irq: &mut Irq) {
-        // we use the internal_ticks to check if we need
-        // to increment the divider register
-        self.internal_ticks += ticks;
+    /// the timer input is the selected system counter bit ANDed with the
+    /// enable bit, TIMA is incremented on its falling edges
+    fn timer_input(&self) -> bool {
+        self.is_clock_enable() && self.system_counter & self.get_clock() != 0
+    }
 
-        while self.internal_ticks >= 256 {
-            self.divider = self.divider.wrapping_add(1);
-            self.internal_ticks -= 256;
+    /// increment TIMA if the timer input went from high to low
+    fn detect_falling_edge(&mut self, old_input: bool) {
+        if old_input && !self.timer_input() {
+            self.increment_counter();
         }
+    }
+
+    /// increment TIMA, on overflow it stays at 0 for a machine cycle
+    /// before being reloaded with TMA
+    fn increment_counter(&mut self) {
+        let (counter, overflow) = self.counter.overflowing_add(1);
+        self.counter = counter;
 
-        // is clock enable?
-        if self.is_clock_enable() {
-            // increment one to the counter
-            self.counter.wrapping_add(1);
+        if overflow {
+            self.reload = Reload::Pending;
+        }
+    }
 
-            // when the counter overflows generate an interrupt
-            // and then are loaded with the contents of TMA
-            if self.counter == 0 {
+    /// execute a single machine cycle
+    fn step(&mut self, irq: &mut Irq) {
+        self.reload = match self.reload {
+            Reload::Pending => {
                 self.counter = self.modulo;
                 irq.request_interrupt(Interrupt::TimerOverflow);
+                Reload::Reloading
             }
+            _ => Reload::None,
+        };
+
+        let input = self.timer_input();
+        self.system_counter = self.system_counter.wrapping_add(TICKS_PER_CYCLE as u16);
+        self.detect_falling_edge(input);
+    }
+
+    /// execute the timer cycle
+    pub fn do_cycle(&mut self, ticks: u32, irq: &mut Irq) {
+        self.internal_ticks += ticks;
+
+        while self.internal_ticks >= TICKS_PER_CYCLE {
+            self.step(irq);
+            self.internal_ticks -= TICKS_PER_CYCLE;
+        }
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.system_counter);
+        writer.write_u8(self.counter);
+        writer.write_u8(self.modulo);
+        writer.write_u8(self.control);
+        writer.write_u8(match self.reload {
+            Reload::None => 0,
+            Reload::Pending => 1,
+            Reload::Reloading => 2,
+        });
+        writer.write_u32(self.internal_ticks);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.system_counter = reader.read_u16()?;
+        self.counter = reader.read_u8()?;
+        self.modulo = reader.read_u8()?;
+        let control = reader.read_u8()?;
+
+        // only the clock select and enable bits are stored
+        if control > 0b111 {
+            return Err(StateError::InvalidValue("timer control"));
         }
+
+        self.control = control;
+
+        self.reload = match reader.read_u8()? {
+            0 => Reload::None,
+            1 => Reload::Pending,
+            2 => Reload::Reloading,
+            _ => return Err(StateError::InvalidValue("timer reload")),
+        };
+        self.internal_ticks = reader.read_u32()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// TIMA input on bit 3 of the system counter, a falling edge every 16
+    /// clock ticks
+    const TAC_FAST: u8 = 0b101;
+
+    fn timer_overflow_requested(irq: &Irq) -> bool {
+        irq.get_interrupt_flag() & Interrupt::TimerOverflow as u8 != 0
+    }
+
+    #[test]
+    fn counts_on_the_selected_clock() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff07, TAC_FAST);
+        timer.do_cycle(15, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 0);
+
+        timer.do_cycle(1, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 1);
+
+        timer.do_cycle(16 * 9, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 10);
+    }
+
+    #[test]
+    fn div_reset_on_a_high_input_increments() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff07, TAC_FAST);
+        timer.do_cycle(8, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 0);
+
+        timer.write_byte(0xff04, 0x12);
+        assert_eq!(timer.read_byte(0xff05), 1);
+        assert_eq!(timer.read_byte(0xff04), 0);
+
+        // the input was low, no edge
+        timer.write_byte(0xff04, 0x12);
+        assert_eq!(timer.read_byte(0xff05), 1);
+    }
+
+    #[test]
+    fn disabling_on_a_high_input_increments() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff07, TAC_FAST);
+        timer.do_cycle(8, &mut irq);
+
+        timer.write_byte(0xff07, 0);
+        assert_eq!(timer.read_byte(0xff05), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_a_cycle_later() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff05, 0xff);
+        timer.write_byte(0xff06, 0x42);
+        timer.write_byte(0xff07, TAC_FAST);
+
+        timer.do_cycle(16, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 0);
+        assert!(!timer_overflow_requested(&irq));
+
+        timer.do_cycle(4, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 0x42);
+        assert!(timer_overflow_requested(&irq));
+    }
+
+    #[test]
+    fn tima_write_cancels_the_pending_reload() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff05, 0xff);
+        timer.write_byte(0xff06, 0x42);
+        timer.write_byte(0xff07, TAC_FAST);
+        timer.do_cycle(16, &mut irq);
+
+        timer.write_byte(0xff05, 0x10);
+        timer.do_cycle(4, &mut irq);
+        assert_eq!(timer.read_byte(0xff05), 0x10);
+        assert!(!timer_overflow_requested(&irq));
+    }
+
+    #[test]
+    fn writes_on_the_reload_cycle() {
+        let mut timer = Timer::new();
+        let mut irq = Irq::new();
+
+        timer.write_byte(0xff05, 0xff);
+        timer.write_byte(0xff06, 0x42);
+        timer.write_byte(0xff07, TAC_FAST);
+        timer.do_cycle(20, &mut irq);
+
+        // TIMA writes are ignored, TMA writes go through to TIMA
+        timer.write_byte(0xff05, 0x10);
+        assert_eq!(timer.read_byte(0xff05), 0x42);
+
+        timer.write_byte(0xff06, 0x80);
+        assert_eq!(timer.read_byte(0xff05), 0x80);
+    }
+
+    #[test]
+    fn state_rejects_unused_control_bits() {
+        let mut timer = Timer::new();
+        timer.write_byte(0xff07, TAC_FAST);
+
+        let mut writer = StateWriter::new(0);
+        timer.save_state(&mut writer);
+        let mut data = writer.into_data();
+
+        let mut reader = StateReader::new(&data, 0).unwrap();
+        assert_eq!(Timer::new().load_state(&mut reader), Ok(()));
+
+        // the control is followed by the reload state and the ticks
+        let control = data.len() - 6;
+        assert_eq!(data[control], TAC_FAST);
+        data[control] = 0xf8 | TAC_FAST;
+
+        let mut reader = StateReader::new(&data, 0).unwrap();
+        assert_eq!(Timer::new().load_state(&mut reader), Err(StateError::InvalidValue("timer control")));
     }
 }
@@ -1,5 +1,6 @@
 #[macro_use]
 extern crate bitflags;
+#[cfg(feature = "cpal")]
 extern crate cpal;
 #[macro_use]
 extern crate error_chain;
@@ -11,12 +12,24 @@ extern crate nom;
 
 use std::fs::File;
 use std::io::Read;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use crate::cartridge::Cartridge;
-pub use crate::machine::Machine;
+pub use crate::cartridge::{CartridgeHeader, CartridgeError, CartridgeResult, CgbSupport, Destination};
+pub use crate::machine::{Machine, TICKS_PER_FRAME};
+pub use crate::debugger::Debugger;
+pub use crate::logger::init_logger;
 pub use crate::config::Config;
-pub use crate::gpu::types::{ScreenBuffer, Color};
+pub use crate::gpu::types::{ScreenBuffer, Color, rgb555_to_rgb888};
+pub use crate::gpu::png::write_png;
+pub use crate::sound::{AudioPlayer, NullPlayer, WavFilePlayer, RingBufferPlayer, RingBufferConsumer};
+#[cfg(feature = "cpal")]
+pub use crate::sound::CpalPlayer;
+pub use crate::types::GbKey;
+pub use crate::io::fault::{Fault, FaultPolicy};
+pub use crate::io::bootrom::Boot;
+pub use crate::state::{StateError, StateResult, STATE_VERSION};
+pub use crate::io::serial::{SerialLink, DisconnectedLink, StdoutLink, LoopbackLink, SocketLink};
 
 mod io;
 mod cpu;
@@ -27,6 +40,9 @@ mod machine;
 mod debugger;
 mod types;
 mod config;
+mod state;
+mod rewind;
+mod logger;
 
 pub const SCREEN_X: usize = 160;
 pub const SCREEN_Y: usize = 144;
@@ -35,39 +51,47 @@ pub const SCREEN_Y: usize = 144;
 ///
 /// # Arguments
 /// * `path` - a path for the ROM to load
-fn read_rom<P: AsRef<Path>>(path: P) -> Vec<u8> {
+fn read_rom<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<u8>> {
     // read file
-    let mut file = File::open(path).unwrap();
+    let mut file = File::open(path)?;
 
     // create a new vector
     let mut file_buffer = Vec::new();
 
     // get file buffer
-    file.read_to_end(&mut file_buffer).unwrap();
+    file.read_to_end(&mut file_buffer)?;
 
     // return buffer
-    file_buffer
+    Ok(file_buffer)
 }
 
-pub fn create_emulator(rom_name: &str) -> Machine {
+/// Get the save file path of a ROM, `<rom>.sav`
+pub fn save_file_path(rom_name: &str) -> PathBuf {
+    Path::new(rom_name).with_extension("sav")
+}
+
+/// Get the path of a save state slot of a ROM, `<rom>.ss<slot>`
+pub fn state_file_path(rom_name: &str, slot: u8) -> PathBuf {
+    Path::new(rom_name).with_extension(format!("ss{}", slot))
+}
+
+pub fn create_emulator(rom_name: &str, player: Box<AudioPlayer>, boot: Boot) -> CartridgeResult<Machine> {
     // Get rom buffer and create a new cartridge
-    let rom_buf = read_rom(rom_name);
-    let cartridge = Cartridge::new(rom_buf);
+    let rom_buf = read_rom(rom_name)?;
+    let cartridge = Cartridge::new(rom_buf)?;
+
+    // Cartridges with a bad header checksum only fail to start when the
+    // boot ROM runs, the built-in one is skipped on color cartridges
+    let runs_bootrom = match boot {
+        Boot::BootRom(_) => true,
+        Boot::Internal => !cartridge.is_gbc(),
+        Boot::Skip => false,
+    };
+
+    if runs_bootrom {
+        cartridge.check_header_checksum()?;
+    }
 
     // Create a new machine
-    Machine::new(cartridge)
-//
-//    if matches.is_present("debug") {
-//        let mut debugger = debugger::Debugger::new(machine);
-//        debugger.run();
-//    } else {
-//        loop {
-//            machine.emulate();
-//        }
-    // Start the controller
-//        match Controller::new(machine) {
-//            Ok(controller) => controller.main(),
-//            Err(e) => println!("{:?}", e)
-//        };
-//    }
+    Ok(Machine::new(cartridge, player, boot))
 }
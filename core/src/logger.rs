@@ -0,0 +1,38 @@
+//! Logger printing the emulator messages on stderr, for the binaries.
+
+use log::{self, LevelFilter, Log, Metadata, Record};
+use std::env;
+
+/// Level used when `RUST_LOG` isn't set, the faults are logged as warnings
+const DEFAULT_LEVEL: LevelFilter = LevelFilter::Warn;
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("{}: {}", record.level(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Print the log messages on stderr, from the level named by the
+/// `RUST_LOG` environment variable or from warnings by default
+pub fn init_logger() {
+    let level = env::var("RUST_LOG").ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(DEFAULT_LEVEL);
+
+    // a logger is already installed when called twice
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
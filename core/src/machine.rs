@@ -1,35 +1,495 @@
-use crate::cartridge::Cartridge;
+use crate::cartridge::{Cartridge, CartridgeHeader};
 use crate::cpu::Cpu;
 use crate::gpu::Gpu;
 use crate::gpu::types;
 use crate::io::Interconnect;
+use crate::io::bootrom::{self, Boot};
+use crate::io::fault::{Fault, FaultPolicy};
+use crate::io::serial::SerialLink;
+use std::fs;
+use std::io;
+use std::path::Path;
+use crate::rewind::Rewind;
+use crate::sound::AudioPlayer;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
+use crate::types::GbKey;
 
 pub struct Machine {
     pub cpu: Cpu,
-    pub interconnect: Interconnect
+    pub interconnect: Interconnect,
+    /// Fault that stopped the emulation with `FaultPolicy::Error`
+    error: Option<Fault>,
+    /// Dots elapsed on the current frame
+    frame_ticks: u32,
+    /// Frames completed since the machine was created
+    frames: u64,
+    /// Captured frames, when rewinding is enabled
+    rewind: Option<Rewind>,
 }
 
+/// Dots in a frame: 154 lines of 456 dots, a frame lasts 1/59.73 second
+pub const TICKS_PER_FRAME: u32 = 70224;
+
 /// Manage the GameBoy as a whole.
 impl Machine {
-    pub fn new(cartridge: Cartridge) -> Self {
-        let interconnect = Interconnect::new(cartridge, Gpu::new());
-        let cpu = Cpu::new();
+    /// Create a new machine for the given cartridge, the audio is sent
+    /// to `player` and `boot` selects the boot ROM
+    pub fn new(cartridge: Cartridge, player: Box<AudioPlayer>, boot: Boot) -> Self {
+        // Cartridges with color support run on color mode
+        let (mut cpu, gpu) = if cartridge.is_gbc() {
+            (Cpu::new_gbc(), Gpu::new_gbc())
+        } else {
+            (Cpu::new(), Gpu::new())
+        };
+
+        let boot = match boot {
+            Boot::Internal if cartridge.is_gbc() => {
+                warn!("No built-in Game Boy Color boot ROM, skipping the boot");
+                Boot::Skip
+            }
+            Boot::Internal => Boot::BootRom(bootrom::BOOTROM.to_vec()),
+            boot => boot,
+        };
+
+        let mut interconnect = Interconnect::new(cartridge, gpu, player);
+
+        match boot {
+            Boot::BootRom(data) => {
+                cpu.power_on();
+                interconnect.map_bootrom(data);
+            }
+            _ => interconnect.skip_boot(),
+        }
 
         Machine {
             cpu,
-            interconnect
+            interconnect,
+            error: None,
+            frame_ticks: 0,
+            frames: 0,
+            rewind: None,
+        }
+    }
+
+    /// Execute the next instruction.
+    ///
+    /// Returns the fault raised by the instruction with the `Pause` and
+    /// `Error` fault policies. After a pause the emulation can be resumed
+    /// by calling `emulate` again, after an error it stays stopped.
+    pub fn emulate(&mut self) -> Result<(), Fault> {
+        self.step().map(|_| ())
+    }
+
+    /// Run until the end of the current frame, when the LCD enters the
+    /// VBlank period. On success the frame is ready on `screen_buffer`.
+    ///
+    /// The faults are reported like on `emulate`, after a pause the rest
+    /// of the frame is run by calling `run_frame` again.
+    pub fn run_frame(&mut self) -> Result<(), Fault> {
+        loop {
+            let (_, frame_ready) = self.step()?;
+
+            if frame_ready {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Run for at least `ticks` dots, the machine stops on the first
+    /// instruction boundary after them. Returns `true` if a frame was
+    /// completed meanwhile.
+    ///
+    /// The faults are reported like on `emulate`.
+    pub fn run_cycles(&mut self, ticks: u32) -> Result<bool, Fault> {
+        let mut elapsed = 0;
+        let mut frame_ready = false;
+
+        while elapsed < ticks {
+            let (step_ticks, step_frame_ready) = self.step()?;
+
+            elapsed += step_ticks;
+            frame_ready |= step_frame_ready;
         }
+
+        Ok(frame_ready)
     }
 
-    pub fn emulate(&mut self) {
+    /// Execute the next instruction, returning the dots it took and if
+    /// it completed a frame
+    fn step(&mut self) -> Result<(u32, bool), Fault> {
+        if let Some(fault) = self.error {
+            return Err(fault);
+        }
+
         // Process the next CPU instruction
         let cycles = self.cpu.next_trick(&mut self.interconnect);
 
         // Do the interconnect cycle
-        self.interconnect.do_cycle(cycles);
+        let ticks = self.interconnect.do_cycle(cycles);
+
+        // Frames end on the VBlank, while the LCD is off they keep the
+        // same length so the embedders can still pace the emulation
+        self.frame_ticks += ticks;
+        let vblank = self.interconnect.gpu.take_vblank();
+        let frame_ready = vblank
+            || (!self.interconnect.gpu.is_lcd_on() && self.frame_ticks >= TICKS_PER_FRAME);
+
+        if frame_ready {
+            self.frame_ticks = 0;
+            self.frames += 1;
+            self.end_frame();
+        }
+
+        match self.interconnect.faults.take() {
+            None => Ok((ticks, frame_ready)),
+            Some(fault) => {
+                if self.fault_policy() == FaultPolicy::Error {
+                    self.error = Some(fault);
+                }
+
+                Err(fault)
+            }
+        }
+    }
+
+    /// Get the number of frames completed since the machine was created,
+    /// including the ones ended by a faulting instruction. Loading a state
+    /// or rewinding doesn't change it.
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Called at the end of every frame
+    fn end_frame(&mut self) {
+        if let Some(ref mut rewind) = self.rewind {
+            rewind.push(write_state(&self.cpu, &self.interconnect));
+        }
+    }
+
+    /// Enable rewinding, keeping as many frames as fit in `budget` bytes,
+    /// or disable it with `None`
+    pub fn set_rewind_budget(&mut self, budget: Option<usize>) {
+        match budget {
+            None => self.rewind = None,
+            Some(budget) => match self.rewind {
+                Some(ref mut rewind) => rewind.set_budget(budget),
+                None => self.rewind = Some(Rewind::new(budget)),
+            },
+        }
+    }
+
+    /// Go back `frames` frames, or as far as possible. Returns the number
+    /// of frames rewound. A snapshot that can't be restored empties the
+    /// rewind buffer and leaves the machine as it was.
+    pub fn rewind(&mut self, frames: usize) -> usize {
+        let (frames, state) = {
+            let rewind = match self.rewind {
+                Some(ref mut rewind) => rewind,
+                None => return 0,
+            };
+
+            let frames = frames.min(rewind.len().saturating_sub(1));
+            if frames == 0 {
+                return 0;
+            }
+
+            (frames, rewind.rewind(frames))
+        };
+
+        let result = match state {
+            Some(state) => self.load_state(&state).map_err(|e| e.to_string()),
+            None => Err("Unable to decode the rewind snapshot".to_string()),
+        };
+
+        if let Err(e) = result {
+            error!("Rewind failed, dropping the rewind buffer: {}", e);
+
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.clear();
+            }
+
+            return 0;
+        }
+
+        self.frame_ticks = 0;
+        frames
+    }
+
+    /// Returns `true` if the audio player ran out of audio to play, the
+    /// emulation is running slower than the audio output. Always `false`
+    /// while the audio is muted.
+    pub fn audio_underflowed(&self) -> bool {
+        self.interconnect.audio_underflowed()
+    }
+
+    /// Resample the audio for an emulation running `speed` times faster
+    /// than the Game Boy, 0.5 for half speed. The audio pitch follows the
+    /// speed but the player gets the samples at its own rate.
+    pub fn set_audio_speed(&mut self, speed: f64) {
+        self.interconnect.set_audio_speed(speed);
+    }
+
+    /// Stop or resume sending the audio to the player, to run the
+    /// emulation unthrottled or frame by frame without crackling
+    pub fn set_audio_muted(&mut self, muted: bool) {
+        self.interconnect.set_audio_muted(muted);
+    }
+
+    /// Get what is done on invalid memory accesses
+    pub fn fault_policy(&self) -> FaultPolicy {
+        self.interconnect.faults.policy()
+    }
+
+    /// Set what is done on invalid memory accesses
+    pub fn set_fault_policy(&mut self, policy: FaultPolicy) {
+        self.interconnect.faults.set_policy(policy);
+    }
+
+    /// Plug a device on the link cable port
+    pub fn set_serial_link(&mut self, link: Box<SerialLink>) {
+        self.interconnect.serial.set_link(link);
+    }
+
+    /// Take the error that disconnected the link cable device, if any.
+    /// After an error the port behaves as if the cable was unplugged.
+    pub fn take_serial_link_error(&mut self) -> Option<io::Error> {
+        self.interconnect.serial.take_link_error()
+    }
+
+    /// Press a key on the joypad
+    pub fn key_down(&mut self, key: GbKey) {
+        self.interconnect.joypad.key_down(key);
+    }
+
+    /// Release a key on the joypad
+    pub fn key_up(&mut self, key: GbKey) {
+        self.interconnect.joypad.key_up(key);
+    }
+
+    /// Take the state of the cartridge rumble motor if it changed since
+    /// the last call, `true` when it's on
+    pub fn take_rumble_event(&mut self) -> Option<bool> {
+        self.interconnect.cartridge.take_rumble_event()
+    }
+
+    /// Get the battery backed data of the cartridge: the RAM followed by
+    /// the clock state on cartridges with a clock. Returns `None` if the
+    /// cartridge has no battery.
+    pub fn save_ram(&mut self) -> Option<Vec<u8>> {
+        if !self.interconnect.cartridge.has_battery() {
+            return None;
+        }
+
+        Some(self.interconnect.cartridge.save_data())
+    }
+
+    /// Restore the battery backed data of the cartridge
+    pub fn load_save_ram(&mut self, data: &[u8]) {
+        self.interconnect.cartridge.load_save_data(data);
+    }
+
+    /// Returns `true` when the battery backed data changed and the game
+    /// stopped writing it for a while, embedders should save it then
+    pub fn save_ram_pending(&self) -> bool {
+        self.interconnect.cartridge.has_battery() && self.interconnect.cartridge.save_data_pending()
+    }
+
+    /// Load the battery backed data from a save file, a missing file is
+    /// not an error
+    pub fn load_save_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        if !self.interconnect.cartridge.has_battery() {
+            return Ok(());
+        }
+
+        match fs::read(path) {
+            Ok(data) => {
+                self.load_save_ram(&data);
+                Ok(())
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Write the battery backed data to a save file, if the cartridge has
+    /// a battery. The data is first written to a temporary file so a
+    /// crash never leaves a truncated save.
+    pub fn write_save_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = match self.save_ram() {
+            Some(data) => data,
+            None => return Ok(()),
+        };
+
+        let path = path.as_ref();
+        let temporary = path.with_extension("sav.tmp");
+
+        fs::write(&temporary, &data)?;
+        fs::rename(&temporary, path)
+    }
+
+    /// Take a snapshot of the whole machine. The devices plugged into it,
+    /// like the audio player or the link cable, aren't part of it.
+    pub fn save_state(&self) -> Vec<u8> {
+        write_state(&self.cpu, &self.interconnect)
+    }
+
+    /// Restore a snapshot taken with `save_state` on the same ROM. On
+    /// error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> StateResult<()> {
+        let mut reader = StateReader::new(data, self.interconnect.cartridge.rom_checksum())?;
+        let backup = self.save_state();
+
+        let result = self.cpu.load_state(&mut reader)
+            .and_then(|_| self.interconnect.load_state(&mut reader))
+            .and_then(|_| reader.finish());
+
+        match result {
+            Ok(()) => {
+                self.error = None;
+                Ok(())
+            }
+            Err(e) => {
+                let mut reader = StateReader::new(&backup, self.interconnect.cartridge.rom_checksum())
+                    .expect("Invalid backup state");
+                self.cpu.load_state(&mut reader)
+                    .and_then(|_| self.interconnect.load_state(&mut reader))
+                    .expect("Unable to restore the backup state");
+
+                Err(e)
+            }
+        }
+    }
+
+    /// Write a snapshot of the machine to a file
+    pub fn write_state_file<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.save_state())
+    }
+
+    /// Restore a snapshot of the machine from a file
+    pub fn load_state_file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let data = fs::read(path)?;
+
+        self.load_state(&data)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Get the header of the inserted cartridge
+    pub fn cartridge_header(&self) -> &CartridgeHeader {
+        self.interconnect.cartridge.header()
     }
 
     pub fn screen_buffer(&self) -> &types::ScreenBuffer {
         &self.interconnect.screen_buffer()
     }
 }
+
+/// Take a snapshot of the machine made of `cpu` and `interconnect`, split
+/// from `Machine::save_state` to leave the rest of the machine borrowable
+fn write_state(cpu: &Cpu, interconnect: &Interconnect) -> Vec<u8> {
+    let mut writer = StateWriter::new(interconnect.cartridge.rom_checksum());
+
+    cpu.save_state(&mut writer);
+    interconnect.save_state(&mut writer);
+
+    writer.into_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sound::NullPlayer;
+    use crate::state::StateError;
+
+    /// Build a machine running a ROM that keeps incrementing a byte of
+    /// the work RAM, on a MBC1 cartridge with battery backed RAM
+    fn machine() -> Machine {
+        let mut rom = vec![0; 0x8000];
+        // entry point: JP 0x0150
+        rom[0x100..0x103].copy_from_slice(&[0xc3, 0x50, 0x01]);
+        // MBC1 with RAM and battery, 32KB of ROM, 8KB of RAM
+        rom[0x147] = 0x03;
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x02;
+        // INC A, LD (0xc000) A, JP 0x0150
+        rom[0x150..0x157].copy_from_slice(&[0x3c, 0xea, 0x00, 0xc0, 0xc3, 0x50, 0x01]);
+
+        let cartridge = Cartridge::new(rom).unwrap();
+        Machine::new(cartridge, Box::new(NullPlayer::new()), Boot::Skip)
+    }
+
+    fn run_frames(machine: &mut Machine, frames: usize) {
+        for _ in 0..frames {
+            machine.run_frame().unwrap();
+        }
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut machine = machine();
+        run_frames(&mut machine, 5);
+
+        let state = machine.save_state();
+        run_frames(&mut machine, 5);
+        let expected = machine.save_state();
+
+        machine.load_state(&state).unwrap();
+        assert_eq!(machine.save_state(), state);
+
+        run_frames(&mut machine, 5);
+        assert_eq!(machine.save_state(), expected);
+    }
+
+    #[test]
+    fn invalid_state_leaves_the_machine_unchanged() {
+        let mut machine = machine();
+        run_frames(&mut machine, 5);
+        let state = machine.save_state();
+        run_frames(&mut machine, 5);
+        let current = machine.save_state();
+
+        assert_eq!(machine.load_state(&state[..state.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(machine.save_state(), current);
+
+        let mut other_rom = machine.save_state();
+        other_rom[6] ^= 0xff;
+        assert!(match machine.load_state(&other_rom) {
+            Err(StateError::RomMismatch { .. }) => true,
+            _ => false,
+        });
+        assert_eq!(machine.save_state(), current);
+    }
+
+    #[test]
+    fn only_a_different_ram_marks_the_save_file() {
+        let mut machine = machine();
+        // enable the cartridge RAM
+        machine.interconnect.write_byte(0x0000, 0x0a);
+        machine.interconnect.write_byte(0xa000, 1);
+        machine.save_ram();
+        let state = machine.save_state();
+
+        machine.load_state(&state).unwrap();
+        assert!(!machine.save_ram_pending());
+
+        machine.interconnect.write_byte(0xa000, 2);
+        machine.save_ram();
+        machine.load_state(&state).unwrap();
+        assert!(machine.save_ram_pending());
+    }
+
+    #[test]
+    fn rewinds_to_the_captured_frames() {
+        let mut machine = machine();
+        machine.set_rewind_budget(Some(1024 * 1024));
+
+        run_frames(&mut machine, 10);
+        let state = machine.save_state();
+        run_frames(&mut machine, 3);
+
+        assert_eq!(machine.rewind(3), 3);
+        assert_eq!(machine.save_state(), state);
+
+        assert_eq!(machine.rewind(100), 9);
+        assert_eq!(machine.rewind(1), 0);
+    }
+}
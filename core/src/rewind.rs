@@ -0,0 +1,320 @@
+//! Rewind buffer.
+//!
+//! The machine state is captured at the end of every frame. Most frames
+//! only change a few bytes of the state, so instead of full snapshots the
+//! buffer keeps a keyframe every `KEYFRAME_INTERVAL` frames and, for the
+//! frames in between, the XOR of their state with the keyframe. Both are
+//! compressed by collapsing the runs of zero bytes, which is what the
+//! deltas are mostly made of. The oldest frames are dropped, a keyframe
+//! and its deltas at a time, to stay within the memory budget.
+
+use std::collections::VecDeque;
+
+/// Frames between two keyframes
+const KEYFRAME_INTERVAL: usize = 60;
+
+/// Shortest run of zeros worth ending a literal block for
+const MIN_ZERO_RUN: usize = 4;
+
+/// Captured frame
+struct Snapshot {
+    /// `true` for a full state, `false` for a delta against the previous
+    /// keyframe
+    keyframe: bool,
+    /// Compressed state or delta
+    data: Vec<u8>,
+}
+
+pub struct Rewind {
+    /// Maximum size of the compressed snapshots, in bytes
+    budget: usize,
+    /// Size of the compressed snapshots, in bytes
+    used: usize,
+    /// Captured frames, the most recent last
+    snapshots: VecDeque<Snapshot>,
+    /// Uncompressed state of the last keyframe, the next deltas are
+    /// computed against it
+    keyframe: Vec<u8>,
+    /// Deltas captured since the last keyframe
+    deltas: usize,
+}
+
+impl Rewind {
+    /// Create an empty rewind buffer using up to `budget` bytes
+    pub fn new(budget: usize) -> Rewind {
+        Rewind {
+            budget,
+            used: 0,
+            snapshots: VecDeque::new(),
+            keyframe: Vec::new(),
+            deltas: 0,
+        }
+    }
+
+    /// Get the number of frames that can be rewound
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    /// Change the memory budget, dropping the oldest frames if needed
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget = budget;
+        self.shrink();
+    }
+
+    /// Capture the state of a new frame
+    pub fn push(&mut self, state: Vec<u8>) {
+        // the state size changes when the boot ROM is unmapped
+        let keyframe = self.snapshots.is_empty()
+            || self.deltas + 1 >= KEYFRAME_INTERVAL
+            || state.len() != self.keyframe.len();
+
+        let data = if keyframe {
+            let data = compress(&state);
+            self.keyframe = state;
+            self.deltas = 0;
+            data
+        } else {
+            self.deltas += 1;
+            compress(&xor(&state, &self.keyframe))
+        };
+
+        self.used += data.len();
+        self.snapshots.push_back(Snapshot { keyframe, data });
+        self.shrink();
+    }
+
+    /// Drop the `frames` most recent frames and return the state of the
+    /// frame before them, which stays in the buffer. Returns `None` when
+    /// there aren't enough frames or the snapshot can't be decoded.
+    pub fn rewind(&mut self, frames: usize) -> Option<Vec<u8>> {
+        if frames >= self.snapshots.len() {
+            return None;
+        }
+
+        for _ in 0..frames {
+            let snapshot = self.snapshots.pop_back().unwrap();
+            self.used -= snapshot.data.len();
+        }
+
+        // find the keyframe of the frame to restore
+        let current = self.snapshots.len() - 1;
+        let keyframe = (0..=current).rev().find(|&index| self.snapshots[index].keyframe)?;
+
+        self.keyframe = decompress(&self.snapshots[keyframe].data)?;
+        self.deltas = current - keyframe;
+
+        if keyframe == current {
+            return Some(self.keyframe.clone());
+        }
+
+        let delta = decompress(&self.snapshots[current].data)?;
+        if delta.len() != self.keyframe.len() {
+            return None;
+        }
+
+        Some(xor(&delta, &self.keyframe))
+    }
+
+    /// Drop every captured frame
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.keyframe.clear();
+        self.used = 0;
+        self.deltas = 0;
+    }
+
+    /// Drop the oldest keyframes and their deltas until the buffer fits in
+    /// the budget, the most recent keyframe is always kept
+    fn shrink(&mut self) {
+        while self.used > self.budget {
+            let next_keyframe = self.snapshots.iter()
+                .skip(1)
+                .position(|snapshot| snapshot.keyframe);
+
+            let count = match next_keyframe {
+                Some(position) => position + 1,
+                None => break,
+            };
+
+            for snapshot in self.snapshots.drain(..count) {
+                self.used -= snapshot.data.len();
+            }
+        }
+    }
+}
+
+/// XOR two buffers of the same size
+fn xor(data: &[u8], reference: &[u8]) -> Vec<u8> {
+    data.iter().zip(reference.iter()).map(|(a, b)| a ^ b).collect()
+}
+
+/// Write a variable length integer, 7 bits per byte
+fn write_varint(output: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        output.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+
+    output.push(value as u8);
+}
+
+/// Read a variable length integer, advancing `position`. Returns `None`
+/// if the input ends first or the integer is too long.
+fn read_varint(input: &[u8], position: &mut usize) -> Option<usize> {
+    let mut value = 0usize;
+    let mut shift = 0;
+
+    loop {
+        let byte = *input.get(*position)?;
+        *position += 1;
+
+        if shift >= usize::BITS {
+            return None;
+        }
+
+        value |= ((byte & 0x7f) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+}
+
+/// Compress `data` as a sequence of blocks, each one holding the length of
+/// a run of zeros followed by the length and the bytes of a literal run
+fn compress(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::new();
+    let mut position = 0;
+
+    while position < data.len() {
+        let zeros = data[position..].iter().take_while(|&&byte| byte == 0).count();
+        position += zeros;
+
+        // the literal run ends on the next run of zeros long enough
+        let start = position;
+        while position < data.len() {
+            let run = data[position..].iter()
+                .take(MIN_ZERO_RUN)
+                .take_while(|&&byte| byte == 0)
+                .count();
+
+            if run == MIN_ZERO_RUN || position + run == data.len() {
+                break;
+            }
+
+            position += run + 1;
+        }
+
+        write_varint(&mut output, zeros);
+        write_varint(&mut output, position - start);
+        output.extend_from_slice(&data[start..position]);
+    }
+
+    output
+}
+
+/// Restore the data compressed with `compress`, returns `None` if the
+/// input is corrupted
+fn decompress(input: &[u8]) -> Option<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut position = 0;
+
+    while position < input.len() {
+        let zeros = read_varint(input, &mut position)?;
+        output.resize(output.len().checked_add(zeros)?, 0);
+
+        let literals = read_varint(input, &mut position)?;
+        let end = position.checked_add(literals)?;
+        output.extend_from_slice(input.get(position..end)?);
+        position = end;
+    }
+
+    Some(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// State of the frame `frame`, a few bytes change on every frame
+    fn state(frame: usize) -> Vec<u8> {
+        let mut state = vec![0; 1024];
+        state[10] = frame as u8;
+        state[500] = (frame >> 8) as u8;
+        state[1000..1008].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        state
+    }
+
+    #[test]
+    fn compress_round_trip() {
+        let samples: Vec<Vec<u8>> = vec![
+            vec![],
+            vec![0; 300],
+            vec![0xff; 300],
+            vec![1, 0, 0, 0, 2, 0, 0, 0, 0, 3, 0],
+            (0..1000).map(|i| (i % 7) as u8).collect(),
+            state(1234),
+        ];
+
+        for data in samples {
+            assert_eq!(decompress(&compress(&data)), Some(data));
+        }
+
+        assert!(compress(&state(0)).len() < 32);
+    }
+
+    #[test]
+    fn decompress_rejects_corrupted_data() {
+        let data = compress(&[0, 0, 0, 0, 0, 1, 2, 3]);
+
+        assert_eq!(decompress(&data[..data.len() - 1]), None);
+        assert_eq!(decompress(&[0x80]), None);
+        assert_eq!(decompress(&[0xff; 16]), None);
+    }
+
+    #[test]
+    fn rewinds_keyframes_and_deltas() {
+        let mut rewind = Rewind::new(usize::MAX);
+        for frame in 0..150 {
+            rewind.push(state(frame));
+        }
+
+        assert_eq!(rewind.len(), 150);
+        assert!(rewind.snapshots[0].keyframe);
+        assert!(!rewind.snapshots[1].keyframe);
+        assert!(rewind.snapshots[KEYFRAME_INTERVAL].keyframe);
+
+        // a delta, then a keyframe
+        assert_eq!(rewind.rewind(1), Some(state(148)));
+        assert_eq!(rewind.rewind(148 - 2 * KEYFRAME_INTERVAL), Some(state(2 * KEYFRAME_INTERVAL)));
+
+        // the deltas pushed after a rewind follow the restored keyframe
+        rewind.push(state(500));
+        assert_eq!(rewind.rewind(0), Some(state(500)));
+        assert_eq!(rewind.rewind(1), Some(state(2 * KEYFRAME_INTERVAL)));
+
+        assert_eq!(rewind.rewind(rewind.len()), None);
+    }
+
+    #[test]
+    fn budget_drops_the_oldest_keyframes() {
+        let mut rewind = Rewind::new(usize::MAX);
+        for frame in 0..3 * KEYFRAME_INTERVAL {
+            rewind.push(state(frame));
+        }
+
+        let used = rewind.used;
+        rewind.set_budget(used - 1);
+
+        assert_eq!(rewind.len(), 2 * KEYFRAME_INTERVAL);
+        assert!(rewind.snapshots[0].keyframe);
+        assert_eq!(rewind.rewind(2 * KEYFRAME_INTERVAL - 1), Some(state(KEYFRAME_INTERVAL)));
+
+        // the most recent keyframe stays even without budget
+        rewind.set_budget(0);
+        assert_eq!(rewind.len(), 1);
+        assert_eq!(rewind.rewind(0), Some(state(KEYFRAME_INTERVAL)));
+    }
+}
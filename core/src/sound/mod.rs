@@ -1,17 +1,26 @@
 //! Game Boy sound emulation
 
 use self::blip_buf::BlipBuf;
+#[cfg(feature = "cpal")]
 pub use self::player::CpalPlayer;
+pub use self::null_player::NullPlayer;
+pub use self::wav_file_player::WavFilePlayer;
+pub use self::ring_buffer_player::{RingBufferPlayer, RingBufferConsumer};
 use self::wave_channel::WaveChannel;
 use self::noise_channel::NoiseChannel;
 use self::square_channel::SquareChannel;
 use self::volume_envelope::VolumeEnvelope;
 
 use crate::io::io_map;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 
 extern crate blip_buf;
 
+#[cfg(feature = "cpal")]
 mod player;
+mod null_player;
+mod wav_file_player;
+mod ring_buffer_player;
 mod wave_channel;
 mod noise_channel;
 mod square_channel;
@@ -22,7 +31,12 @@ mod volume_envelope;
 const WAVE_PATTERN: [[i32; 8]; 4] = [[-1, -1, -1, -1, 1, -1, -1, -1], [-1, -1, -1, -1, 1, 1, -1, -1], [-1, -1, 1, 1, 1, 1, -1, -1], [1, 1, 1, 1, -1, -1, 1, 1]];
 const CLOCKS_PER_SECOND: u32 = 1 << 22;
 const OUTPUT_SAMPLE_COUNT: usize = 2000;
+/// The frame sequencer clocks the length counters, sweep and volume
+/// envelopes at 512 Hz
+const FRAME_SEQUENCER_PERIOD: u32 = CLOCKS_PER_SECOND / 512;
 
+/// Output for the generated audio. The emulator doesn't require a sound
+/// device, frontends pick the player they want when creating the machine.
 pub trait AudioPlayer: Send {
     fn play(&mut self, left_channel: &[f32], right_channel: &[f32]);
     fn samples_rate(&self) -> u32;
@@ -36,6 +50,10 @@ fn create_blipbuf(samples_rate: u32) -> BlipBuf {
     blipbuf
 }
 
+/// Slowest emulation speed the audio can be resampled for, the blip
+/// buffers hold a second of audio
+const MIN_SPEED: f64 = 0.125;
+
 /// Sound processing unit state
 pub struct Sound {
     /// True if the sound circuit is enabled
@@ -56,6 +74,16 @@ pub struct Sound {
     volume_left: u8,
     /// Right volume
     volume_right: u8,
+    /// Clock ticks elapsed on the current blip frame
+    time: u32,
+    /// Time up to which the channels generated their output
+    prev_time: u32,
+    /// Time of the next frame sequencer step
+    next_time: u32,
+    /// Frame sequencer step, from 0 to 7
+    frame_step: u8,
+    /// When `true` the audio is generated but not sent to the player
+    muted: bool,
 }
 
 impl Sound {
@@ -77,9 +105,168 @@ impl Sound {
             player,
             volume_left: 7,
             volume_right: 7,
+            time: 0,
+            prev_time: 0,
+            next_time: FRAME_SEQUENCER_PERIOD,
+            frame_step: 0,
+            muted: false,
+        }
+    }
+
+    /// Resample the audio for an emulation running `speed` times faster
+    /// than the Game Boy, so the player gets as many samples as it plays
+    pub fn set_speed(&mut self, speed: f64) {
+        let clock_rate = CLOCKS_PER_SECOND as f64 * speed.max(MIN_SPEED);
+        let samples_rate = self.player.samples_rate() as f64;
+
+        self.channel1.blip.set_rates(clock_rate, samples_rate);
+        self.channel2.blip.set_rates(clock_rate, samples_rate);
+        self.channel3.blip.set_rates(clock_rate, samples_rate);
+        self.channel4.blip.set_rates(clock_rate, samples_rate);
+    }
+
+    /// Stop or resume sending the audio to the player
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+
+    /// Advance the sound unit by the given number of clock ticks
+    pub fn do_cycle(&mut self, ticks: u32) {
+        self.time += ticks;
+
+        if self.time < self.next_time { return; }
+
+        // the channel state only changes on the frame sequencer steps (or
+        // on register writes), so they can generate their output up to
+        // each step in one go
+        while self.time >= self.next_time {
+            self.run_channels(self.prev_time, self.next_time);
+            self.prev_time = self.next_time;
+            self.next_time += FRAME_SEQUENCER_PERIOD;
+
+            // while the sound unit is disabled the channels are off and
+            // the frame sequencer stopped, but the player keeps getting
+            // the silence
+            if self.enabled {
+                self.step_frame_sequencer();
+            }
+        }
+
+        // close the blip frame and restart the time from there
+        self.end_frame(self.prev_time);
+        self.time -= self.prev_time;
+        self.next_time -= self.prev_time;
+        self.prev_time = 0;
+
+        if self.channel1.blip.samples_avail() as usize >= OUTPUT_SAMPLE_COUNT {
+            self.mix_buffers();
+        }
+    }
+
+    /// Generate the output of every channel between the given times
+    fn run_channels(&mut self, start_time: u32, end_time: u32) {
+        self.channel1.run(start_time, end_time);
+        self.channel2.run(start_time, end_time);
+        self.channel3.run(start_time, end_time);
+        self.channel4.run(start_time, end_time);
+    }
+
+    /// Clear NR10-NR51 and turn the channels off, when the sound unit is
+    /// disabled
+    fn power_off(&mut self) {
+        for address in 0x10..0x26 {
+            self.write_byte(address, 0);
+        }
+
+        self.channel1.disable();
+        self.channel2.disable();
+        self.channel3.disable();
+        self.channel4.disable();
+    }
+
+    /// Frame sequencer step: length counters on even steps, sweep on
+    /// steps 2 and 6 and volume envelopes on step 7
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step % 2 == 0 {
+            self.channel1.step_length();
+            self.channel2.step_length();
+            self.channel3.step_length();
+            self.channel4.step_length();
+        }
+
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.channel1.step_sweep();
+        }
+
+        if self.frame_step == 7 {
+            self.channel1.step_envelope();
+            self.channel2.step_envelope();
+            self.channel4.step_envelope();
+        }
+
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    /// Close the current frame of every blip buffer
+    fn end_frame(&mut self, time: u32) {
+        self.channel1.blip.end_frame(time);
+        self.channel2.blip.end_frame(time);
+        self.channel3.blip.end_frame(time);
+        self.channel4.blip.end_frame(time);
+    }
+
+    /// Mix the channels into stereo chunks of `OUTPUT_SAMPLE_COUNT`
+    /// samples, following the NR50 volume and NR51 panning, and send
+    /// them to the player
+    fn mix_buffers(&mut self) {
+        // volumes go up to 7 and channel amplitudes up to 15, leave some
+        // headroom for the four channels
+        let left_volume = (self.volume_left as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let right_volume = (self.volume_right as f32 / 7.0) * (1.0 / 15.0) * 0.25;
+        let panning = self.registerdata[(io_map::NR51 - io_map::NR10) as usize];
+
+        let mut buf = [0i16; OUTPUT_SAMPLE_COUNT];
+
+        while self.channel1.blip.samples_avail() as usize >= OUTPUT_SAMPLE_COUNT {
+            let mut buf_left = [0f32; OUTPUT_SAMPLE_COUNT];
+            let mut buf_right = [0f32; OUTPUT_SAMPLE_COUNT];
+
+            for channel in 0..4 {
+                let count = match channel {
+                    0 => self.channel1.blip.read_samples(&mut buf, false),
+                    1 => self.channel2.blip.read_samples(&mut buf, false),
+                    2 => self.channel3.blip.read_samples(&mut buf, false),
+                    _ => self.channel4.blip.read_samples(&mut buf, false),
+                };
+
+                // NR51 low nibble routes the channels to the right
+                // output and the high nibble to the left one
+                let to_right = panning & (1 << channel) != 0;
+                let to_left = panning & (0x10 << channel) != 0;
+
+                for (i, sample) in buf[..count].iter().enumerate() {
+                    if to_left {
+                        buf_left[i] += *sample as f32 * left_volume;
+                    }
+
+                    if to_right {
+                        buf_right[i] += *sample as f32 * right_volume;
+                    }
+                }
+            }
+
+            if !self.muted {
+                self.player.play(&buf_left, &buf_right);
+            }
         }
     }
 
+    /// Returns `true` if the player ran out of audio to play. A muted
+    /// player isn't fed, so it never counts as underflowed.
+    pub fn underflowed(&self) -> bool {
+        !self.muted && self.player.underflowed()
+    }
+
     pub fn read_byte(&self, address: u16) -> u8 {
         match address {
             // data registers
@@ -96,8 +283,8 @@ impl Sound {
 
             // wave RAM
             io_map::NR3_RAM_START ... io_map::NR3_RAM_END => {
-                (self.channel3.waveram[(address as usize - 0x30) / 2] << 4) |
-                    self.channel3.waveram[(address as usize - 0x30) / 2 + 1]
+                (self.channel3.waveram[(address as usize - 0x30) * 2] << 4) |
+                    self.channel3.waveram[(address as usize - 0x30) * 2 + 1]
             },
 
             _ => 0,
@@ -106,8 +293,15 @@ impl Sound {
 
     /// write a value to the sound memory space
     pub fn write_byte(&mut self, address: u16, value: u8) {
-        // The sound unity must be enabled to use all the address space
-        if address != 0x26 && !self.enabled { return; }
+        // The sound unity must be enabled to use all the address space,
+        // except for the wave RAM
+        let wave_ram = address >= io_map::NR3_RAM_START && address <= io_map::NR3_RAM_END;
+        if address != 0x26 && !wave_ram && !self.enabled { return; }
+
+        // bring the channels up to the current time, so the write only
+        // changes the output from now on
+        self.run_channels(self.prev_time, self.time);
+        self.prev_time = self.time;
 
         // registers address space
         if address >= 0x10 && address <= 0x26 {
@@ -123,16 +317,242 @@ impl Sound {
             0x1a ... 0x1e => self.channel3.write_byte(address, value),
             // Channel 4 address space
             0x20 ... 0x23 => self.channel4.write_byte(address, value),
-            // Set volume
+            // Set volume, bits [2:0] are the right output (SO1) and bits
+            // [6:4] the left one (SO2)
             0x24 => {
-                self.volume_left = value & 0x7;
-                self.volume_right = (value >> 4) & 0x7;
+                self.volume_right = value & 0x7;
+                self.volume_left = (value >> 4) & 0x7;
             }
             // Allow enable and disable the sound unity
-            0x26 => self.enabled = value & 0x80 == 0x80,
+            0x26 => {
+                let enabled = value & 0x80 == 0x80;
+
+                if self.enabled && !enabled {
+                    self.power_off();
+                } else if !self.enabled && enabled {
+                    // the frame sequencer restarts from its first step
+                    self.frame_step = 0;
+                }
+
+                self.enabled = enabled;
+            }
             // Channel 3 address space echo
             0x30 ... 0x3f => self.channel3.write_byte(address, value),
             _ => (),
         }
     }
 }
+
+/// The player isn't part of the state, the audio not played yet is
+/// dropped on load
+impl SaveState for Sound {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bytes(&self.registerdata);
+        self.channel1.save_state(writer);
+        self.channel2.save_state(writer);
+        self.channel3.save_state(writer);
+        self.channel4.save_state(writer);
+        writer.write_u8(self.volume_left);
+        writer.write_u8(self.volume_right);
+        writer.write_u32(self.time);
+        writer.write_u32(self.prev_time);
+        writer.write_u32(self.next_time);
+        writer.write_u8(self.frame_step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.enabled = reader.read_bool()?;
+        reader.read_into(&mut self.registerdata)?;
+        self.channel1.load_state(reader)?;
+        self.channel2.load_state(reader)?;
+        self.channel3.load_state(reader)?;
+        self.channel4.load_state(reader)?;
+        self.volume_left = reader.read_u8()? & 0x7;
+        self.volume_right = reader.read_u8()? & 0x7;
+        self.time = reader.read_u32()?;
+        self.prev_time = reader.read_u32()?;
+        self.next_time = reader.read_u32()?;
+        self.frame_step = reader.read_u8()? % 8;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    type Chunks = Arc<Mutex<Vec<(Vec<f32>, Vec<f32>)>>>;
+
+    /// Player keeping every chunk it gets
+    struct RecordingPlayer {
+        chunks: Chunks,
+    }
+
+    impl AudioPlayer for RecordingPlayer {
+        fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+            self.chunks.lock().unwrap().push((left_channel.to_vec(), right_channel.to_vec()));
+        }
+
+        fn samples_rate(&self) -> u32 {
+            44100
+        }
+
+        fn underflowed(&self) -> bool {
+            false
+        }
+    }
+
+    /// Create a powered on sound unit playing on `player`, with every
+    /// channel sent to both outputs at full volume
+    fn powered_on(player: Box<AudioPlayer>) -> Sound {
+        let mut sound = Sound::new(player);
+        sound.write_byte(io_map::NR52, 0x80);
+        sound.write_byte(io_map::NR50, 0x77);
+        sound.write_byte(io_map::NR51, 0xff);
+        sound
+    }
+
+    /// Start channel 2 at full volume, 4 KHz, with the given length and
+    /// envelope
+    fn start_channel2(sound: &mut Sound, length: u8, envelope: u8) {
+        sound.write_byte(0x16, 0x80 | (64 - length));
+        sound.write_byte(0x17, envelope);
+        sound.write_byte(0x18, 0x00);
+        sound.write_byte(0x19, 0xc7);
+    }
+
+    fn channel2_on(sound: &Sound) -> bool {
+        sound.read_byte(io_map::NR52) & 0x2 != 0
+    }
+
+    fn run_steps(sound: &mut Sound, steps: u32) {
+        for _ in 0..steps {
+            sound.do_cycle(FRAME_SEQUENCER_PERIOD);
+        }
+    }
+
+    #[test]
+    fn length_counter_disables_the_channel_at_256_hz() {
+        let mut sound = powered_on(Box::new(NullPlayer::new()));
+        start_channel2(&mut sound, 2, 0xf0);
+        assert!(channel2_on(&sound));
+
+        // the length is clocked on the steps 0 and 2
+        sound.do_cycle(FRAME_SEQUENCER_PERIOD - 1);
+        assert!(channel2_on(&sound));
+        sound.do_cycle(1);
+        assert!(channel2_on(&sound));
+        run_steps(&mut sound, 1);
+        assert!(channel2_on(&sound));
+        run_steps(&mut sound, 1);
+        assert!(!channel2_on(&sound));
+    }
+
+    #[test]
+    fn envelope_steps_at_64_hz() {
+        let mut sound = powered_on(Box::new(NullPlayer::new()));
+        start_channel2(&mut sound, 64, 0xf1);
+
+        run_steps(&mut sound, 7);
+        assert_eq!(sound.channel2.volume(), 15);
+        run_steps(&mut sound, 1);
+        assert_eq!(sound.channel2.volume(), 14);
+        run_steps(&mut sound, 7);
+        assert_eq!(sound.channel2.volume(), 14);
+        run_steps(&mut sound, 1);
+        assert_eq!(sound.channel2.volume(), 13);
+    }
+
+    #[test]
+    fn sweep_steps_at_128_hz() {
+        let mut sound = powered_on(Box::new(NullPlayer::new()));
+        // sweep every step, adding a half of the frequency
+        sound.write_byte(0x10, 0x11);
+        sound.write_byte(0x12, 0xf0);
+        sound.write_byte(0x13, 0x00);
+        sound.write_byte(0x14, 0x82);
+        assert_eq!(sound.channel1.frequency(), 0x200);
+
+        // the sweep is clocked on the steps 2 and 6
+        run_steps(&mut sound, 2);
+        assert_eq!(sound.channel1.frequency(), 0x200);
+        run_steps(&mut sound, 1);
+        assert_eq!(sound.channel1.frequency(), 0x300);
+        run_steps(&mut sound, 3);
+        assert_eq!(sound.channel1.frequency(), 0x300);
+        run_steps(&mut sound, 1);
+        assert_eq!(sound.channel1.frequency(), 0x480);
+    }
+
+    #[test]
+    fn power_off_clears_the_registers() {
+        let mut sound = powered_on(Box::new(NullPlayer::new()));
+        start_channel2(&mut sound, 64, 0xf0);
+        sound.write_byte(io_map::NR3_RAM_START, 0x12);
+
+        sound.write_byte(io_map::NR52, 0x00);
+        assert_eq!(sound.read_byte(io_map::NR52) & 0x8f, 0);
+        for address in io_map::NR10..io_map::NR52 {
+            assert_eq!(sound.read_byte(address), 0, "register {:#04x}", address);
+        }
+
+        // the registers ignore the writes until the unit is powered on,
+        // the wave RAM doesn't
+        sound.write_byte(io_map::NR50, 0x77);
+        assert_eq!(sound.read_byte(io_map::NR50), 0);
+        assert_eq!(sound.read_byte(io_map::NR3_RAM_START), 0x12);
+
+        sound.write_byte(io_map::NR52, 0x80);
+        sound.write_byte(io_map::NR50, 0x77);
+        assert_eq!(sound.read_byte(io_map::NR50), 0x77);
+    }
+
+    #[test]
+    fn nr50_sets_the_left_volume_on_the_high_bits() {
+        let chunks = Chunks::default();
+        let mut sound = powered_on(Box::new(RecordingPlayer { chunks: chunks.clone() }));
+        start_channel2(&mut sound, 64, 0xf0);
+
+        let silent = |samples: &[f32]| samples.iter().all(|&sample| sample == 0.0);
+
+        sound.write_byte(io_map::NR50, 0x70);
+        run_steps(&mut sound, 64);
+        {
+            let chunks = chunks.lock().unwrap();
+            assert!(!chunks.is_empty());
+            assert!(chunks.iter().all(|&(ref left, ref right)| !silent(left) && silent(right)));
+        }
+
+        chunks.lock().unwrap().clear();
+        sound.write_byte(io_map::NR50, 0x07);
+        run_steps(&mut sound, 64);
+
+        let chunks = chunks.lock().unwrap();
+        assert!(!chunks.is_empty());
+        assert!(chunks.iter().all(|&(ref left, ref right)| silent(left) && !silent(right)));
+    }
+
+    #[test]
+    fn players_get_stereo_chunks() {
+        let chunks = Chunks::default();
+        let mut sound = powered_on(Box::new(RecordingPlayer { chunks: chunks.clone() }));
+        // a quarter of second, the silence of a powered off unit included
+        sound.write_byte(io_map::NR52, 0x00);
+        run_steps(&mut sound, 128);
+
+        let chunks = chunks.lock().unwrap();
+        assert!(chunks.len() >= 2);
+        assert!(chunks.iter().all(|&(ref left, ref right)| {
+            left.len() == OUTPUT_SAMPLE_COUNT && right.len() == OUTPUT_SAMPLE_COUNT
+        }));
+
+        let (player, consumer) = RingBufferPlayer::new(44100, 44100);
+        let mut sound = powered_on(Box::new(player));
+        run_steps(&mut sound, 128);
+
+        assert!(!consumer.is_empty());
+        assert_eq!(consumer.len() % OUTPUT_SAMPLE_COUNT, 0);
+    }
+}
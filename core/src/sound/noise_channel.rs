@@ -1,5 +1,6 @@
 use self::blip_buf::BlipBuf;
 use super::volume_envelope::VolumeEnvelope;
+use crate::state::{SaveState, StateError, StateReader, StateResult, StateWriter};
 
 extern crate blip_buf;
 
@@ -14,7 +15,7 @@ pub struct NoiseChannel {
     state: u16,
     delay: u32,
     last_amp: i32,
-    blip: BlipBuf
+    pub blip: BlipBuf
 }
 
 impl NoiseChannel {
@@ -40,6 +41,68 @@ impl NoiseChannel {
         self.enabled
     }
 
+    /// Turn the channel off, when the sound unit is disabled
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
+    /// Generate the channel output between `start_time` and `end_time`
+    pub fn run(&mut self, start_time: u32, end_time: u32) {
+        let volume = self.volume_envelope.volume() as i32;
+
+        if !self.enabled || volume == 0 {
+            // silence the channel
+            if self.last_amp != 0 {
+                self.blip.add_delta(start_time, -self.last_amp);
+                self.last_amp = 0;
+                self.delay = 0;
+            }
+
+            return;
+        }
+
+        let mut time = start_time + self.delay;
+
+        while time < end_time {
+            // advance the linear feedback shift register
+            let old_state = self.state;
+            self.state <<= 1;
+            let bit = ((old_state >> self.shift_width) ^ (self.state >> self.shift_width)) & 1;
+            self.state |= bit;
+
+            let amp = match (old_state >> self.shift_width) & 1 {
+                0 => -volume,
+                _ => volume,
+            };
+
+            if amp != self.last_amp {
+                self.blip.add_delta(time, amp - self.last_amp);
+                self.last_amp = amp;
+            }
+
+            time += self.period;
+        }
+
+        // the next run starts where this one overshot
+        self.delay = time - end_time;
+    }
+
+    /// Length counter step, disables the channel when it expires
+    pub fn step_length(&mut self) {
+        if self.length_enabled && self.length != 0 {
+            self.length -= 1;
+
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Volume envelope step
+    pub fn step_envelope(&mut self) {
+        self.volume_envelope.step();
+    }
+
     /// write a byte on sound channel
     pub fn write_byte(&mut self, address: u16, value: u8) {
         match address {
@@ -54,6 +117,8 @@ impl NoiseChannel {
                 self.period = freq_div << (value >> 4);
             },
             0x23 => {
+                self.length_enabled = value & 0x40 == 0x40;
+
                 if value & 0x80 == 0x80 {
                     self.enabled = true;
                     self.length = self.new_length;
@@ -68,3 +133,45 @@ impl NoiseChannel {
         self.volume_envelope.write_byte(address, value);
     }
 }
+
+/// The blip buffer is cleared on load, the output restarts from silence
+impl SaveState for NoiseChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.length);
+        writer.write_u8(self.new_length);
+        writer.write_bool(self.length_enabled);
+        self.volume_envelope.save_state(writer);
+        writer.write_u32(self.period);
+        writer.write_u8(self.shift_width);
+        writer.write_u16(self.state);
+        writer.write_u32(self.delay);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.enabled = reader.read_bool()?;
+        self.length = reader.read_u8()?;
+        self.new_length = reader.read_u8()?;
+        self.length_enabled = reader.read_bool()?;
+        self.volume_envelope.load_state(reader)?;
+        let period = reader.read_u32()?;
+        if period == 0 {
+            return Err(StateError::InvalidValue("noise period"));
+        }
+
+        // the LFSR is 15 bits wide
+        let shift_width = reader.read_u8()?;
+        if shift_width > 15 {
+            return Err(StateError::InvalidValue("noise shift width"));
+        }
+
+        self.period = period;
+        self.shift_width = shift_width;
+        self.state = reader.read_u16()?;
+        self.delay = reader.read_u32()?;
+
+        self.last_amp = 0;
+        self.blip.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+use crate::sound::AudioPlayer;
+
+/// Sample rate reported by players that don't have a device to ask
+pub const DEFAULT_SAMPLES_RATE: u32 = 44100;
+
+/// Player that discards every sample, for machines without a sound card
+/// or when the audio is not needed
+pub struct NullPlayer {
+    samples_rate: u32,
+}
+
+impl NullPlayer {
+    /// create a new NullPlayer instance
+    pub fn new() -> NullPlayer {
+        NullPlayer {
+            samples_rate: DEFAULT_SAMPLES_RATE,
+        }
+    }
+}
+
+impl Default for NullPlayer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AudioPlayer for NullPlayer {
+    fn play(&mut self, _left_channel: &[f32], _right_channel: &[f32]) {}
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        false
+    }
+}
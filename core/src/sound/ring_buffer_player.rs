@@ -0,0 +1,107 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use crate::sound::AudioPlayer;
+
+/// Stereo frames shared between the player and its consumer
+struct RingBuffer {
+    frames: VecDeque<(f32, f32)>,
+    /// Maximum number of frames kept, the oldest ones are dropped first
+    capacity: usize,
+    /// Set when the consumer asked for more frames than available
+    underflowed: bool,
+}
+
+/// Player that keeps the audio in a bounded in-memory buffer, which a
+/// frontend drains through the paired `RingBufferConsumer` (e.g. from its
+/// own audio callback).
+pub struct RingBufferPlayer {
+    buffer: Arc<Mutex<RingBuffer>>,
+    samples_rate: u32,
+}
+
+/// Reading end of a `RingBufferPlayer`, can be moved to another thread
+#[derive(Clone)]
+pub struct RingBufferConsumer {
+    buffer: Arc<Mutex<RingBuffer>>,
+}
+
+impl RingBufferPlayer {
+    /// Create a player holding up to `capacity` stereo frames and the
+    /// consumer used to read them
+    pub fn new(samples_rate: u32, capacity: usize) -> (RingBufferPlayer, RingBufferConsumer) {
+        let buffer = Arc::new(Mutex::new(RingBuffer {
+            frames: VecDeque::with_capacity(capacity),
+            capacity,
+            underflowed: false,
+        }));
+
+        let player = RingBufferPlayer {
+            buffer: buffer.clone(),
+            samples_rate,
+        };
+
+        (player, RingBufferConsumer { buffer })
+    }
+}
+
+impl AudioPlayer for RingBufferPlayer {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+
+        for frame in left_channel.iter().cloned().zip(right_channel.iter().cloned()) {
+            if buffer.frames.len() == buffer.capacity {
+                buffer.frames.pop_front();
+            }
+
+            buffer.frames.push_back(frame);
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        self.buffer.lock().unwrap().underflowed
+    }
+}
+
+impl RingBufferConsumer {
+    /// Number of stereo frames waiting to be read
+    pub fn len(&self) -> usize {
+        self.buffer.lock().unwrap().frames.len()
+    }
+
+    /// Returns `true` if no frame is waiting to be read
+    pub fn is_empty(&self) -> bool {
+        self.buffer.lock().unwrap().frames.is_empty()
+    }
+
+    /// Move frames into `out` as interleaved left/right samples, returning
+    /// the number of frames written. Missing frames are left untouched
+    /// and flag an underflow.
+    pub fn drain(&self, out: &mut [f32]) -> usize {
+        let mut buffer = self.buffer.lock().unwrap();
+        let mut count = 0;
+
+        for samples in out.chunks_mut(2) {
+            if samples.len() < 2 {
+                break;
+            }
+
+            match buffer.frames.pop_front() {
+                Some((left, right)) => {
+                    samples[0] = left;
+                    samples[1] = right;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+
+        buffer.underflowed = count < out.len() / 2;
+
+        count
+    }
+}
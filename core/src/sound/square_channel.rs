@@ -1,5 +1,7 @@
 use self::blip_buf::BlipBuf;
 use super::volume_envelope::VolumeEnvelope;
+use super::WAVE_PATTERN;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 
 extern crate blip_buf;
 
@@ -21,7 +23,7 @@ pub struct SquareChannel {
     sweep_shift: u8,
     sweep_frequency_increase: bool,
     volume_envelope: VolumeEnvelope,
-    blip: BlipBuf,
+    pub blip: BlipBuf,
 }
 
 impl SquareChannel {
@@ -54,6 +56,23 @@ impl SquareChannel {
         self.enabled
     }
 
+    /// Current volume of the envelope
+    #[cfg(test)]
+    pub fn volume(&self) -> u8 {
+        self.volume_envelope.volume()
+    }
+
+    /// Current frequency, updated by the sweep
+    #[cfg(test)]
+    pub fn frequency(&self) -> u16 {
+        self.frequency
+    }
+
+    /// Turn the channel off, when the sound unit is disabled
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
     /// write a byte on sound channel
     pub fn write_byte(&mut self, address: u16, value: u8) {
         match address {
@@ -103,6 +122,56 @@ impl SquareChannel {
         self.volume_envelope.write_byte(address, value);
     }
 
+    /// Generate the channel output between `start_time` and `end_time`
+    pub fn run(&mut self, start_time: u32, end_time: u32) {
+        let volume = self.volume_envelope.volume() as i32;
+
+        if !self.enabled || self.period == 0 || volume == 0 {
+            // silence the channel
+            if self.last_amp != 0 {
+                self.blip.add_delta(start_time, -self.last_amp);
+                self.last_amp = 0;
+                self.delay = 0;
+            }
+
+            return;
+        }
+
+        let mut time = start_time + self.delay;
+        let pattern = WAVE_PATTERN[self.duty as usize];
+
+        while time < end_time {
+            let amp = volume * pattern[self.phase as usize];
+
+            if amp != self.last_amp {
+                self.blip.add_delta(time, amp - self.last_amp);
+                self.last_amp = amp;
+            }
+
+            time += self.period;
+            self.phase = (self.phase + 1) % 8;
+        }
+
+        // the next run starts where this one overshot
+        self.delay = time - end_time;
+    }
+
+    /// Length counter step, disables the channel when it expires
+    pub fn step_length(&mut self) {
+        if self.length_enabled && self.length != 0 {
+            self.length -= 1;
+
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Volume envelope step
+    pub fn step_envelope(&mut self) {
+        self.volume_envelope.step();
+    }
+
     /// Compute the new period based on the wave frequency.
     fn calculate_period(&mut self) {
         if self.frequency > 2048 {
@@ -112,7 +181,8 @@ impl SquareChannel {
         }
     }
 
-    fn step_sweep(&mut self) {
+    /// Frequency sweep step
+    pub fn step_sweep(&mut self) {
         if !self.has_sweep || self.sweep_period == 0 { return; }
 
         if self.sweep_delay > 1 {
@@ -149,3 +219,46 @@ impl SquareChannel {
             }
     }
 }
+
+/// The blip buffer is cleared on load, the output restarts from silence
+impl SaveState for SquareChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.phase);
+        writer.write_u8(self.length);
+        writer.write_u8(self.new_length);
+        writer.write_bool(self.length_enabled);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.period);
+        writer.write_u32(self.delay);
+        writer.write_u16(self.sweep_frequency);
+        writer.write_u8(self.sweep_delay);
+        writer.write_u8(self.sweep_period);
+        writer.write_u8(self.sweep_shift);
+        writer.write_bool(self.sweep_frequency_increase);
+        self.volume_envelope.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 0x3;
+        self.phase = reader.read_u8()? % 8;
+        self.length = reader.read_u8()?;
+        self.new_length = reader.read_u8()?;
+        self.length_enabled = reader.read_bool()?;
+        self.frequency = reader.read_u16()?;
+        self.period = reader.read_u32()?;
+        self.delay = reader.read_u32()?;
+        self.sweep_frequency = reader.read_u16()?;
+        self.sweep_delay = reader.read_u8()?;
+        self.sweep_period = reader.read_u8()?;
+        self.sweep_shift = reader.read_u8()?;
+        self.sweep_frequency_increase = reader.read_bool()?;
+        self.volume_envelope.load_state(reader)?;
+
+        self.last_amp = 0;
+        self.blip.clear();
+        Ok(())
+    }
+}
@@ -1,4 +1,5 @@
 use self::blip_buf::BlipBuf;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 
 extern crate blip_buf;
 
@@ -39,6 +40,11 @@ impl VolumeEnvelope {
         }
     }
 
+    /// Current volume of the channel
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
     /// Step function for the volume envelop.
     pub fn step(&mut self) {
         // don't do nothing during the delay period
@@ -57,3 +63,22 @@ impl VolumeEnvelope {
         }
     }
 }
+
+impl SaveState for VolumeEnvelope {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.period);
+        writer.write_bool(self.goes_up);
+        writer.write_u8(self.delay);
+        writer.write_u8(self.initial_volume);
+        writer.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.period = reader.read_u8()?;
+        self.goes_up = reader.read_bool()?;
+        self.delay = reader.read_u8()?;
+        self.initial_volume = reader.read_u8()?;
+        self.volume = reader.read_u8()?;
+        Ok(())
+    }
+}
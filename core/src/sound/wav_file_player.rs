@@ -0,0 +1,106 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::sound::AudioPlayer;
+
+/// Size of the RIFF/WAVE header written before the samples
+const HEADER_SIZE: u32 = 44;
+/// Samples are stored as interleaved 16-bit stereo
+const CHANNELS: u16 = 2;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Player that writes the audio as 16-bit stereo PCM to a WAV file.
+///
+/// The header sizes are only known at the end, they are patched when the
+/// player is dropped.
+pub struct WavFilePlayer {
+    file: BufWriter<File>,
+    samples_rate: u32,
+    /// Bytes of sample data written so far
+    data_size: u32,
+}
+
+impl WavFilePlayer {
+    /// Create the WAV file at `path`
+    pub fn create<P: AsRef<Path>>(path: P, samples_rate: u32) -> io::Result<WavFilePlayer> {
+        let mut player = WavFilePlayer {
+            file: BufWriter::new(File::create(path)?),
+            samples_rate,
+            data_size: 0,
+        };
+
+        player.write_header()?;
+
+        Ok(player)
+    }
+
+    /// Write the header for the current data size at the file start
+    fn write_header(&mut self) -> io::Result<()> {
+        let block_align = CHANNELS * BITS_PER_SAMPLE / 8;
+        let byte_rate = self.samples_rate * block_align as u32;
+
+        self.file.seek(SeekFrom::Start(0))?;
+
+        self.file.write_all(b"RIFF")?;
+        self.file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.write_all(b"WAVE")?;
+
+        self.file.write_all(b"fmt ")?;
+        self.file.write_all(&16u32.to_le_bytes())?;
+        // PCM format
+        self.file.write_all(&1u16.to_le_bytes())?;
+        self.file.write_all(&CHANNELS.to_le_bytes())?;
+        self.file.write_all(&self.samples_rate.to_le_bytes())?;
+        self.file.write_all(&byte_rate.to_le_bytes())?;
+        self.file.write_all(&block_align.to_le_bytes())?;
+        self.file.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+
+        self.file.write_all(b"data")?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+
+        self.file.seek(SeekFrom::End(0))?;
+
+        Ok(())
+    }
+
+    /// Append the samples to the file
+    fn write_samples(&mut self, left_channel: &[f32], right_channel: &[f32]) -> io::Result<()> {
+        for (left, right) in left_channel.iter().zip(right_channel) {
+            self.file.write_all(&to_i16(*left).to_le_bytes())?;
+            self.file.write_all(&to_i16(*right).to_le_bytes())?;
+            self.data_size += (CHANNELS * BITS_PER_SAMPLE / 8) as u32;
+        }
+
+        Ok(())
+    }
+}
+
+/// Convert a sample in the [-1, 1] range to 16-bit PCM
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16
+}
+
+impl AudioPlayer for WavFilePlayer {
+    fn play(&mut self, left_channel: &[f32], right_channel: &[f32]) {
+        if let Err(e) = self.write_samples(left_channel, right_channel) {
+            error!("Unable to write the audio samples: {}", e);
+        }
+    }
+
+    fn samples_rate(&self) -> u32 {
+        self.samples_rate
+    }
+
+    fn underflowed(&self) -> bool {
+        false
+    }
+}
+
+impl Drop for WavFilePlayer {
+    fn drop(&mut self) {
+        if let Err(e) = self.write_header().and_then(|_| self.file.flush()) {
+            error!("Unable to finish the WAV file: {}", e);
+        }
+    }
+}
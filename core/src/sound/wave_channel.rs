@@ -1,4 +1,5 @@
 use self::blip_buf::BlipBuf;
+use crate::state::{SaveState, StateReader, StateResult, StateWriter};
 
 extern crate blip_buf;
 
@@ -15,7 +16,7 @@ pub struct WaveChannel {
     volume_shift: u8,
     pub waveram: [u8; 32],
     current_wave: u8,
-    blip: BlipBuf
+    pub blip: BlipBuf
 }
 
 impl WaveChannel {
@@ -43,11 +44,16 @@ impl WaveChannel {
         self.enabled
     }
 
+    /// Turn the channel off, when the sound unit is disabled
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+
     /// write a byte on sound channel
     pub fn write_byte(&mut self, address: u16, value: u8) {
         match address {
             0x1a => {
-                self.enabled_flag = true;
+                self.enabled_flag = value & 0x80 == 0x80;
                 self.enabled = self.enabled && self.enabled_flag;
             }
             0x1b => self.new_length = 256 - (value as u16),
@@ -67,14 +73,66 @@ impl WaveChannel {
                     self.delay = 0;
                 }
             },
-            0x30 | 0x3f => {
-                self.waveram[(address as usize - 0xFF30) / 2] = value >> 4;
-                self.waveram[(address as usize - 0xFF30) / 2 + 1] = value & 0xF;
+            // each byte holds two 4-bit samples, upper nibble first
+            0x30 ... 0x3f => {
+                self.waveram[(address as usize - 0x30) * 2] = value >> 4;
+                self.waveram[(address as usize - 0x30) * 2 + 1] = value & 0xF;
             }
             _ => ()
         }
     }
 
+    /// Generate the channel output between `start_time` and `end_time`
+    pub fn run(&mut self, start_time: u32, end_time: u32) {
+        if !self.enabled || self.period == 0 {
+            // silence the channel
+            if self.last_amp != 0 {
+                self.blip.add_delta(start_time, -self.last_amp);
+                self.last_amp = 0;
+                self.delay = 0;
+            }
+
+            return;
+        }
+
+        let mut time = start_time + self.delay;
+
+        // samples are played muted, at 100%, 50% or 25%
+        let volume_shift = match self.volume_shift {
+            0 => 4,
+            1 => 0,
+            2 => 1,
+            _ => 2,
+        };
+
+        while time < end_time {
+            let sample = self.waveram[self.current_wave as usize];
+            let amp = (sample >> volume_shift) as i32;
+
+            if amp != self.last_amp {
+                self.blip.add_delta(time, amp - self.last_amp);
+                self.last_amp = amp;
+            }
+
+            time += self.period;
+            self.current_wave = (self.current_wave + 1) % 32;
+        }
+
+        // the next run starts where this one overshot
+        self.delay = time - end_time;
+    }
+
+    /// Length counter step, disables the channel when it expires
+    pub fn step_length(&mut self) {
+        if self.length_enabled && self.length != 0 {
+            self.length -= 1;
+
+            if self.length == 0 {
+                self.enabled = false;
+            }
+        }
+    }
+
     /// Compute the wave period.
     fn calculate_period(&mut self) {
         if self.frequency > 2048 {
@@ -84,3 +142,38 @@ impl WaveChannel {
         }
     }
 }
+
+/// The blip buffer is cleared on load, the output restarts from silence
+impl SaveState for WaveChannel {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.enabled_flag);
+        writer.write_u16(self.length);
+        writer.write_u16(self.new_length);
+        writer.write_bool(self.length_enabled);
+        writer.write_u16(self.frequency);
+        writer.write_u32(self.period);
+        writer.write_u32(self.delay);
+        writer.write_u8(self.volume_shift);
+        writer.write_bytes(&self.waveram);
+        writer.write_u8(self.current_wave);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()> {
+        self.enabled = reader.read_bool()?;
+        self.enabled_flag = reader.read_bool()?;
+        self.length = reader.read_u16()?;
+        self.new_length = reader.read_u16()?;
+        self.length_enabled = reader.read_bool()?;
+        self.frequency = reader.read_u16()?;
+        self.period = reader.read_u32()?;
+        self.delay = reader.read_u32()?;
+        self.volume_shift = reader.read_u8()?;
+        reader.read_into(&mut self.waveram)?;
+        self.current_wave = reader.read_u8()? % 32;
+
+        self.last_amp = 0;
+        self.blip.clear();
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+//! Save states.
+//!
+//! A save state is a snapshot of the whole machine in a small binary
+//! format: a header with a magic number, the format version and a
+//! checksum of the cartridge ROM, followed by the state of every
+//! component. All the values are little-endian. The ROM itself and the
+//! devices plugged into the machine (audio player, link cable) are not
+//! part of the state.
+
+use std::{error, fmt};
+
+/// Magic number at the start of every save state
+const MAGIC: &[u8; 4] = b"RBST";
+
+/// Version of the format, increased on every incompatible change
+pub const STATE_VERSION: u16 = 2;
+
+// ------------------------------------------------------------ [Errors]
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// The data isn't a save state
+    InvalidMagic,
+    /// The save state was made by an incompatible version
+    UnsupportedVersion(u16),
+    /// The save state was made with another ROM
+    RomMismatch { expected: u32, found: u32 },
+    /// The data ends before the state is complete
+    Truncated,
+    /// A value in the state is out of range
+    InvalidValue(&'static str),
+}
+
+/// Type to be used when returning a result
+pub type StateResult<T> = Result<T, StateError>;
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::InvalidMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported save state version {} (expected {})", version, STATE_VERSION)
+            }
+            StateError::RomMismatch { expected, found } => {
+                write!(f, "The save state belongs to another ROM (checksum {:#010x}, expected {:#010x})",
+                       found, expected)
+            }
+            StateError::Truncated => write!(f, "The save state is truncated"),
+            StateError::InvalidValue(name) => write!(f, "Invalid {} in the save state", name),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+// ------------------------------------------------------------ [Format]
+
+/// Serialises the state of the components
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    /// Create a writer, starting with the header of a state made with
+    /// the ROM with the given checksum
+    pub fn new(rom_checksum: u32) -> StateWriter {
+        let mut writer = StateWriter { data: Vec::new() };
+
+        writer.write_bytes(MAGIC);
+        writer.write_u16(STATE_VERSION);
+        writer.write_u32(rom_checksum);
+
+        writer
+    }
+
+    /// Get the serialised state
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write a fixed size block, the reader must know its size
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Write a block prefixed by its size
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+}
+
+/// Reads back a state serialised by `StateWriter`
+pub struct StateReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    /// Create a reader, checking the header of the state against the
+    /// checksum of the current ROM
+    pub fn new(data: &'a [u8], rom_checksum: u32) -> StateResult<StateReader<'a>> {
+        let mut reader = StateReader { data };
+
+        if reader.read_bytes(MAGIC.len()).ok() != Some(&MAGIC[..]) {
+            return Err(StateError::InvalidMagic);
+        }
+
+        let version = reader.read_u16()?;
+        if version != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let found = reader.read_u32()?;
+        if found != rom_checksum {
+            return Err(StateError::RomMismatch { expected: rom_checksum, found });
+        }
+
+        Ok(reader)
+    }
+
+    /// Fail if the state has data left after the last component
+    pub fn finish(&self) -> StateResult<()> {
+        if self.data.is_empty() {
+            Ok(())
+        } else {
+            Err(StateError::InvalidValue("trailing data"))
+        }
+    }
+
+    pub fn read_u8(&mut self) -> StateResult<u8> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> StateResult<bool> {
+        match self.read_u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::InvalidValue("flag")),
+        }
+    }
+
+    pub fn read_u16(&mut self) -> StateResult<u16> {
+        let mut bytes = [0u8; 2];
+        bytes.copy_from_slice(self.read_bytes(2)?);
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> StateResult<u32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_u64(&mut self) -> StateResult<u64> {
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(self.read_bytes(8)?);
+        Ok(u64::from_le_bytes(bytes))
+    }
+
+    pub fn read_i32(&mut self) -> StateResult<i32> {
+        let mut bytes = [0u8; 4];
+        bytes.copy_from_slice(self.read_bytes(4)?);
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    /// Read a fixed size block
+    pub fn read_bytes(&mut self, size: usize) -> StateResult<&'a [u8]> {
+        if self.data.len() < size {
+            return Err(StateError::Truncated);
+        }
+
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    /// Read a fixed size block into `buffer`
+    pub fn read_into(&mut self, buffer: &mut [u8]) -> StateResult<()> {
+        buffer.copy_from_slice(self.read_bytes(buffer.len())?);
+        Ok(())
+    }
+
+    /// Read a block prefixed by its size
+    pub fn read_vec(&mut self) -> StateResult<Vec<u8>> {
+        let size = self.read_u32()? as usize;
+        Ok(self.read_bytes(size)?.to_vec())
+    }
+}
+
+/// Components that are part of the save states
+pub trait SaveState {
+    /// Serialise the state of the component
+    fn save_state(&self, writer: &mut StateWriter);
+
+    /// Restore the state of the component. On error the component may be
+    /// left partially restored.
+    fn load_state(&mut self, reader: &mut StateReader) -> StateResult<()>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM_CHECKSUM: u32 = 0x1234_5678;
+
+    fn header() -> Vec<u8> {
+        StateWriter::new(ROM_CHECKSUM).into_data()
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new(ROM_CHECKSUM);
+        writer.write_u8(0xab);
+        writer.write_bool(true);
+        writer.write_u16(0xbeef);
+        writer.write_u32(0xdead_beef);
+        writer.write_u64(0x0123_4567_89ab_cdef);
+        writer.write_i32(-42);
+        writer.write_bytes(&[1, 2, 3]);
+        writer.write_vec(&[4, 5]);
+        let data = writer.into_data();
+
+        let mut reader = StateReader::new(&data, ROM_CHECKSUM).unwrap();
+        assert_eq!(reader.read_u8(), Ok(0xab));
+        assert_eq!(reader.read_bool(), Ok(true));
+        assert_eq!(reader.read_u16(), Ok(0xbeef));
+        assert_eq!(reader.read_u32(), Ok(0xdead_beef));
+        assert_eq!(reader.read_u64(), Ok(0x0123_4567_89ab_cdef));
+        assert_eq!(reader.read_i32(), Ok(-42));
+        assert_eq!(reader.read_bytes(3), Ok(&[1, 2, 3][..]));
+        assert_eq!(reader.read_vec(), Ok(vec![4, 5]));
+        assert_eq!(reader.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_other_data() {
+        let mut data = header();
+        data[0] = b'X';
+        assert_eq!(StateReader::new(&data, ROM_CHECKSUM).err(), Some(StateError::InvalidMagic));
+
+        assert_eq!(StateReader::new(b"RB", ROM_CHECKSUM).err(), Some(StateError::InvalidMagic));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut data = header();
+        data[4..6].copy_from_slice(&(STATE_VERSION + 1).to_le_bytes());
+
+        assert_eq!(StateReader::new(&data, ROM_CHECKSUM).err(),
+                   Some(StateError::UnsupportedVersion(STATE_VERSION + 1)));
+    }
+
+    #[test]
+    fn rejects_other_roms() {
+        let data = header();
+
+        assert_eq!(StateReader::new(&data, 0xcafe_f00d).err(),
+                   Some(StateError::RomMismatch { expected: 0xcafe_f00d, found: ROM_CHECKSUM }));
+    }
+
+    #[test]
+    fn rejects_truncated_and_trailing_data() {
+        let mut writer = StateWriter::new(ROM_CHECKSUM);
+        writer.write_vec(&[1, 2, 3, 4]);
+        let data = writer.into_data();
+
+        let mut reader = StateReader::new(&data[..data.len() - 1], ROM_CHECKSUM).unwrap();
+        assert_eq!(reader.read_vec(), Err(StateError::Truncated));
+
+        let mut reader = StateReader::new(&data, ROM_CHECKSUM).unwrap();
+        assert_eq!(reader.read_u16(), Ok(4));
+        assert_eq!(reader.finish(), Err(StateError::InvalidValue("trailing data")));
+
+        assert_eq!(StateReader::new(&data[..6], ROM_CHECKSUM).err(), Some(StateError::Truncated));
+    }
+}
@@ -1,4 +1,5 @@
-#[derive(Debug)]
+/// Keys of the Game Boy
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum GbKey {
     Right,
     Left,
@@ -0,0 +1,391 @@
+//! Key and game controller bindings.
+//!
+//! Bindings are read from a small TOML file with a `[keyboard]` and a
+//! `[controller]` section, each mapping an action to one or more inputs:
+//!
+//! ```toml
+//! [keyboard]
+//! a = "X"
+//! fast_forward = ["Tab", "Space"]
+//!
+//! [controller]
+//! up = ["dpup", "-lefty"]
+//! fast_forward = "+righttrigger"
+//! ```
+//!
+//! Keyboard inputs use the SDL key names, controller inputs the SDL button
+//! names or an axis name prefixed by the direction. A file only replaces
+//! the actions it lists, so a per-ROM file can override a few of them.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use rustboy::GbKey;
+use sdl2::controller::{Axis, Button};
+use sdl2::keyboard::Keycode;
+
+/// Emulator commands that can be bound to an input
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Hotkey {
+    Pause,
+    /// Run a single frame and pause
+    FrameAdvance,
+    FastForward,
+    /// Select the next faster speed
+    SpeedUp,
+    /// Select the next slower speed
+    SpeedDown,
+    Rewind,
+    SaveState,
+    LoadState,
+    Screenshot,
+    Quit,
+    /// Select the save state slot
+    SelectSlot(u8),
+}
+
+/// What an input does when pressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Action {
+    Key(GbKey),
+    Hotkey(Hotkey),
+}
+
+/// Names of the actions on the bindings file
+const ACTIONS: [(&str, Action); 28] = [
+    ("right", Action::Key(GbKey::Right)),
+    ("left", Action::Key(GbKey::Left)),
+    ("up", Action::Key(GbKey::Up)),
+    ("down", Action::Key(GbKey::Down)),
+    ("a", Action::Key(GbKey::A)),
+    ("b", Action::Key(GbKey::B)),
+    ("select", Action::Key(GbKey::Select)),
+    ("start", Action::Key(GbKey::Start)),
+    ("pause", Action::Hotkey(Hotkey::Pause)),
+    ("frame_advance", Action::Hotkey(Hotkey::FrameAdvance)),
+    ("fast_forward", Action::Hotkey(Hotkey::FastForward)),
+    ("speed_up", Action::Hotkey(Hotkey::SpeedUp)),
+    ("speed_down", Action::Hotkey(Hotkey::SpeedDown)),
+    ("rewind", Action::Hotkey(Hotkey::Rewind)),
+    ("save_state", Action::Hotkey(Hotkey::SaveState)),
+    ("load_state", Action::Hotkey(Hotkey::LoadState)),
+    ("screenshot", Action::Hotkey(Hotkey::Screenshot)),
+    ("quit", Action::Hotkey(Hotkey::Quit)),
+    ("slot_0", Action::Hotkey(Hotkey::SelectSlot(0))),
+    ("slot_1", Action::Hotkey(Hotkey::SelectSlot(1))),
+    ("slot_2", Action::Hotkey(Hotkey::SelectSlot(2))),
+    ("slot_3", Action::Hotkey(Hotkey::SelectSlot(3))),
+    ("slot_4", Action::Hotkey(Hotkey::SelectSlot(4))),
+    ("slot_5", Action::Hotkey(Hotkey::SelectSlot(5))),
+    ("slot_6", Action::Hotkey(Hotkey::SelectSlot(6))),
+    ("slot_7", Action::Hotkey(Hotkey::SelectSlot(7))),
+    ("slot_8", Action::Hotkey(Hotkey::SelectSlot(8))),
+    ("slot_9", Action::Hotkey(Hotkey::SelectSlot(9))),
+];
+
+/// Default bindings, in the bindings file format
+const DEFAULT_BINDINGS: &str = r#"
+[keyboard]
+right = "Right"
+left = "Left"
+up = "Up"
+down = "Down"
+a = "X"
+b = "Z"
+select = "Backspace"
+start = "Return"
+pause = "P"
+frame_advance = "N"
+fast_forward = "Tab"
+speed_up = "="
+speed_down = "-"
+rewind = "R"
+save_state = "F5"
+load_state = "F9"
+screenshot = "F12"
+quit = "Escape"
+slot_0 = "0"
+slot_1 = "1"
+slot_2 = "2"
+slot_3 = "3"
+slot_4 = "4"
+slot_5 = "5"
+slot_6 = "6"
+slot_7 = "7"
+slot_8 = "8"
+slot_9 = "9"
+
+[controller]
+right = ["dpright", "+leftx"]
+left = ["dpleft", "-leftx"]
+up = ["dpup", "-lefty"]
+down = ["dpdown", "+lefty"]
+a = "a"
+b = "b"
+select = "back"
+start = "start"
+fast_forward = "+righttrigger"
+rewind = "+lefttrigger"
+"#;
+
+/// Section of the bindings file being parsed
+enum Section {
+    None,
+    Keyboard,
+    Controller,
+}
+
+#[derive(Clone)]
+pub struct Bindings {
+    keyboard: HashMap<Keycode, Action>,
+    buttons: HashMap<Button, Action>,
+    /// Axes bindings, the flag is `true` for the positive direction
+    axes: HashMap<(Axis, bool), Action>,
+}
+
+impl Bindings {
+    /// Create the default bindings
+    pub fn new() -> Self {
+        let mut bindings = Self {
+            keyboard: HashMap::new(),
+            buttons: HashMap::new(),
+            axes: HashMap::new(),
+        };
+
+        bindings.parse(DEFAULT_BINDINGS)
+            .expect("Invalid default bindings");
+
+        bindings
+    }
+
+    /// Load a bindings file on top of the current bindings
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("{}: {}", path.display(), e))?;
+
+        self.parse(&content)
+            .map_err(|e| format!("{}: {}", path.display(), e))
+    }
+
+    /// Action bound to a keyboard key
+    pub fn keyboard(&self, keycode: Keycode) -> Option<Action> {
+        self.keyboard.get(&keycode).cloned()
+    }
+
+    /// Action bound to a game controller button
+    pub fn button(&self, button: Button) -> Option<Action> {
+        self.buttons.get(&button).cloned()
+    }
+
+    /// Action bound to a game controller axis moved in a direction
+    pub fn axis(&self, axis: Axis, positive: bool) -> Option<Action> {
+        self.axes.get(&(axis, positive)).cloned()
+    }
+
+    /// Parse the bindings file content, on error the bindings are left
+    /// unchanged
+    fn parse(&mut self, content: &str) -> Result<(), String> {
+        let mut bindings = self.clone();
+        bindings.apply(content)?;
+
+        *self = bindings;
+        Ok(())
+    }
+
+    /// Apply the bindings of a file content, on error the bindings are
+    /// left partially applied
+    fn apply(&mut self, content: &str) -> Result<(), String> {
+        let mut section = Section::None;
+
+        for (number, line) in content.lines().enumerate() {
+            let line = strip_comment(line).trim();
+            let error = |message: &str| format!("line {}: {}", number + 1, message);
+
+            if line.is_empty() {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                section = match line[1..line.len() - 1].trim() {
+                    "keyboard" => Section::Keyboard,
+                    "controller" => Section::Controller,
+                    name => return Err(error(&format!("unknown section {}", name))),
+                };
+                continue;
+            }
+
+            let mut parts = line.splitn(2, '=');
+            let name = parts.next().unwrap().trim();
+            let value = parts.next().ok_or_else(|| error("expected name = value"))?;
+
+            let action = ACTIONS.iter()
+                .find(|&&(action_name, _)| action_name == name)
+                .map(|&(_, action)| action)
+                .ok_or_else(|| error(&format!("unknown action {}", name)))?;
+            let inputs = parse_value(value.trim()).ok_or_else(|| error("invalid value"))?;
+
+            match section {
+                Section::None => return Err(error("binding outside of a section")),
+                Section::Keyboard => {
+                    self.keyboard.retain(|_, bound| *bound != action);
+
+                    for input in inputs {
+                        let keycode = Keycode::from_name(&input)
+                            .ok_or_else(|| error(&format!("unknown key {}", input)))?;
+                        self.keyboard.insert(keycode, action);
+                    }
+                }
+                Section::Controller => {
+                    self.buttons.retain(|_, bound| *bound != action);
+                    self.axes.retain(|_, bound| *bound != action);
+
+                    for input in inputs {
+                        let positive = input.starts_with('+');
+
+                        if positive || input.starts_with('-') {
+                            let axis = Axis::from_string(&input[1..])
+                                .ok_or_else(|| error(&format!("unknown axis {}", input)))?;
+                            self.axes.insert((axis, positive), action);
+                        } else {
+                            let button = Button::from_string(&input)
+                                .ok_or_else(|| error(&format!("unknown button {}", input)))?;
+                            self.buttons.insert(button, action);
+                        }
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Remove a comment from a line, ignoring `#` inside strings
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+
+    for (index, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..index],
+            _ => (),
+        }
+    }
+
+    line
+}
+
+/// Parse a string or an array of strings
+fn parse_value(value: &str) -> Option<Vec<String>> {
+    if value.starts_with('[') && value.ends_with(']') {
+        // an empty array unbinds the action, a trailing comma is allowed
+        split_items(&value[1..value.len() - 1]).into_iter()
+            .filter(|item| !item.trim().is_empty())
+            .map(|item| parse_string(item.trim()))
+            .collect()
+    } else {
+        parse_string(value).map(|string| vec![string])
+    }
+}
+
+/// Split the items of an array on the commas, ignoring `,` inside strings
+fn split_items(items: &str) -> Vec<&str> {
+    let mut in_string = false;
+    let mut start = 0;
+    let mut split = Vec::new();
+
+    for (index, c) in items.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            ',' if !in_string => {
+                split.push(&items[start..index]);
+                start = index + 1;
+            }
+            _ => (),
+        }
+    }
+
+    split.push(&items[start..]);
+    split
+}
+
+/// Parse a quoted string
+fn parse_string(value: &str) -> Option<String> {
+    if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+        Some(value[1..value.len() - 1].to_string())
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_bindings_parse() {
+        let bindings = Bindings::new();
+
+        assert_eq!(bindings.keyboard(Keycode::X), Some(Action::Key(GbKey::A)));
+        assert_eq!(bindings.keyboard(Keycode::Equals), Some(Action::Hotkey(Hotkey::SpeedUp)));
+        assert_eq!(bindings.keyboard(Keycode::Num5), Some(Action::Hotkey(Hotkey::SelectSlot(5))));
+        assert_eq!(bindings.button(Button::DPadUp), Some(Action::Key(GbKey::Up)));
+        assert_eq!(bindings.axis(Axis::LeftY, false), Some(Action::Key(GbKey::Up)));
+        assert_eq!(bindings.axis(Axis::TriggerRight, true), Some(Action::Hotkey(Hotkey::FastForward)));
+    }
+
+    #[test]
+    fn override_only_replaces_the_listed_actions() {
+        let mut bindings = Bindings::new();
+        bindings.parse("[keyboard]\na = \"A\"\n").unwrap();
+
+        assert_eq!(bindings.keyboard(Keycode::A), Some(Action::Key(GbKey::A)));
+        assert_eq!(bindings.keyboard(Keycode::X), None);
+        assert_eq!(bindings.keyboard(Keycode::Z), Some(Action::Key(GbKey::B)));
+        assert_eq!(bindings.button(Button::A), Some(Action::Key(GbKey::A)));
+    }
+
+    #[test]
+    fn empty_array_unbinds_the_action() {
+        let mut bindings = Bindings::new();
+        bindings.parse("[controller]\nrewind = []\n").unwrap();
+
+        assert_eq!(bindings.axis(Axis::TriggerLeft, true), None);
+        assert_eq!(bindings.keyboard(Keycode::R), Some(Action::Hotkey(Hotkey::Rewind)));
+    }
+
+    #[test]
+    fn comment_and_comma_inside_strings() {
+        let mut bindings = Bindings::new();
+        bindings.parse("[keyboard] # keys\nscreenshot = \"#\" # the hash key\npause = [\",\", \"P\",]\n").unwrap();
+
+        assert_eq!(bindings.keyboard(Keycode::Hash), Some(Action::Hotkey(Hotkey::Screenshot)));
+        assert_eq!(bindings.keyboard(Keycode::Comma), Some(Action::Hotkey(Hotkey::Pause)));
+        assert_eq!(bindings.keyboard(Keycode::P), Some(Action::Hotkey(Hotkey::Pause)));
+    }
+
+    #[test]
+    fn axis_direction_prefixes() {
+        let mut bindings = Bindings::new();
+        bindings.parse("[controller]\nup = \"-righty\"\ndown = \"+righty\"\n").unwrap();
+
+        assert_eq!(bindings.axis(Axis::RightY, false), Some(Action::Key(GbKey::Up)));
+        assert_eq!(bindings.axis(Axis::RightY, true), Some(Action::Key(GbKey::Down)));
+        assert_eq!(bindings.axis(Axis::LeftY, false), None);
+        assert_eq!(bindings.button(Button::DPadUp), None);
+    }
+
+    #[test]
+    fn errors_leave_the_bindings_unchanged() {
+        let mut bindings = Bindings::new();
+
+        assert!(bindings.parse("[keyboard]\na = \"A\"\nturbo = \"T\"\n").is_err());
+        assert!(bindings.parse("[keyboard]\na = \"A\"\n[mouse]\n").is_err());
+        assert!(bindings.parse("a = \"A\"\n").is_err());
+
+        assert_eq!(bindings.keyboard(Keycode::X), Some(Action::Key(GbKey::A)));
+        assert_eq!(bindings.keyboard(Keycode::A), None);
+    }
+}
@@ -2,14 +2,34 @@
 
 use rustboy::Machine;
 use crate::renderer::Renderer;
-use crate::sdl::Context;
-use super::sdl::display::Display;
+use crate::bindings::{Action, Bindings, Hotkey};
+use crate::sdl::{Context, Input};
+use super::sdl::display::{self, Display};
 
 #[derive(Debug)]
 pub enum Event {
     Quit,
     Break,
     Continue,
+    /// Toggle the pause
+    Pause,
+    /// Run a single frame and pause
+    FrameAdvance,
+    /// Fast-forward pressed or released
+    FastForward(bool),
+    /// Select the next faster speed
+    SpeedUp,
+    /// Select the next slower speed
+    SpeedDown,
+    /// Rewind pressed or released
+    Rewind(bool),
+    /// Save the state to the selected slot
+    SaveState,
+    /// Load the state from the selected slot
+    LoadState,
+    /// Select the save state slot
+    SelectSlot(u8),
+    Screenshot,
 }
 
 /// Structure that controls all the front-end interactions.
@@ -22,8 +42,8 @@ pub struct Controller {
 
 impl Controller {
     /// Create a new Controller instance
-    pub fn new(x: u32, y: u32) -> Self {
-        let context = Context::new();
+    pub fn new(x: u32, y: u32, bindings: Bindings) -> Self {
+        let context = Context::new(bindings);
         let display = context.new_display(x, y);
 
         Self {
@@ -39,9 +59,55 @@ impl Controller {
         }
 
         // TODO: add support for sound
+
+        // Follow the cartridge rumble motor
+        if let Some(rumble) = emulator.take_rumble_event() {
+            self.context.set_rumble(rumble);
+        }
+    }
+
+    /// Forward the joypad inputs to the emulator and return the events
+    /// of the pressed hotkeys
+    pub fn update_controller(&mut self, emulator: &mut Machine) -> Vec<Event> {
+        let mut events = Vec::new();
+
+        for input in self.context.update_controller() {
+            let event = match input {
+                Input::Quit => Event::Quit,
+                Input::Pressed(Action::Key(key)) => {
+                    emulator.key_down(key);
+                    continue;
+                }
+                Input::Released(Action::Key(key)) => {
+                    emulator.key_up(key);
+                    continue;
+                }
+                Input::Pressed(Action::Hotkey(hotkey)) => match hotkey {
+                    Hotkey::Pause => Event::Pause,
+                    Hotkey::FrameAdvance => Event::FrameAdvance,
+                    Hotkey::FastForward => Event::FastForward(true),
+                    Hotkey::SpeedUp => Event::SpeedUp,
+                    Hotkey::SpeedDown => Event::SpeedDown,
+                    Hotkey::Rewind => Event::Rewind(true),
+                    Hotkey::SaveState => Event::SaveState,
+                    Hotkey::LoadState => Event::LoadState,
+                    Hotkey::Screenshot => Event::Screenshot,
+                    Hotkey::Quit => Event::Quit,
+                    Hotkey::SelectSlot(slot) => Event::SelectSlot(slot),
+                },
+                Input::Released(Action::Hotkey(Hotkey::FastForward)) => Event::FastForward(false),
+                Input::Released(Action::Hotkey(Hotkey::Rewind)) => Event::Rewind(false),
+                Input::Released(Action::Hotkey(_)) => continue,
+            };
+
+            events.push(event);
+        }
+
+        events
     }
 
-    pub fn update_controller(&mut self) {
-        self.context.update_controller();
+    /// Save the current screen to a BMP file
+    pub fn screenshot(&self, emulator: &Machine, path: &str) -> Result<(), String> {
+        display::save_screenshot(emulator.screen_buffer(), path)
     }
 }
\ No newline at end of file
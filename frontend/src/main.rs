@@ -7,15 +7,27 @@ use clap::{
     ArgMatches,
     App,
 };
-use rustboy::Config;
-use crate::controller::Controller;
+use rustboy::{AudioPlayer, Config, CpalPlayer, Debugger, FaultPolicy, Machine, NullPlayer, SocketLink};
+use crate::bindings::Bindings;
+use crate::controller::{Controller, Event};
+use crate::speed::{Speed, FRAME_DURATION};
+use std::path::Path;
+use std::process;
 use std::{thread, time};
 
+mod bindings;
 mod controller;
 mod renderer;
 mod sdl;
+mod speed;
 
-const FRAME_TARGET: time::Duration = time::Duration::from_millis(14);
+/// Lag after which the pacing gives up catching up with the clock
+const MAX_LAG: time::Duration = time::Duration::from_millis(100);
+
+/// Bindings file used when none is given on the command line
+const DEFAULT_BINDINGS_FILE: &str = "bindings.toml";
+/// Extension of the per-ROM bindings file, placed next to the ROM
+const ROM_BINDINGS_EXTENSION: &str = "bindings.toml";
 
 /// Build the RustBoy's command line.
 fn build_command_line() -> ArgMatches<'static> {
@@ -29,43 +41,264 @@ fn build_command_line() -> ArgMatches<'static> {
             .index(1))
         .arg(Arg::with_name("debug")
             .short("d")
-            .help("Use debug mode"))
+            .help("Break into the debugger on the terminal when a fault pauses the emulation, \
+                   implies --fault-policy pause"))
+        .arg(Arg::with_name("link-listen")
+            .long("link-listen")
+            .value_name("ADDRESS")
+            .conflicts_with("link-connect")
+            .help("Wait for a link cable connection on host:port or unix:/path"))
+        .arg(Arg::with_name("link-connect")
+            .long("link-connect")
+            .value_name("ADDRESS")
+            .help("Connect the link cable to host:port or unix:/path"))
+        .arg(Arg::with_name("fault-policy")
+            .long("fault-policy")
+            .value_name("POLICY")
+            .possible_values(&["ignore", "log", "pause", "error"])
+            .help("What to do when the game accesses unmapped memory"))
+        .arg(Arg::with_name("boot-rom")
+            .long("boot-rom")
+            .value_name("FILE")
+            .conflicts_with("skip-boot")
+            .help("Run a DMG or CGB boot ROM dump instead of the built-in one"))
+        .arg(Arg::with_name("skip-boot")
+            .long("skip-boot")
+            .help("Start the game directly, without running the boot ROM"))
+        .arg(Arg::with_name("rewind-memory")
+            .long("rewind-memory")
+            .value_name("MB")
+            .help("Memory used to rewind the game, 0 disables rewinding (default 64)"))
+        .arg(Arg::with_name("sync")
+            .long("sync")
+            .value_name("SOURCE")
+            .possible_values(&["audio", "clock"])
+            .help("Pace the frames on the audio output or on the clock (default audio)"))
+        .arg(Arg::with_name("bindings")
+            .long("bindings")
+            .value_name("FILE")
+            .help("Key bindings file, defaults to bindings.toml when it exists"))
         .get_matches()
 }
 
+/// Load the bindings file and the overrides of the ROM on top of the
+/// default bindings
+fn load_bindings(config: &Config) -> Bindings {
+    let mut bindings = Bindings::new();
+
+    let global = match config.bindings {
+        Some(ref path) => Some(path.clone()),
+        None if Path::new(DEFAULT_BINDINGS_FILE).exists() => Some(DEFAULT_BINDINGS_FILE.to_string()),
+        None => None,
+    };
+
+    let rom = Path::new(&config.rom_name).with_extension(ROM_BINDINGS_EXTENSION);
+    let rom = if rom.exists() { Some(rom.to_string_lossy().into_owned()) } else { None };
+
+    for path in global.iter().chain(rom.iter()) {
+        if let Err(e) = bindings.load(path) {
+            println!("Unable to load the bindings: {}", e);
+        }
+    }
+
+    bindings
+}
+
+/// Flush the battery backed RAM to the save file
+fn write_save_file(emulator: &mut Machine, path: &Path) {
+    if let Err(e) = emulator.write_save_file(path) {
+        println!("Unable to write {}: {}", path.display(), e);
+    }
+}
+
+/// Find a screenshot file name not used yet, based on the ROM name
+fn screenshot_path(rom_name: &str) -> String {
+    let stem = Path::new(rom_name).file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "screenshot".to_string());
+
+    (1..)
+        .map(|number| format!("{}-{}.bmp", stem, number))
+        .find(|path| !Path::new(path).exists())
+        .unwrap()
+}
+
 fn main() {
+    rustboy::init_logger();
+
     // Build command line and get the matched arguments and
     // get a config instance from that args.
     let matches = build_command_line();
     let config = Config::from_clap(matches);
 
-    let mut emulator = rustboy::create_emulator(&config.rom_name);
+    // Fallback to a silent player when there is no sound device
+    let player: Box<AudioPlayer> = match CpalPlayer::get() {
+        Some(player) => Box::new(player),
+        None => Box::new(NullPlayer::new()),
+    };
+
+    let boot = match config.boot() {
+        Ok(boot) => boot,
+        Err(e) => {
+            println!("Unable to load the boot ROM {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut emulator = match rustboy::create_emulator(&config.rom_name, player, boot) {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            println!("Unable to load {}: {}", config.rom_name, e);
+            process::exit(1);
+        }
+    };
+
+    emulator.set_fault_policy(config.fault_policy);
+    emulator.set_rewind_budget(config.rewind_budget);
 
-    let mut controller = if !config.is_headless {
-        Some(Controller::new(
-            rustboy::SCREEN_X as u32,
-            rustboy::SCREEN_Y as u32,
-        ))
+    // Battery backed RAM of the cartridge
+    let save_path = rustboy::save_file_path(&config.rom_name);
+    if let Err(e) = emulator.load_save_file(&save_path) {
+        println!("Unable to load {}: {}", save_path.display(), e);
+    }
+
+    // Plug the link cable to another instance
+    let link = if let Some(ref address) = config.link_listen {
+        println!("Waiting for the link cable on {}", address);
+        Some(SocketLink::listen(address))
+    } else if let Some(ref address) = config.link_connect {
+        Some(SocketLink::connect(address))
     } else {
         None
     };
 
-    let normal_speed = true;
+    match link {
+        Some(Ok(link)) => emulator.set_serial_link(Box::new(link)),
+        Some(Err(e)) => println!("Unable to connect the link cable: {}", e),
+        None => (),
+    }
 
-    loop {
-        let now = time::SystemTime::now();
+    let mut controller = Controller::new(
+        rustboy::SCREEN_X as u32,
+        rustboy::SCREEN_Y as u32,
+        load_bindings(&config),
+    );
 
-        if let Some(ref mut c) = controller {
-            emulator.emulate();
-            c.update_controller();
-            c.refresh(&mut emulator);
+    let mut speed = Speed::new();
+    let mut paused = false;
+    // run a single frame while paused
+    let mut advance = false;
+    let mut rewinding = false;
+    let mut state_slot = 0;
 
-            if normal_speed {
-                let elapsed = now.elapsed().unwrap();
-                if elapsed < FRAME_TARGET {
-                    thread::sleep(FRAME_TARGET - elapsed);
+    let mut next_frame = time::Instant::now();
+    let mut last_refresh = next_frame;
+
+    'main: loop {
+        if rewinding {
+            emulator.rewind(1);
+        } else if !paused || advance {
+            advance = false;
+
+            if let Err(fault) = emulator.run_frame() {
+                println!("{}", fault);
+
+                if config.fault_policy == FaultPolicy::Error {
+                    write_save_file(&mut emulator, &save_path);
+                    break 'main;
+                }
+
+                if config.is_debug {
+                    // The emulation resumes once the debugger is exited
+                    println!("Type exit to resume the emulation");
+                    Debugger::new(&mut emulator).run();
+                } else {
+                    // Pause so the state can be inspected
+                    paused = true;
                 }
             }
         }
+
+        if let Some(e) = emulator.take_serial_link_error() {
+            println!("Link cable disconnected: {}", e);
+        }
+
+        if emulator.save_ram_pending() {
+            write_save_file(&mut emulator, &save_path);
+        }
+
+        for event in controller.update_controller(&mut emulator) {
+            match event {
+                Event::Quit => {
+                    write_save_file(&mut emulator, &save_path);
+                    break 'main;
+                }
+                Event::Pause => paused = !paused,
+                Event::FrameAdvance => {
+                    paused = true;
+                    advance = true;
+                }
+                Event::FastForward(pressed) => speed.set_fast_forward(pressed),
+                Event::SpeedUp => {
+                    speed.faster();
+                    println!("Speed {}x", speed.multiplier());
+                }
+                Event::SpeedDown => {
+                    speed.slower();
+                    println!("Speed {}x", speed.multiplier());
+                }
+                Event::Rewind(pressed) => rewinding = pressed,
+                Event::Screenshot => {
+                    let path = screenshot_path(&config.rom_name);
+                    match controller.screenshot(&emulator, &path) {
+                        Ok(()) => println!("Screenshot saved to {}", path),
+                        Err(e) => println!("Unable to save the screenshot: {}", e),
+                    }
+                }
+                Event::SelectSlot(slot) => {
+                    state_slot = slot;
+                    println!("Save state slot {}", slot);
+                }
+                Event::SaveState => {
+                    let path = rustboy::state_file_path(&config.rom_name, state_slot);
+                    match emulator.write_state_file(&path) {
+                        Ok(()) => println!("State saved to slot {}", state_slot),
+                        Err(e) => println!("Unable to write {}: {}", path.display(), e),
+                    }
+                }
+                Event::LoadState => {
+                    let path = rustboy::state_file_path(&config.rom_name, state_slot);
+                    match emulator.load_state_file(&path) {
+                        Ok(()) => println!("State loaded from slot {}", state_slot),
+                        Err(e) => println!("Unable to load {}: {}", path.display(), e),
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        speed.apply_audio(&mut emulator, paused);
+
+        // Unthrottled, only the frames falling on the display rate
+        // are shown
+        let now = time::Instant::now();
+        if !speed.is_unthrottled() || now >= last_refresh + FRAME_DURATION {
+            controller.refresh(&mut emulator);
+            last_refresh = now;
+        }
+
+        // Wait for the next frame, unless the audio is starving or
+        // the emulation is unthrottled. The audio only paces the frames
+        // while they feed it, the clock does otherwise.
+        let now = time::Instant::now();
+        next_frame += speed.frame_duration();
+
+        let feeding_audio = !paused && !rewinding;
+        let starving = config.audio_sync && feeding_audio && emulator.audio_underflowed();
+        if speed.is_unthrottled() || starving || now > next_frame + MAX_LAG {
+            next_frame = now;
+        } else if next_frame > now {
+            thread::sleep(next_frame - now);
+        }
     }
-}
\ No newline at end of file
+}
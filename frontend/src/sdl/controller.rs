@@ -1,19 +1,147 @@
-use sdl2::Sdl;
+use std::collections::HashMap;
 
-pub struct Controller {}
+use sdl2::controller::{Axis, GameController};
+use sdl2::event::Event;
+use sdl2::haptic::Haptic;
+use sdl2::{EventPump, GameControllerSubsystem, HapticSubsystem, Sdl};
+use crate::bindings::{Action, Bindings};
+
+/// Axis position needed to consider it moved in a direction
+const AXIS_DEAD_ZONE: i16 = 8000;
+/// Rumble duration meaning "until stopped"
+const RUMBLE_INFINITY: u32 = ::std::u32::MAX;
+
+/// Input received from the media system
+pub enum Input {
+    Quit,
+    Pressed(Action),
+    Released(Action),
+}
+
+pub struct Controller {
+    event_pump: EventPump,
+    subsystem: GameControllerSubsystem,
+    /// Haptic subsystem, not available on all platforms
+    haptic_subsystem: Option<HapticSubsystem>,
+    bindings: Bindings,
+    /// Opened game controllers, they only send events while open
+    game_controllers: Vec<GameController>,
+    /// Rumble devices of the game controllers, with the instance id of
+    /// their controller
+    haptics: Vec<(i32, Haptic)>,
+    /// Action currently pressed by each axis
+    axes: HashMap<Axis, Action>,
+}
 
 impl Controller {
-    pub fn new() -> Self {
-        Self {}
+    pub fn new(sdl: &Sdl, bindings: Bindings) -> Self {
+        Self {
+            event_pump: sdl.event_pump().unwrap(),
+            subsystem: sdl.game_controller().unwrap(),
+            haptic_subsystem: sdl.haptic().ok(),
+            bindings,
+            game_controllers: Vec::new(),
+            haptics: Vec::new(),
+            axes: HashMap::new(),
+        }
     }
 
-    pub fn update(&self, sdl: &Sdl) {
-        let mut event_pump = sdl.event_pump().unwrap();
+    /// Process the pending events and return the resulting inputs
+    pub fn update(&mut self) -> Vec<Input> {
+        let mut inputs = Vec::new();
+        let events: Vec<Event> = self.event_pump.poll_iter().collect();
 
-        for e in event_pump.poll_iter() {
+        for e in events {
             match e {
+                Event::Quit { .. } => inputs.push(Input::Quit),
+                Event::KeyDown { keycode: Some(keycode), repeat: false, .. } => {
+                    if let Some(action) = self.bindings.keyboard(keycode) {
+                        inputs.push(Input::Pressed(action));
+                    }
+                }
+                Event::KeyUp { keycode: Some(keycode), .. } => {
+                    if let Some(action) = self.bindings.keyboard(keycode) {
+                        inputs.push(Input::Released(action));
+                    }
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    let controller = match self.subsystem.open(which) {
+                        Ok(controller) => controller,
+                        Err(e) => {
+                            println!("Unable to open game controller {}: {}", which, e);
+                            continue;
+                        }
+                    };
+
+                    // controllers without rumble are expected to fail here
+                    if let Some(ref haptic_subsystem) = self.haptic_subsystem {
+                        if let Ok(haptic) = haptic_subsystem.open_from_joystick_id(which) {
+                            self.haptics.push((controller.instance_id(), haptic));
+                        }
+                    }
+
+                    self.game_controllers.push(controller);
+                }
+                // unlike on the added event, `which` is the instance id
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    self.game_controllers.retain(|c| c.instance_id() != which);
+                    self.haptics.retain(|&(instance_id, _)| instance_id != which);
+                }
+                Event::ControllerButtonDown { button, .. } => {
+                    if let Some(action) = self.bindings.button(button) {
+                        inputs.push(Input::Pressed(action));
+                    }
+                }
+                Event::ControllerButtonUp { button, .. } => {
+                    if let Some(action) = self.bindings.button(button) {
+                        inputs.push(Input::Released(action));
+                    }
+                }
+                Event::ControllerAxisMotion { axis, value, .. } => {
+                    self.update_axis(axis, value, &mut inputs);
+                }
                 _ => ()
             }
         }
+
+        inputs
+    }
+
+    /// Turn the rumble of all game controllers on or off
+    pub fn set_rumble(&mut self, rumble: bool) {
+        for &mut (_, ref mut haptic) in self.haptics.iter_mut() {
+            if rumble {
+                haptic.rumble_play(1.0, RUMBLE_INFINITY);
+            } else {
+                haptic.rumble_stop();
+            }
+        }
+    }
+
+    /// Release the action of the previous axis direction and press the
+    /// one of the new direction
+    fn update_axis(&mut self, axis: Axis, value: i16, inputs: &mut Vec<Input>) {
+        let action = if value > AXIS_DEAD_ZONE {
+            self.bindings.axis(axis, true)
+        } else if value < -AXIS_DEAD_ZONE {
+            self.bindings.axis(axis, false)
+        } else {
+            None
+        };
+
+        let previous = self.axes.get(&axis).cloned();
+        if previous == action {
+            return;
+        }
+
+        if let Some(previous) = previous {
+            inputs.push(Input::Released(previous));
+            self.axes.remove(&axis);
+        }
+
+        if let Some(action) = action {
+            inputs.push(Input::Pressed(action));
+            self.axes.insert(axis, action);
+        }
     }
-}
\ No newline at end of file
+}
@@ -2,9 +2,11 @@ use sdl2::Sdl;
 use sdl2::video::Window;
 use sdl2::render::Canvas;
 use crate::renderer::Renderer;
-use sdl2::pixels::Color as SColor;
+use sdl2::pixels::{Color as SColor, PixelFormatEnum};
+use sdl2::surface::Surface;
 use sdl2::rect::Point;
-use rustboy::{ScreenBuffer, Color};
+use rustboy::{ScreenBuffer, rgb555_to_rgb888, SCREEN_X, SCREEN_Y};
+use std::path::Path;
 
 pub struct Display {
     canvas: Canvas<Window>
@@ -41,14 +43,8 @@ impl Renderer for Display {
         let mut index = 0;
         for y in 0..144 {
             for x in 0..160 {
-                let color = pixels[x + y * 160];
-
-                let color = match color {
-                    Color::Off      => SColor::RGB(0x00, 0x00, 0x00),
-                    Color::Dark     => SColor::RGB(0x55, 0x55, 0x55),
-                    Color::Light    => SColor::RGB(0xab, 0xab, 0xab),
-                    Color::On       => SColor::RGB(0xff, 0xff, 0xff),
-                };
+                let (r, g, b) = rgb555_to_rgb888(pixels[x + y * 160]);
+                let color = SColor::RGB(r, g, b);
 
                 self.canvas.set_draw_color(color);
                 self.canvas.draw_point(Point::new(x as i32, y as i32));
@@ -59,4 +55,18 @@ impl Renderer for Display {
 
         self.canvas.present();
     }
-}
\ No newline at end of file
+}
+
+/// Save the screen to a BMP file
+pub fn save_screenshot<P: AsRef<Path>>(pixels: &ScreenBuffer, path: P) -> Result<(), String> {
+    let mut data = Vec::with_capacity(pixels.len() * 3);
+
+    for &pixel in pixels.iter() {
+        let (r, g, b) = rgb555_to_rgb888(pixel);
+        data.extend_from_slice(&[r, g, b]);
+    }
+
+    let surface = Surface::from_data(&mut data, SCREEN_X as u32, SCREEN_Y as u32,
+                                     SCREEN_X as u32 * 3, PixelFormatEnum::RGB24)?;
+    surface.save_bmp(path)
+}
@@ -1,5 +1,8 @@
 use sdl2::Sdl;
 use self::controller::Controller;
+use crate::bindings::Bindings;
+
+pub use self::controller::Input;
 
 pub mod display;
 mod controller;
@@ -11,9 +14,9 @@ pub struct Context {
 
 impl Context {
     /// Build a new context instance
-    pub fn new() -> Self {
+    pub fn new(bindings: Bindings) -> Self {
         let context = sdl2::init().unwrap();
-        let controller = Controller::new();
+        let controller = Controller::new(&context, bindings);
 
         Self {
             context,
@@ -26,7 +29,13 @@ impl Context {
         display::Display::new(&self.context, width, height)
     }
 
-    pub fn update_controller(&mut self) {
-        self.controller.update(&self.context);
+    /// Process the pending events
+    pub fn update_controller(&mut self) -> Vec<Input> {
+        self.controller.update()
+    }
+
+    /// Turn the game controllers rumble on or off
+    pub fn set_rumble(&mut self, rumble: bool) {
+        self.controller.set_rumble(rumble);
     }
 }
\ No newline at end of file
@@ -0,0 +1,70 @@
+//! Emulation speed control.
+//!
+//! The emulation runs at one of a few fixed speeds, selected with the
+//! speed hotkeys, or unthrottled while fast-forward is held. The audio is
+//! resampled to follow the fixed speeds and muted when unthrottled.
+
+use rustboy::Machine;
+use std::time::Duration;
+
+/// Duration of a frame at normal speed, the Game Boy runs at 59.73 frames
+/// per second
+pub const FRAME_DURATION: Duration = Duration::from_nanos(16_742_706);
+
+/// Speeds selectable with the speed hotkeys
+const SPEEDS: [f64; 5] = [0.25, 0.5, 1.0, 2.0, 4.0];
+/// Index of the normal speed on `SPEEDS`
+const NORMAL_SPEED: usize = 2;
+
+pub struct Speed {
+    /// Index of the selected speed on `SPEEDS`
+    selected: usize,
+    /// Run as fast as possible, while fast-forward is held
+    fast_forward: bool,
+}
+
+impl Speed {
+    pub fn new() -> Self {
+        Self {
+            selected: NORMAL_SPEED,
+            fast_forward: false,
+        }
+    }
+
+    /// Select the next faster speed, if any
+    pub fn faster(&mut self) {
+        self.selected = (self.selected + 1).min(SPEEDS.len() - 1);
+    }
+
+    /// Select the next slower speed, if any
+    pub fn slower(&mut self) {
+        self.selected = self.selected.saturating_sub(1);
+    }
+
+    /// Start or stop running unthrottled
+    pub fn set_fast_forward(&mut self, fast_forward: bool) {
+        self.fast_forward = fast_forward;
+    }
+
+    /// Returns `true` when the emulation runs as fast as possible
+    pub fn is_unthrottled(&self) -> bool {
+        self.fast_forward
+    }
+
+    /// Get the selected speed, 1.0 being the Game Boy speed
+    pub fn multiplier(&self) -> f64 {
+        SPEEDS[self.selected]
+    }
+
+    /// Get the real time a frame lasts at the selected speed
+    pub fn frame_duration(&self) -> Duration {
+        FRAME_DURATION.mul_f64(1.0 / self.multiplier())
+    }
+
+    /// Configure the audio of the emulator for the current speed, muting
+    /// it when unthrottled or when `paused`
+    pub fn apply_audio(&self, emulator: &mut Machine, paused: bool) {
+        emulator.set_audio_speed(self.multiplier());
+        emulator.set_audio_muted(paused || self.fast_forward);
+    }
+}
@@ -0,0 +1,213 @@
+//! Headless runner.
+//!
+//! Runs a ROM without a window or audio, as fast as possible, for a number
+//! of frames or until the game prints a given text on the serial port,
+//! which is how most test ROMs report their result. The outcome is
+//! reported through the exit status, so batches of ROMs can be run from a
+//! script. The messages go to stderr, stdout is left to the serial output.
+//!
+//! Batch runs start from a clean cartridge and leave the save file
+//! untouched.
+
+extern crate clap;
+extern crate rustboy;
+
+use clap::{
+    Arg,
+    ArgMatches,
+    App,
+};
+use rustboy::{Config, Machine, NullPlayer, SerialLink};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+/// The frames ran, or the success text was printed
+const EXIT_SUCCESS: i32 = 0;
+/// The failure text was printed, or the emulation stopped on an error
+const EXIT_FAILURE: i32 = 1;
+/// The frames ran without printing the success text
+const EXIT_TIMEOUT: i32 = 2;
+
+/// Frames run when `--frames` isn't given, 5 minutes of emulated time
+const DEFAULT_MAX_FRAMES: u64 = 18000;
+
+/// Link cable device recording the bytes sent by the game
+struct CaptureLink {
+    output: Arc<Mutex<Vec<u8>>>,
+    /// Copy the bytes to stdout
+    echo: bool,
+}
+
+impl SerialLink for CaptureLink {
+    fn is_connected(&self) -> bool {
+        true
+    }
+
+    fn exchange(&mut self, value: u8) -> io::Result<u8> {
+        self.output.lock().unwrap().push(value);
+
+        if self.echo {
+            let mut stdout = io::stdout();
+            stdout.write_all(&[value])?;
+            stdout.flush()?;
+        }
+
+        // nothing on the other end
+        Ok(0xff)
+    }
+}
+
+/// Build the headless runner's command line.
+fn build_command_line() -> ArgMatches<'static> {
+    App::new("RustBoy headless")
+        .version("1.0")
+        .author("Gil Mendes <gil00mendes@gmail.com>")
+        .about("Runs a GameBoy ROM without a window or audio, the exit status tells how the run ended.")
+        .arg(Arg::with_name("ROM")
+            .help("ROM to be used")
+            .required(true)
+            .index(1))
+        .arg(Arg::with_name("fault-policy")
+            .long("fault-policy")
+            .value_name("POLICY")
+            .possible_values(&["ignore", "log", "error"])
+            .help("What to do when the game accesses unmapped memory"))
+        .arg(Arg::with_name("boot-rom")
+            .long("boot-rom")
+            .value_name("FILE")
+            .conflicts_with("skip-boot")
+            .help("Run a DMG or CGB boot ROM dump instead of the built-in one"))
+        .arg(Arg::with_name("skip-boot")
+            .long("skip-boot")
+            .help("Start the game directly, without running the boot ROM"))
+        .arg(Arg::with_name("frames")
+            .long("frames")
+            .value_name("COUNT")
+            .validator(|frames| frames.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+            .help("Stop the run after this number of frames (default 18000, 5 minutes)"))
+        .arg(Arg::with_name("until")
+            .long("until")
+            .value_name("TEXT")
+            .help("Stop the run successfully once the game sends this text on the serial port"))
+        .arg(Arg::with_name("fail-on")
+            .long("fail-on")
+            .value_name("TEXT")
+            .help("Stop the run with a failure once the game sends this text on the serial port"))
+        .arg(Arg::with_name("dump-screen")
+            .long("dump-screen")
+            .value_name("FILE")
+            .help("Write the last frame to a PNG file"))
+        .arg(Arg::with_name("serial-stdout")
+            .long("serial-stdout")
+            .help("Print the serial output to stdout"))
+        .get_matches()
+}
+
+/// Returns `true` if `text` appears on `output`
+fn contains(output: &[u8], text: &str) -> bool {
+    !text.is_empty() && output.windows(text.len()).any(|window| window == text.as_bytes())
+}
+
+/// Write the screen to a PNG file
+fn dump_screen(emulator: &Machine, path: &str) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    rustboy::write_png(emulator.screen_buffer(), &mut file)?;
+    file.flush()
+}
+
+/// Run the emulator following the headless options of `config` and
+/// return the exit status
+fn run(emulator: &mut Machine, config: &Config) -> i32 {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    emulator.set_serial_link(Box::new(CaptureLink {
+        output: output.clone(),
+        echo: config.serial_stdout,
+    }));
+
+    let max_frames = config.max_frames.unwrap_or(DEFAULT_MAX_FRAMES);
+    let status = loop {
+        // the machine counts the frames ended by a faulting instruction too
+        if emulator.frame_count() >= max_frames {
+            break if config.success_text.is_some() { EXIT_TIMEOUT } else { EXIT_SUCCESS };
+        }
+
+        // only the error policy stops a frame, there is no pause
+        if let Err(fault) = emulator.run_frame() {
+            eprintln!("{}", fault);
+            break EXIT_FAILURE;
+        }
+
+        if let Some(rumble) = emulator.take_rumble_event() {
+            eprintln!("Frame {}: rumble {}", emulator.frame_count(), if rumble { "on" } else { "off" });
+        }
+
+        let output = output.lock().unwrap();
+
+        if config.failure_text.as_ref().is_some_and(|text| contains(&output, text)) {
+            break EXIT_FAILURE;
+        }
+
+        if config.success_text.as_ref().is_some_and(|text| contains(&output, text)) {
+            break EXIT_SUCCESS;
+        }
+    };
+
+    eprintln!("Stopped after {} frames", emulator.frame_count());
+
+    if let Some(ref path) = config.screen_dump {
+        if let Err(e) = dump_screen(emulator, path) {
+            eprintln!("Unable to write {}: {}", path, e);
+            return EXIT_FAILURE;
+        }
+    }
+
+    status
+}
+
+fn main() {
+    rustboy::init_logger();
+
+    let matches = build_command_line();
+    let config = Config::from_clap(matches);
+
+    let boot = match config.boot() {
+        Ok(boot) => boot,
+        Err(e) => {
+            eprintln!("Unable to load the boot ROM {}", e);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+
+    let mut emulator = match rustboy::create_emulator(&config.rom_name, Box::new(NullPlayer::new()), boot) {
+        Ok(emulator) => emulator,
+        Err(e) => {
+            eprintln!("Unable to load {}: {}", config.rom_name, e);
+            process::exit(EXIT_FAILURE);
+        }
+    };
+
+    emulator.set_fault_policy(config.fault_policy);
+    process::exit(run(&mut emulator, &config));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::contains;
+
+    #[test]
+    fn finds_the_text_anywhere() {
+        assert!(contains(b"Passed", "Passed"));
+        assert!(contains(b"cpu_instrs\n\nPassed all tests\n", "Passed"));
+        assert!(contains(b"\xff\x00Failed", "Failed"));
+    }
+
+    #[test]
+    fn misses_partial_and_empty_texts() {
+        assert!(!contains(b"Pass", "Passed"));
+        assert!(!contains(b"", "Passed"));
+        assert!(!contains(b"passed", "Passed"));
+        assert!(!contains(b"Passed", ""));
+    }
+}